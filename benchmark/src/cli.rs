@@ -21,6 +21,10 @@ pub struct Args {
     #[arg(long, short)]
     pub config: Vec<String>,
 
+    /// Config profile (a `[profile.<name>]` config table) to translate every program with
+    #[arg(long)]
+    pub profile: Option<String>,
+
     /// Timeout in seconds for running test cases
     #[arg(long, default_value = "10")]
     pub timeout: u64,
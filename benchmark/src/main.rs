@@ -19,7 +19,9 @@ use crate::ir_utils::{cargo_build_result, raw_cargo_package, raw_source};
 use crate::logger::TeeLogger;
 use crate::stats::{ProgramEvalStats, SummaryStats, TestResult};
 use clap::Parser;
+use harvest_core::config::Config;
 use harvest_core::HarvestIR;
+use harvest_translate::cli::InitializeError;
 use harvest_translate::{transpile, util::set_user_only_umask};
 use std::fs::File;
 use std::path::{Path, PathBuf};
@@ -51,21 +53,45 @@ impl TranspilationResult {
     }
 }
 
-/// Translates a C source directory to a Rust Cargo project using harvest_translate
-pub fn translate_c_directory_to_rust_project(
+/// Loads and validates harvest_translate's config for translating `input_dir` into `output_dir`.
+fn initialize_config(
     input_dir: &Path,
     output_dir: &Path,
     config_overrides: &[String],
-) -> TranspilationResult {
+    profile: Option<&str>,
+) -> Result<Config, InitializeError> {
     let args: Arc<harvest_translate::cli::Args> = harvest_translate::cli::Args {
         input: Some(input_dir.to_path_buf()),
         output: Some(output_dir.to_path_buf()),
+        print_config: false,
         print_config_path: false,
+        profile: profile.map(str::to_owned),
         config: config_overrides.to_vec(),
         force: false,
+        recover: None,
     }
     .into();
-    let mut config = harvest_translate::cli::initialize(args).expect("Failed to generate config");
+    Ok(harvest_translate::cli::initialize(args)?.expect("Failed to generate config"))
+}
+
+/// Translates a C source directory to a Rust Cargo project using harvest_translate
+pub fn translate_c_directory_to_rust_project(
+    input_dir: &Path,
+    output_dir: &Path,
+    config_overrides: &[String],
+    profile: Option<&str>,
+) -> TranspilationResult {
+    let mut config = match initialize_config(input_dir, output_dir, config_overrides, profile) {
+        Ok(config) => config,
+        Err(error) => {
+            return TranspilationResult {
+                translation_success: false,
+                build_success: false,
+                rust_binary_path: PathBuf::new(),
+                build_error: Some(format!("Invalid config: {error}")),
+            };
+        }
+    };
     if config.log_filter.is_empty() {
         config.log_filter = "off".to_owned(); // Disable console logging in harvest_translate
     }
@@ -101,6 +127,7 @@ pub fn run_all_benchmarks(
     program_dirs: &[PathBuf],
     output_dir: &Path,
     config_overrides: &[String],
+    profile: Option<&str>,
     timeout: u64,
 ) -> HarvestResult<Vec<ProgramEvalStats>> {
     // Process all examples
@@ -112,7 +139,8 @@ pub fn run_all_benchmarks(
         log::info!("Processing example {} of {}", i + 1, total_examples);
         log::info!("{}", "=".repeat(80));
 
-        let result = benchmark_single_program(program_dir, output_dir, config_overrides, timeout);
+        let result =
+            benchmark_single_program(program_dir, output_dir, config_overrides, profile, timeout);
 
         results.push(result);
     }
@@ -180,6 +208,7 @@ fn benchmark_single_program(
     program_dir: &Path,
     output_root_dir: &Path,
     config_overrides: &[String],
+    profile: Option<&str>,
     timeout: u64,
 ) -> ProgramEvalStats {
     let program_name = program_dir
@@ -223,8 +252,12 @@ fn benchmark_single_program(
     }
 
     // Do the actual translation
-    let translation_result =
-        translate_c_directory_to_rust_project(&test_case_src_dir, &output_dir, config_overrides);
+    let translation_result = translate_c_directory_to_rust_project(
+        &test_case_src_dir,
+        &output_dir,
+        config_overrides,
+        profile,
+    );
 
     result.translation_success = translation_result.translation_success;
     result.rust_build_success = translation_result.build_success;
@@ -310,6 +343,17 @@ fn run(args: Args) -> HarvestResult<()> {
     log::info!("Running Benchmarks");
     log::info!("Input directory: {}", args.input_dir.display());
     log::info!("Output directory: {}", args.output_dir.display());
+    if let Some(profile) = &args.profile {
+        log::info!("Config profile: {profile}");
+    }
+
+    // Check the config once up front, rather than failing every program for the same reason.
+    initialize_config(
+        &args.input_dir,
+        &args.output_dir,
+        &args.config,
+        args.profile.as_deref(),
+    )?;
 
     // Get the programs to evaluate
     // Should be in directories that are immediate children of input_dir
@@ -317,7 +361,13 @@ fn run(args: Args) -> HarvestResult<()> {
     log_found_programs(&program_dirs, &args.input_dir)?;
 
     // Process all programs
-    let results = run_all_benchmarks(&program_dirs, &args.output_dir, &args.config, args.timeout)?;
+    let results = run_all_benchmarks(
+        &program_dirs,
+        &args.output_dir,
+        &args.config,
+        args.profile.as_deref(),
+        args.timeout,
+    )?;
     let csv_output_path = args.output_dir.join("results.csv");
     write_csv_results(&csv_output_path, &results)?;
 
@@ -1,22 +1,41 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use serde::Deserialize;
-use serde_json::Value;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use thiserror::Error;
 
 /// Configuration for this harvest-translate run. The sources of these configuration values (from
 /// highest-precedence to lowest-precedence) are:
 ///
 /// 1. Configurations passed using the `--config` command line flag.
-/// 2. A user-specific configuration directory (e.g. `$HOME/.config/harvest/config.toml').
-/// 3. Defaults specified in the code (using `#[serde(default)]`).
+/// 2. Environment variables, such as `HARVEST__TOOLS__RAW_SOURCE_TO_CARGO_LLM__API_KEY` for
+///    `tools.raw_source_to_cargo_llm.api_key`.
+/// 3. The selected profile (see `profile`).
+/// 4. `config.toml` in the working directory.
+/// 5. A user-specific configuration directory (e.g. `$HOME/.config/harvest/config.toml').
+/// 6. Defaults specified in the code (using `#[serde(default)]`).
+///
+/// Use `--print-config` to see which of these set each value.
+///
+/// In the `tools` sections, a `<name>_file` value is replaced by a `<name>` value containing the
+/// named file's contents, which is useful for secrets.
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    /// Path to the directory containing the C code to translate.
+    /// Path to the directory containing the C code to translate, or to a tar archive (optionally
+    /// gzip-compressed) of that directory.
     pub input: PathBuf,
 
     /// Path to output directory.
     pub output: PathBuf,
 
+    /// The profile to apply if none is selected with `--profile`.
+    pub default_profile: Option<String>,
+
     /// Path to the diagnostics directory, if you want diagnostics output. If you do not specify a
     /// diagnostics path, a temporary directory will be created (so that working directories can be
     /// created for tools) and cleaned up when translate completes.
@@ -28,13 +47,51 @@ pub struct Config {
     /// If false: if the directory exists and is nonempty, translate will output an error and exit.
     pub force: bool,
 
+    /// If true, translate stops with an error when an IR invariant is violated. If false,
+    /// violations are only reported in the diagnostics.
+    pub fail_on_invariant_violation: bool,
+
+    /// Path to a directory (which must not exist) to write a journal of IR changes to. If translate
+    /// is interrupted, it can continue from the journal using `--recover`.
+    pub journal: Option<PathBuf>,
+
     /// Filter describing which log messages should be output to stdout. This is in the
     /// `tracing_subscriber::filter::EnvFilter` format.
     pub log_filter: String,
 
+    /// The tool invocations to queue, in order. Each `[[pipeline]]` entry names a tool with `tool`
+    /// (see [ToolRegistry](crate::tools::ToolRegistry)); its other keys are the invocation's
+    /// arguments. A tool may be listed more than once, for example to run an LLM tool with two
+    /// different models.
+    pub pipeline: Vec<Invocation>,
+
+    /// Named sets of config values, defined in `[profile.<name>]` tables. The selected profile
+    /// (from `--profile` or `default_profile`) is merged over the config files, including any
+    /// `tools` sections it contains.
+    #[serde(default)]
+    pub profile: HashMap<String, Value>,
+
+    /// Path to the journal (see `journal`) of an interrupted run. If set, translate rebuilds the IR
+    /// from the journal, continues appending to it, and runs the tools that had not finished.
+    /// Takes precedence over `resume` and `journal`.
+    pub recover: Option<PathBuf>,
+
+    /// Path to an IR saved by a previous run (see `save_ir`). If set, translate starts from this IR
+    /// instead of an empty IR, and tools whose outputs are already present will not run again.
+    pub resume: Option<PathBuf>,
+
+    /// If true, each IR version is also saved in a reloadable format to `ir/$version/saved` in the
+    /// diagnostics directory. These saved IRs can be passed to `resume`.
+    pub save_ir: bool,
+
     /// Sub-configuration for each tool.
     pub tools: HashMap<String, serde_json::Value>,
 
+    /// The parsed and validated sub-configuration of each tool with a registered [ToolConfig].
+    /// This is filled in from `tools` using [ToolConfigRegistry::load].
+    #[serde(skip)]
+    pub tool_configs: ToolConfigs,
+
     // serde will place any unrecognized fields here. This will be passed to unknown_field_warning
     // after parsing to emit warnings on unrecognized config entries (we don't error on unknown
     // fields because that can be annoying to work with if you are switching back and forth between
@@ -49,13 +106,64 @@ impl Config {
         Self {
             input: PathBuf::from("mock_input"),
             output: PathBuf::from("mock_output"),
+            default_profile: None,
             diagnostics_dir: None,
             force: false,
+            fail_on_invariant_violation: false,
+            journal: None,
             log_filter: "off".to_owned(),
+            pipeline: vec![],
+            profile: Default::default(),
+            recover: None,
+            resume: None,
+            save_ir: false,
             tools: Default::default(),
+            tool_configs: Default::default(),
             unknown: Default::default(),
         }
     }
+
+    /// Returns the config of an invocation of the tool named `tool` with arguments `args` (see
+    /// [Invocation]): the tool's `tools.<tool>` section with `args` merged over it, parsed and
+    /// validated as `C`.
+    pub fn invocation_config<C: ToolConfig>(
+        &self,
+        tool: &str,
+        args: &Map<String, Value>,
+    ) -> Result<Arc<C>, Vec<String>> {
+        let mut section = match self.tools.get(tool) {
+            Some(Value::Object(section)) => section.clone(),
+            _ => Map::new(),
+        };
+        section.extend(args.clone());
+        let config = parse::<C>(&Value::Object(section))?;
+        // Unknown fields of the section were already reported by ToolConfigRegistry::load, so only
+        // report the ones that came from `args`.
+        let mut unknown: Vec<_> = config
+            .unknown_fields()
+            .into_iter()
+            .flat_map(HashMap::keys)
+            .filter(|name| args.contains_key(*name))
+            .collect();
+        unknown.sort_unstable();
+        for name in unknown {
+            eprintln!("Warning: unknown argument {name} of {tool} in pipeline");
+        }
+        Ok(Arc::new(config))
+    }
+}
+
+/// A tool invocation listed in the `pipeline` config.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Invocation {
+    /// The tool's name.
+    pub tool: String,
+
+    /// Every other key of the pipeline entry. Tools with a [ToolConfig] use these to override
+    /// values of their `tools.<name>` section for this invocation only (see
+    /// [Config::invocation_config]).
+    #[serde(flatten)]
+    pub args: Map<String, Value>,
 }
 
 /// Prints out a warning message for every field in `unknown`.
@@ -70,3 +178,221 @@ pub fn unknown_field_warning(prefix: &str, unknown: &HashMap<String, Value>) {
         p => eprintln!("Warning: unknown config key {p}.{name}"),
     });
 }
+
+/// A tool's configuration type, which is read from the tool's `tools.<name>` config section.
+/// Defaults should be provided using `#[serde(default)]`: if the section is missing, the type is
+/// deserialized from an empty table.
+pub trait ToolConfig: Any + Debug + DeserializeOwned + Send + Sync {
+    /// The fields that hold secrets (such as API keys), which are redacted when the config is
+    /// printed.
+    const SECRET_FIELDS: &'static [&'static str] = &[];
+
+    /// Checks the configuration, returning a description of each problem found. Problems that
+    /// should not stop translate should be printed as warnings instead.
+    fn validate(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Returns the fields that were not recognized (usually collected with `#[serde(flatten)]`),
+    /// which are printed as warnings by the caller.
+    fn unknown_fields(&self) -> Option<&HashMap<String, Value>> {
+        None
+    }
+}
+
+/// Parses and validates a tool's config section.
+fn parse<C: ToolConfig>(value: &Value) -> Result<C, Vec<String>> {
+    // Values set by environment variables and `--config` flags are strings. Deserializing through
+    // config::Value converts them (and any other scalars) to the types C's fields have, so a number
+    // or boolean is only parsed where C expects one.
+    let value = ::config::Value::deserialize(value).map_err(|error| vec![error.to_string()])?;
+    let config = C::deserialize(value).map_err(|error| vec![error.to_string()])?;
+    match config.validate() {
+        errors if errors.is_empty() => Ok(config),
+        errors => Err(errors),
+    }
+}
+
+/// Object-safe counterpart of [ToolConfig], used to store configs of different types together.
+trait AnyToolConfig: Any + Debug + Send + Sync {}
+
+impl<C: ToolConfig> AnyToolConfig for C {}
+
+/// A function that parses and validates the config section of the named tool, printing a warning
+/// for each unknown field.
+type Parser = fn(&str, &Value) -> Result<Arc<dyn AnyToolConfig>, Vec<String>>;
+
+/// Maps tool names to their [ToolConfig] types.
+#[derive(Default)]
+pub struct ToolConfigRegistry {
+    parsers: BTreeMap<&'static str, Parser>,
+    secret_fields: HashMap<&'static str, &'static [&'static str]>,
+}
+
+impl ToolConfigRegistry {
+    /// Registers `C` as the config type of the tool named `tool`.
+    pub fn register<C: ToolConfig>(&mut self, tool: &'static str) {
+        self.secret_fields.insert(tool, C::SECRET_FIELDS);
+        self.parsers.insert(tool, |tool, value| {
+            let config = parse::<C>(value)?;
+            if let Some(unknown) = config.unknown_fields() {
+                unknown_field_warning(&format!("tools.{tool}"), unknown);
+            }
+            Ok(Arc::new(config))
+        });
+    }
+
+    /// Returns whether `field` of the tool named `tool` holds a secret (see
+    /// [ToolConfig::SECRET_FIELDS]).
+    pub fn is_secret(&self, tool: &str, field: &str) -> bool {
+        self.secret_fields
+            .get(tool)
+            .is_some_and(|fields| fields.contains(&field))
+    }
+
+    /// Parses and validates the config section (from `tools`) of every registered tool. Prints a
+    /// warning for each section that does not belong to a registered tool.
+    pub fn load(&self, tools: &HashMap<String, Value>) -> Result<ToolConfigs, ToolConfigErrors> {
+        let empty = Value::Object(Default::default());
+        let mut configs = HashMap::new();
+        let mut errors = vec![];
+        for (&tool, parser) in &self.parsers {
+            match parser(tool, tools.get(tool).unwrap_or(&empty)) {
+                Ok(config) => _ = configs.insert(tool, config),
+                Err(tool_errors) => errors.extend(
+                    tool_errors
+                        .into_iter()
+                        .map(|error| format!("tools.{tool}: {error}")),
+                ),
+            }
+        }
+        let unknown = tools
+            .iter()
+            .filter(|(tool, _)| !self.parsers.contains_key(tool.as_str()))
+            .map(|(tool, value)| (tool.clone(), value.clone()))
+            .collect();
+        unknown_field_warning("tools", &unknown);
+        match errors.is_empty() {
+            true => Ok(ToolConfigs(configs)),
+            false => Err(ToolConfigErrors(errors)),
+        }
+    }
+}
+
+/// The parsed config of each tool, produced by [ToolConfigRegistry::load].
+#[derive(Clone, Default)]
+pub struct ToolConfigs(HashMap<&'static str, Arc<dyn AnyToolConfig>>);
+
+impl ToolConfigs {
+    /// Returns the config of the tool named `tool`, or `None` if no config of type `C` was
+    /// loaded for it.
+    pub fn get<C: ToolConfig>(&self, tool: &str) -> Option<Arc<C>> {
+        let config: Arc<dyn Any + Send + Sync> = self.0.get(tool)?.clone();
+        config.downcast().ok()
+    }
+}
+
+impl Debug for ToolConfigs {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(BTreeMap::from_iter(self.0.iter()))
+            .finish()
+    }
+}
+
+/// Error returned by [ToolConfigRegistry::load] and
+/// [ToolRegistry::pipeline](crate::tools::ToolRegistry::pipeline). Lists every problem found,
+/// across all tools.
+#[derive(Debug, Error, PartialEq)]
+#[error("invalid tool configuration:{}", .0.iter().map(|error| format!("\n  {error}")).collect::<String>())]
+pub struct ToolConfigErrors(pub Vec<String>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    struct Llm {
+        model: String,
+        #[serde(default)]
+        max_tokens: u32,
+    }
+
+    impl ToolConfig for Llm {
+        const SECRET_FIELDS: &'static [&'static str] = &["model"];
+
+        fn validate(&self) -> Vec<String> {
+            match self.max_tokens {
+                0 => vec!["max_tokens must be positive".into()],
+                _ => vec![],
+            }
+        }
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    struct Load {
+        #[serde(default)]
+        ignore_files: bool,
+    }
+
+    impl ToolConfig for Load {}
+
+    #[test]
+    fn tool_config_registry() {
+        let mut registry = ToolConfigRegistry::default();
+        registry.register::<Llm>("llm");
+        registry.register::<Load>("load");
+        let tools = HashMap::from([("llm".into(), json!({"model": "m", "max_tokens": 5}))]);
+        let configs = registry.load(&tools).unwrap();
+        assert_eq!(configs.get::<Llm>("llm").unwrap().model, "m");
+        // Missing sections use the defaults.
+        assert!(!configs.get::<Load>("load").unwrap().ignore_files);
+        assert!(configs.get::<Llm>("load").is_none());
+        assert!(configs.get::<Load>("missing").is_none());
+
+        // All problems are reported together.
+        let tools = HashMap::from([
+            ("llm".into(), json!({"model": "m"})),
+            ("load".into(), json!({"ignore_files": "sometimes"})),
+        ]);
+        let errors = registry.load(&tools).unwrap_err().0;
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], "tools.llm: max_tokens must be positive");
+        assert!(errors[1].starts_with("tools.load: "), "{errors:?}");
+        let errors = registry.load(&HashMap::new()).unwrap_err().0;
+        assert!(
+            errors[0].contains(r#"missing configuration field "model""#),
+            "{errors:?}"
+        );
+        assert!(registry.is_secret("llm", "model"));
+        assert!(!registry.is_secret("llm", "max_tokens"));
+        assert!(!registry.is_secret("load", "model"));
+    }
+
+    #[test]
+    fn invocation_config() {
+        let mut config = Config::mock();
+        config
+            .tools
+            .insert("llm".into(), json!({"model": "m", "max_tokens": 5}));
+        let args = Map::from_iter([("model".into(), json!("n"))]);
+        let llm = config.invocation_config::<Llm>("llm", &args).unwrap();
+        assert_eq!((llm.model.as_str(), llm.max_tokens), ("n", 5));
+        let load = config
+            .invocation_config::<Load>("load", &Map::new())
+            .unwrap();
+        assert!(!load.ignore_files);
+        let args = Map::from_iter([("max_tokens".into(), json!(0))]);
+        let errors = config.invocation_config::<Llm>("llm", &args).unwrap_err();
+        assert_eq!(errors, ["max_tokens must be positive"]);
+        // Strings (e.g. from environment variables) are parsed where a number is expected, and
+        // numbers are converted where a string is expected.
+        let args = Map::from_iter([
+            ("model".into(), json!(7)),
+            ("max_tokens".into(), json!("8")),
+        ]);
+        let llm = config.invocation_config::<Llm>("llm", &args).unwrap();
+        assert_eq!((llm.model.as_str(), llm.max_tokens), ("7", 8));
+    }
+}
@@ -11,10 +11,11 @@
 mod tests;
 mod tool_reporter;
 
-use crate::HarvestIR;
 use crate::config::Config;
+use crate::invariants::Violation;
 use crate::tools::Tool;
 use crate::utils::{EmptyDirError, empty_writable_dir};
+use crate::{HarvestIR, Id, Representation};
 use std::collections::HashMap;
 use std::fmt::{Arguments, Write as _};
 use std::fs::{File, canonicalize, create_dir, write};
@@ -26,7 +27,6 @@ use std::sync::mpsc::{Receiver, Sender, channel};
 use std::sync::{Arc, Mutex, MutexGuard};
 use tempfile::{TempDir, tempdir};
 use thiserror::Error;
-use tool_reporter::ToolId;
 use tracing::{dispatcher::DefaultGuard, error, info, subscriber::set_default};
 use tracing_subscriber::filter::ParseError;
 use tracing_subscriber::fmt::{MakeWriter, layer};
@@ -35,6 +35,7 @@ use tracing_subscriber::{EnvFilter, Layer as _, Registry};
 
 pub use tool_reporter::ToolJoiner;
 pub use tool_reporter::ToolReporter;
+pub use tool_reporter::{ToolId, ToolRunId};
 
 /// Diagnostics produced by transpilation. Can be used by callers of `transpile` to inspect the
 /// diagnostics produced during its execution.
@@ -112,6 +113,8 @@ impl Collector {
                 diagnostics_dir,
                 diagnostics_sender,
                 messages_file,
+                previous_ir: Default::default(),
+                save_ir: config.save_ir,
                 tool_run_counts: HashMap::new(),
             })),
             _tempdir,
@@ -147,8 +150,8 @@ pub struct Reporter {
 
 impl Reporter {
     /// Reports a new version of the IR.
-    pub fn report_ir_version(&self, version: u64, snapshot: &HarvestIR) {
-        let shared = lock_shared(&self.shared);
+    pub fn report_ir_version(&self, version: u64, snapshot: &Arc<HarvestIR>) {
+        let mut shared = lock_shared(&self.shared);
         let mut path = shared.diagnostics_dir.clone();
         path.push("ir");
         path.push(format!("{version:03}"));
@@ -157,6 +160,7 @@ impl Reporter {
             return;
         }
         let mut types = vec![];
+        let mut provenance = String::new();
         for (id, repr) in snapshot.iter() {
             let id_string = format!("{:03}", Into::<u64>::into(id));
             path.push(&id_string);
@@ -164,8 +168,28 @@ impl Reporter {
                 error!("Failed to materialize repr: {error}");
             }
             path.pop();
+            if let Some(p) = snapshot.provenance(id) {
+                let tool_run = p.tool_run.map_or("none".into(), |r| r.to_string());
+                let inputs: Vec<_> = p.inputs.iter().map(|&i| u64::from(i)).collect();
+                let _ = writeln!(
+                    provenance,
+                    "{id_string}: tool_run={tool_run} version={:03} inputs={inputs:03?}",
+                    p.version
+                );
+            }
             types.push((id, id_string, repr.name()));
         }
+        path.push("provenance");
+        if let Err(error) = write(&path, provenance) {
+            error!("Failed to write IR provenance: {error}");
+        }
+        path.pop();
+        path.push("changes");
+        if let Err(error) = write(&path, changes_report(&shared.previous_ir, snapshot)) {
+            error!("Failed to write IR changes: {error}");
+        }
+        path.pop();
+        shared.previous_ir = snapshot.clone();
         // TODO: For now, HarvestIR does not guarantee a particular iteration order, but it
         // *happens* to iterate in this same order. We should figure out what guarantees we want
         // HarvestIR to have, and then update this accordingly.
@@ -174,18 +198,77 @@ impl Reporter {
         for (_, id_string, name) in types {
             let _ = writeln!(index, "{id_string}: {name}");
         }
+        if shared.save_ir {
+            path.push("saved");
+            if let Err(error) = snapshot.save(&path) {
+                error!("Failed to save IR: {error}");
+            }
+            path.pop();
+        }
         path.push("index");
         if let Err(error) = write(path, index) {
             error!("Failed to write IR index: {error}");
         }
     }
 
+    /// Reports violations of IR invariants found in IR version `version`, which was produced by
+    /// the tool run `tool_run`. The violations are logged and written into the `violations` file
+    /// of that version's IR directory.
+    pub fn report_invariant_violations(
+        &self,
+        version: u64,
+        tool_run: ToolRunId,
+        violations: &[Violation],
+    ) {
+        let mut contents = String::new();
+        for violation in violations {
+            error!("IR version {version:03} (produced by {tool_run}) violates {violation}");
+            let _ = writeln!(contents, "{tool_run}: {violation}");
+        }
+        let path = PathBuf::from_iter([
+            lock_shared(&self.shared).diagnostics_dir.as_path(),
+            "ir".as_ref(),
+            format!("{version:03}").as_ref(),
+            "violations".as_ref(),
+        ]);
+        if let Err(error) = write(path, contents) {
+            error!("Failed to write invariant violations: {error}");
+        }
+    }
+
     /// Reports the start of a tool's execution.
     pub fn start_tool_run(&self, tool: &dyn Tool) -> Result<(ToolJoiner, ToolReporter), io::Error> {
         ToolReporter::new(self.shared.clone(), tool)
     }
 }
 
+/// Returns a description of the changes between two IR versions, to be written into the `changes`
+/// file of the newer version's diagnostics directory.
+fn changes_report(old: &HarvestIR, new: &HarvestIR) -> String {
+    let diff = old.diff(new);
+    let mut out = String::new();
+    // The IDs in `diff` are guaranteed to be live in the IR(s) they came from.
+    fn get(ir: &HarvestIR, id: Id) -> &dyn Representation {
+        ir.get_untyped(id).expect("diff ID missing from IR")
+    }
+    for &id in &diff.added {
+        let _ = writeln!(out, "added {:03}: {}", u64::from(id), get(new, id).name());
+    }
+    for &id in &diff.removed {
+        let _ = writeln!(out, "removed {:03}: {}", u64::from(id), get(old, id).name());
+    }
+    for &id in &diff.replaced {
+        let new_repr = get(new, id);
+        let _ = writeln!(out, "replaced {:03}: {}", u64::from(id), new_repr.name());
+        if let Some(repr_diff) = new_repr.diff(get(old, id)) {
+            for line in repr_diff.lines() {
+                let _ = writeln!(out, "    {line}");
+            }
+        }
+    }
+    out
+}
+
 /// Error type returned by Collector::new.
 #[derive(Debug, Error)]
 pub enum CollectorNewError {
@@ -224,6 +307,12 @@ struct Shared {
     // Writer for $diagnostic_dir/messages
     messages_file: SharedWriter<File>,
 
+    // The most recently reported IR version, which the next reported version is compared against.
+    previous_ir: Arc<HarvestIR>,
+
+    // Whether to save a reloadable copy of each IR version (see `Config::save_ir`).
+    save_ir: bool,
+
     // The number of times each tool has been run. Tools that have not been run yet will not be
     // present in this map. This is incremented when a tool run starts, not when it ends.
     tool_run_counts: HashMap<ToolId, NonZeroU64>,
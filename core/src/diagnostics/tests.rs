@@ -73,3 +73,38 @@ fn messages() {
     verify("steps/tool_b_001/messages", &["HHHH"]);
     verify("steps/tool_a_002/messages", &["EEEE", "GGGG", "IIII"]);
 }
+
+/// Verifies that each reported IR version gets a `changes` file describing the changes from the
+/// previously-reported version.
+#[test]
+fn ir_changes() {
+    use crate::ir::tests::IdRepresentation;
+    let mut config = Config::mock();
+    let tempdir = tempdir().unwrap();
+    config.diagnostics_dir = Some(tempdir.path().to_path_buf());
+    let collector = Collector::initialize(&config).unwrap();
+    let reporter = collector.reporter();
+    let mut ir = HarvestIR::default();
+    let a = ir.add_representation(Box::new(IdRepresentation(1))).id();
+    reporter.report_ir_version(1, &Arc::new(ir.clone()));
+    let b = ir.add_representation(Box::new(IdRepresentation(2))).id();
+    ir.remove(a);
+    reporter.report_ir_version(2, &Arc::new(ir));
+    drop(reporter);
+    collector.diagnostics();
+    let changes = |version: &str| {
+        read_to_string(PathBuf::from_iter([
+            tempdir.path(),
+            "ir".as_ref(),
+            version.as_ref(),
+            "changes".as_ref(),
+        ]))
+        .unwrap()
+    };
+    let [a, b] = [a, b].map(u64::from);
+    assert_eq!(changes("001"), format!("added {a:03}: id\n"));
+    assert_eq!(
+        changes("002"),
+        format!("added {b:03}: id\nremoved {a:03}: id\n")
+    );
+}
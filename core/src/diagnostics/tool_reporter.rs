@@ -23,6 +23,7 @@ use tracing_subscriber::{Layer as _, Registry};
 #[derive(Clone)]
 pub struct ToolReporter {
     run_shared: Arc<Mutex<RunShared>>,
+    tool_run: ToolRunId,
 }
 
 impl ToolReporter {
@@ -72,6 +73,7 @@ impl ToolReporter {
             ToolJoiner { receiver },
             ToolReporter {
                 run_shared: Arc::new(Mutex::new(RunShared { dispatch, sender })),
+                tool_run,
             },
         ))
     }
@@ -86,6 +88,11 @@ impl ToolReporter {
         }
     }
 
+    /// Returns the ID of the tool run this reporter is for.
+    pub fn tool_run(&self) -> ToolRunId {
+        self.tool_run
+    }
+
     /// Utility to lock this reporter's shared reference.
     fn lock_shared(&self) -> MutexGuard<'_, RunShared> {
         match self.run_shared.lock() {
@@ -110,8 +117,8 @@ pub struct ThreadGuard {
 /// Identifies a particular tool. Conceptually, this is equivalent to the tool's name, but this
 /// design allows us to optimize the representation in the future to e.g. use TypeId for faster
 /// comparisons and hashing.
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
-pub(super) struct ToolId {
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ToolId {
     /// The name returned by `Tool::name`.
     name: &'static str,
 }
@@ -133,8 +140,12 @@ impl Display for ToolId {
 
 /// An identifier for a tool run. Can be converted into a string, which will look like
 /// `try_cargo_build_2`. This string should be suitable to use as a file/directory name.
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
-pub(super) struct ToolRunId {
+#[allow(
+    clippy::manual_non_exhaustive,
+    reason = "_private also prevents construction elsewhere in harvest_core"
+)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ToolRunId {
     pub tool: ToolId,
     /// The first run of a particular tool has number 1, the second has 2, etc.
     pub number: NonZeroU64,
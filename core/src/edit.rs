@@ -1,42 +1,170 @@
 //! A system for organizing concurrent mutations to a [HarvestIR].
 
-use crate::{HarvestIR, Id, Representation};
-use std::collections::{HashMap, HashSet};
+use crate::diagnostics::ToolRunId;
+use crate::id::{IdAllocator, RunIds};
+use crate::ir::Provenance;
+use crate::journal::{Journal, JournalChange};
+use crate::{HarvestIR, Id, Representation, TypedId};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::mem::take;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use thiserror::Error;
+use tracing::error;
 
 /// A tool for organizing concurrent modifications to a [HarvestIR]. This owns the `HarvestIR`,
 /// providing read-only access via `snapshot` and an interface to create and apply [Edit]s that
 /// mutate the IR. `Organizer` does not allow two `Edit`s that can modify the same representation
 /// to exist simultaneously.
-#[derive(Default)]
+///
+/// The `Organizer` also keeps a bounded history of past IR versions, which can be retrieved with
+/// [Organizer::snapshot_at] and restored with [Organizer::rollback]. Because representations are
+/// shared between versions, old versions are cheap to keep.
 pub struct Organizer {
     ir: Arc<HarvestIR>,
     shared: Arc<Shared>,
+    // IR version number. The version starts at 0 and increments by 1 every time an edit is
+    // applied.
+    version: u64,
+    // Past IR versions, oldest first, and the maximum number of them to keep.
+    history: VecDeque<(u64, Arc<HarvestIR>)>,
+    history_limit: usize,
+    // Journal to record each new IR version in, if journaling is enabled.
+    journal: Option<Journal>,
+    // Allocates the IDs of representations added by tool runs' edits.
+    ids: IdAllocator,
+}
+
+impl Default for Organizer {
+    fn default() -> Organizer {
+        Organizer::with_harvest_ir(HarvestIR::default())
+    }
 }
 
 impl Organizer {
+    /// The number of past IR versions an `Organizer` keeps by default.
+    pub const DEFAULT_HISTORY_LIMIT: usize = 16;
+
     /// Constructs a new Organizer with the provided `HarvestIR`.
     pub fn with_harvest_ir(ir: HarvestIR) -> Organizer {
         Organizer {
+            ids: IdAllocator::new(ir.ids()),
             ir: ir.into(),
             shared: Default::default(),
+            version: 0,
+            history: VecDeque::new(),
+            history_limit: Self::DEFAULT_HISTORY_LIMIT,
+            journal: None,
         }
     }
 
-    /// Applies the edit in `Edit` to the IR. This will update the IR and mark the edit's IDs as
-    /// unused.
-    pub fn apply_edit(&mut self, mut edit: Edit) -> Result<(), WrongOrganizer> {
+    /// Starts journaling changes to the IR into a new [Journal] in `dir`, which must not exist.
+    /// Each IR version is recorded in the journal before it becomes visible, so the IR can be
+    /// rebuilt using [Organizer::recover] if this process is interrupted.
+    pub fn start_journal(&mut self, dir: &Path) -> io::Result<()> {
+        self.journal = Some(Journal::create(dir, &self.ir, self.version)?);
+        Ok(())
+    }
+
+    /// Sets the version and journal of an `Organizer` rebuilt by [Organizer::recover].
+    pub(crate) fn set_recovered(&mut self, version: u64, journal: Journal) {
+        self.version = version;
+        self.journal = Some(journal);
+    }
+
+    /// Sets the maximum number of past IR versions to keep (not counting the current version).
+    /// Older versions are discarded.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        self.trim_history();
+    }
+
+    /// Applies the edit in `Edit` to the IR. This will update the IR, record the [Provenance] of
+    /// each written ID, increment the IR version, and mark the edit's IDs as unused.
+    ///
+    /// If any of the IDs the edit read (see [Edit::record_read]) have been changed since the edit
+    /// was created, the edit was computed from stale data, and is rejected with
+    /// [ApplyEditError::StaleRead].
+    pub fn apply_edit(&mut self, mut edit: Edit) -> Result<(), ApplyEditError> {
         // Note: we just drop `edit` to mark the IDs as no longer in use.
         if !Arc::ptr_eq(&self.shared, &edit.shared) {
-            return Err(WrongOrganizer);
+            return Err(ApplyEditError::WrongOrganizer);
+        }
+        let stale: Vec<Id> = edit
+            .reads
+            .iter()
+            .copied()
+            .filter(|id| {
+                match (
+                    edit.base.representations.get(id),
+                    self.ir.representations.get(id),
+                ) {
+                    (Some(old), Some(new)) => !Arc::ptr_eq(old, new),
+                    (None, None) => false,
+                    _ => true,
+                }
+            })
+            .collect();
+        if !stale.is_empty() {
+            return Err(ApplyEditError::StaleRead(stale));
         }
+        // If the tool did not record what it read, conservatively assume it read everything.
+        let inputs = if edit.reads.is_empty() {
+            edit.base.ids().copied().collect()
+        } else {
+            take(&mut edit.reads)
+        };
+        if let Some(journal) = &self.journal {
+            let mut changes: Vec<_> = edit
+                .writable
+                .iter()
+                .filter_map(|(&id, change)| match change {
+                    Change::Keep => None,
+                    Change::Write(representation) => {
+                        Some(JournalChange::Write(id, &**representation))
+                    }
+                    Change::Remove => Some(JournalChange::Remove(id)),
+                    &Change::Supersede(new) => Some(JournalChange::Supersede(id, new)),
+                })
+                .collect();
+            changes.sort_by_key(|change| match *change {
+                JournalChange::Write(id, _)
+                | JournalChange::Remove(id)
+                | JournalChange::Supersede(id, _) => id,
+            });
+            if let Err(error) =
+                journal.record_edit(self.version + 1, edit.tool_run, &inputs, &changes)
+            {
+                error!("Failed to journal edit: {error}");
+                return Err(ApplyEditError::JournalWriteFailed);
+            }
+        }
+        self.advance_version();
+        let provenance = Arc::new(Provenance {
+            tool_run: edit.tool_run,
+            version: self.version,
+            inputs,
+        });
         let ir = Arc::make_mut(&mut self.ir);
-        for (&id, ref mut representation) in &mut edit.writable {
-            if let Some(representation) = representation.take() {
-                ir.insert(id, representation);
+        let mut superseded = vec![];
+        for (&id, change) in &mut edit.writable {
+            match take(change) {
+                Change::Keep => {}
+                Change::Write(representation) => {
+                    ir.insert(id, representation);
+                    ir.provenance.insert(id, provenance.clone());
+                }
+                Change::Remove => ir.remove(id),
+                Change::Supersede(new) => superseded.push((id, new)),
             }
         }
+        // Superseding is applied after all writes, so that the superseding representations exist.
+        for (old, new) in superseded {
+            ir.supersede(old, new);
+        }
         Ok(())
     }
 
@@ -46,22 +174,68 @@ impl Organizer {
     /// The IDs in `might_write` will be marked as in use, and will only be freed when either the
     /// edit is applied (via [Organizer::apply_edit]) or dropped.
     pub fn new_edit(&mut self, might_write: &HashSet<Id>) -> Result<Edit, NewEditError> {
+        self.new_edit_with_reads(might_write, &HashSet::new())
+    }
+
+    /// Like [Organizer::new_edit], but also read-locks the IDs in `must_not_change`: until the
+    /// returned edit is applied or dropped, no other edit that might write those IDs can be
+    /// created. Any number of edits may read-lock the same ID. The read-locked IDs are recorded as
+    /// reads of the edit (see [Edit::record_read]).
+    ///
+    /// All IDs in `might_write` and `must_not_change` must be part of the current IR. An ID that
+    /// is in both sets is only write-locked.
+    pub fn new_edit_with_reads(
+        &mut self,
+        might_write: &HashSet<Id>,
+        must_not_change: &HashSet<Id>,
+    ) -> Result<Edit, NewEditError> {
         // An unknown ID generally represents a bug in the calling code, whereas IdInUse can be a
         // normal situation. Therefore, prioritize returning UnknownId so that IdInUse doesn't hide
         // bugs.
-        if might_write.iter().any(|&id| !self.ir.contains_id(id)) {
+        if might_write
+            .iter()
+            .chain(must_not_change)
+            .any(|&id| !self.ir.contains_id(id))
+        {
             return Err(NewEditError::UnknownId);
         }
-        let mut in_use = self.shared.in_use.lock().expect("in_use poisoned");
-        if might_write.iter().any(|id| in_use.contains(id)) {
-            return Err(NewEditError::IdInUse);
+        let read_locks: HashSet<Id> = must_not_change.difference(might_write).copied().collect();
+        let mut locks = self.shared.locks.lock().expect("locks poisoned");
+        // Report write conflicts in preference to read conflicts, as a write-locked ID is about to
+        // change.
+        if might_write
+            .iter()
+            .chain(&read_locks)
+            .any(|id| matches!(locks.get(id), Some(Lock::Write)))
+        {
+            return Err(NewEditError::IdInUse(LockKind::Write));
+        }
+        if might_write
+            .iter()
+            .any(|id| matches!(locks.get(id), Some(Lock::Read(_))))
+        {
+            return Err(NewEditError::IdInUse(LockKind::Read));
         }
         might_write.iter().for_each(|&id| {
-            in_use.insert(id);
+            locks.insert(id, Lock::Write);
         });
+        read_locks.iter().for_each(|&id| {
+            let Lock::Read(count) = locks.entry(id).or_insert(Lock::Read(0)) else {
+                unreachable!("write-locked ID {id} was read-locked");
+            };
+            *count += 1;
+        });
+        self.shared.edits.fetch_add(1, Ordering::AcqRel);
         Ok(Edit {
             shared: self.shared.clone(),
-            writable: might_write.iter().map(|&id| (id, None)).collect(),
+            writable: might_write.iter().map(|&id| (id, Change::Keep)).collect(),
+            read_locks,
+            base: self.ir.clone(),
+            reads: must_not_change.iter().copied().collect(),
+            tool_run: None,
+            ids: self.ids.clone(),
+            run_ids: None,
+            unreproducible_ids: false,
         })
     }
 
@@ -69,22 +243,117 @@ impl Organizer {
     pub fn snapshot(&self) -> Arc<HarvestIR> {
         self.ir.clone()
     }
+
+    /// Returns the IR as it was at the given version, if that version is the current version or
+    /// is still in the history.
+    pub fn snapshot_at(&self, version: u64) -> Option<Arc<HarvestIR>> {
+        if version == self.version {
+            return Some(self.ir.clone());
+        }
+        self.history
+            .iter()
+            .find(|(v, _)| *v == version)
+            .map(|(_, ir)| ir.clone())
+    }
+
+    /// Restores the IR to its contents at `version`. Rolling back does not rewind the version
+    /// number: the restored IR becomes a new version, so the versions in between can themselves
+    /// be restored later.
+    ///
+    /// Rollback is not allowed while any [Edit]s exist, as they may have been computed from (and
+    /// would be applied to) the IR that is being discarded.
+    pub fn rollback(&mut self, version: u64) -> Result<(), RollbackError> {
+        let ir = self
+            .snapshot_at(version)
+            .ok_or(RollbackError::UnknownVersion(version))?;
+        if self.shared.edits.load(Ordering::Acquire) > 0 {
+            return Err(RollbackError::EditsInProgress);
+        }
+        if let Some(journal) = &self.journal
+            && let Err(error) = journal.record_snapshot(self.version + 1, &ir)
+        {
+            error!("Failed to journal rollback: {error}");
+            return Err(RollbackError::JournalWriteFailed);
+        }
+        self.advance_version();
+        self.ir = ir;
+        Ok(())
+    }
+
+    /// Returns the current IR version. This starts at 0 and is incremented by each successful
+    /// [Organizer::apply_edit] or [Organizer::rollback] call.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Moves the current IR into the history and increments the version number.
+    fn advance_version(&mut self) {
+        self.history.push_back((self.version, self.ir.clone()));
+        self.trim_history();
+        self.version += 1;
+    }
+
+    /// Discards the oldest versions from the history until it fits within the limit.
+    fn trim_history(&mut self) {
+        while self.history.len() > self.history_limit {
+            self.history.pop_front();
+        }
+    }
 }
 
 /// Error type returned by `Organizer::apply_edit`.
 #[derive(Debug, Error, Hash, PartialEq)]
-#[error("edit is for a different Organizer")]
-pub struct WrongOrganizer;
+pub enum ApplyEditError {
+    #[error("edit is for a different Organizer")]
+    WrongOrganizer,
+    /// The listed IDs were read by the edit, but have been changed since the edit was created.
+    #[error("IDs read by the edit have changed: {0:?}")]
+    StaleRead(Vec<Id>),
+    /// The edit could not be recorded in the journal (see [Organizer::start_journal]), so it was
+    /// not applied.
+    #[error("failed to write the edit to the journal")]
+    JournalWriteFailed,
+}
+
+/// Error type returned by `Organizer::rollback`.
+#[derive(Debug, Error, Hash, PartialEq)]
+pub enum RollbackError {
+    #[error("edits are in progress")]
+    EditsInProgress,
+    #[error("IR version {0} is not in the history")]
+    UnknownVersion(u64),
+    #[error("failed to write the rollback to the journal")]
+    JournalWriteFailed,
+}
 
 /// Error type returned by `Organizer::new_edit`.
 #[derive(Debug, Error, Hash, PartialEq)]
 pub enum NewEditError {
-    #[error("one of the IDs is currently in use.")]
-    IdInUse,
+    /// One of the IDs is locked by another edit. Contains the kind of lock that conflicted.
+    #[error("one of the IDs is currently {0}-locked by another edit.")]
+    IdInUse(LockKind),
     #[error("an ID is not in this HarvestIR")]
     UnknownId,
 }
 
+/// The kinds of lock an [Edit] can hold on an ID.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum LockKind {
+    /// The ID must not change until the edit is applied or dropped.
+    Read,
+    /// The edit might write the ID.
+    Write,
+}
+
+impl Display for LockKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            LockKind::Read => write!(f, "read"),
+            LockKind::Write => write!(f, "write"),
+        }
+    }
+}
+
 /// A tool for making changes to (a subset of) the IR. When an `Edit` is
 /// created, it is given a limited set of representations which it can modify
 /// (by ID). An `Edit` can replace those representations as well as create new
@@ -92,30 +361,49 @@ pub enum NewEditError {
 ///
 /// The general pattern for a tool to edit an existing ID's Representation is:
 ///
-/// 1. Read the Representation out of `context.ir_snapshot`.
+/// 1. Read the Representation out of `context.ir_snapshot`, and record the read using
+///    `record_read`.
 /// 2. Clone the Representation to get an owned copy.
 /// 3. Edit the copied Representation.
 /// 4. Store the edited Representation into `context.ir_edit` using `write_id`.
+///
+/// Alternatively, a tool can use `supersede` to store the edited Representation under a new ID
+/// while keeping the old Representation's history, or use `remove_id` to delete a Representation.
 pub struct Edit {
     shared: Arc<Shared>,
 
-    // Contains every ID this tool can write. IDs that contain Some() will be
-    // written, and IDs that contain None will not be touched.
-    writable: HashMap<Id, Option<Box<dyn Representation>>>,
+    // Contains every ID this tool can write, and the change (if any) this edit will make to that
+    // ID.
+    writable: HashMap<Id, Change>,
+    // IDs this edit holds a read lock on.
+    read_locks: HashSet<Id>,
+
+    // The IR at the time this edit was created, and the IDs the tool read from it. Used to detect
+    // stale reads and to record provenance information for every ID this edit writes.
+    base: Arc<HarvestIR>,
+    reads: BTreeSet<Id>,
+    tool_run: Option<ToolRunId>,
+
+    // Allocator for new IDs. Once the tool run is known, IDs are allocated from `run_ids`, which
+    // makes them reproducible. Edits without a tool run (e.g. ones that set up an IR) use
+    // `Id::new`; `unreproducible_ids` records whether any were allocated that way.
+    ids: IdAllocator,
+    run_ids: Option<RunIds>,
+    unreproducible_ids: bool,
 }
 
 impl Edit {
     /// Adds a representation with a new ID and returns the new ID.
-    pub fn add_representation(&mut self, representation: Box<dyn Representation>) -> Id {
-        let id = Id::new();
-        self.writable.insert(id, Some(representation));
-        id
+    pub fn add_representation<R: Representation>(&mut self, representation: Box<R>) -> TypedId<R> {
+        let id = self.next_id();
+        self.writable.insert(id, Change::Write(representation));
+        TypedId::new_unchecked(id)
     }
 
     /// Creates a new ID and gives this tool write access to it.
     pub fn new_id(&mut self) -> Id {
-        let id = Id::new();
-        self.writable.insert(id, None);
+        let id = self.next_id();
+        self.writable.insert(id, Change::Keep);
         id
     }
 
@@ -126,12 +414,79 @@ impl Edit {
         id: Id,
         representation: Box<dyn Representation>,
     ) -> Result<(), NotWritable> {
+        self.set_change(id, Change::Write(representation))
+    }
+
+    /// Removes the representation at `id` from the IR. Errors if this tool cannot write `id`.
+    pub fn remove_id(&mut self, id: Id) -> Result<(), NotWritable> {
+        self.set_change(id, Change::Remove)
+    }
+
+    /// Adds `representation` under a new ID and marks the representation at `old` as superseded
+    /// by it. Once this edit is applied, `old` is no longer live (it will not be returned by e.g.
+    /// [HarvestIR::iter]), but it can still be found using [HarvestIR::superseded_by]. Returns the
+    /// new ID, or errors if this tool cannot write `old`.
+    pub fn supersede<R: Representation>(
+        &mut self,
+        old: Id,
+        representation: Box<R>,
+    ) -> Result<TypedId<R>, NotWritable> {
+        if !self.writable.contains_key(&old) {
+            return Err(NotWritable);
+        }
+        let new = self.add_representation(representation);
+        self.writable.insert(old, Change::Supersede(new.into()));
+        Ok(new)
+    }
+
+    /// Marks the representation at `old` as superseded by `new`, a representation this edit
+    /// adds. Used when several representations are replaced by one (e.g. a new package makes both
+    /// the old package and its build results obsolete). Errors if this tool cannot write `old`.
+    pub fn supersede_with(&mut self, old: Id, new: impl Into<Id>) -> Result<(), NotWritable> {
+        self.set_change(old, Change::Supersede(new.into()))
+    }
+
+    /// Sets the change to make to `id`. Errors if this tool cannot write `id`.
+    fn set_change(&mut self, id: Id, change: Change) -> Result<(), NotWritable> {
         self.writable
             .get_mut(&id)
-            .map(|v| *v = Some(representation))
+            .map(|v| *v = change)
             .ok_or(NotWritable)
     }
 
+    /// Records that the tool read the representation at `id` to compute this edit. When the edit
+    /// is applied, it will be rejected if any recorded read has changed in the meantime.
+    ///
+    /// If no reads are recorded, the edit is never rejected as stale, and the [Provenance] of the
+    /// IDs it writes lists every ID in the IR as an input.
+    pub fn record_read(&mut self, id: impl Into<Id>) {
+        self.reads.insert(id.into());
+    }
+
+    /// Sets the tool run that is making this edit. This is recorded in the [Provenance] of every
+    /// ID this edit writes. IDs created afterwards are derived from the tool run, so they are the
+    /// same every time the tool run creates them. Must be called before this edit allocates any
+    /// IDs, which is checked in debug builds.
+    pub fn set_tool_run(&mut self, tool_run: ToolRunId) {
+        debug_assert!(
+            !self.unreproducible_ids,
+            "IDs were allocated before the tool run was set"
+        );
+        self.tool_run = Some(tool_run);
+        self.run_ids = Some(self.ids.run_ids(tool_run));
+    }
+
+    /// Allocates an ID for a new representation.
+    fn next_id(&mut self) -> Id {
+        match &mut self.run_ids {
+            None => {
+                self.unreproducible_ids = true;
+                Id::new()
+            }
+            Some(run_ids) => run_ids.next(),
+        }
+    }
+
     /// Writes `representation` to the given `id`. Panics if this tool cannot
     /// write `id`.
     #[track_caller]
@@ -144,14 +499,37 @@ impl Edit {
 
 impl Drop for Edit {
     fn drop(&mut self) {
-        // Mark this Edit's IDs as no longer in use.
-        let mut in_use = self.shared.in_use.lock().expect("in_use poisoned");
-        self.writable.iter().for_each(|(id, _)| {
-            in_use.remove(id);
+        self.shared.edits.fetch_sub(1, Ordering::AcqRel);
+        // Release this Edit's locks.
+        let mut locks = self.shared.locks.lock().expect("locks poisoned");
+        self.writable.keys().for_each(|id| {
+            locks.remove(id);
+        });
+        self.read_locks.iter().for_each(|id| {
+            if let Some(Lock::Read(count)) = locks.get_mut(id) {
+                *count -= 1;
+                if *count == 0 {
+                    locks.remove(id);
+                }
+            }
         });
     }
 }
 
+/// A change an [Edit] will make to a writable ID.
+#[derive(Default)]
+enum Change {
+    /// Leave the ID untouched.
+    #[default]
+    Keep,
+    /// Write a new representation to the ID.
+    Write(Box<dyn Representation>),
+    /// Remove the ID from the IR.
+    Remove,
+    /// Mark the ID as superseded by the given (new) ID.
+    Supersede(Id),
+}
+
 /// Error type returned if you try to modify an ID that this [Edit] cannot write.
 #[derive(Debug, Eq, PartialEq, Error)]
 #[error("cannot write this id")]
@@ -160,7 +538,18 @@ pub struct NotWritable;
 /// State shared between the `Organizer` and the `Edit`s it creates.
 #[derive(Default)]
 struct Shared {
-    in_use: Mutex<HashSet<Id>>,
+    locks: Mutex<HashMap<Id, Lock>>,
+    // The number of Edits that currently exist.
+    edits: AtomicUsize,
+}
+
+/// A lock held on an ID by one or more `Edit`s.
+#[derive(Clone, Debug, PartialEq)]
+enum Lock {
+    /// Held by this many edits that need the ID to stay unchanged.
+    Read(usize),
+    /// Held by the one edit that might write the ID.
+    Write,
 }
 
 #[cfg(test)]
@@ -168,6 +557,15 @@ mod tests {
     use super::*;
     use crate::ir::tests::EmptyRepresentation;
 
+    fn locks(organizer: &Organizer) -> HashMap<Id, Lock> {
+        organizer
+            .shared
+            .locks
+            .lock()
+            .expect("locks poisoned")
+            .clone()
+    }
+
     #[test]
     fn organizer() {
         let mut organizer = Organizer::default();
@@ -177,8 +575,8 @@ mod tests {
             .new_edit(&[].into())
             .expect("no-ID new_edit failed");
         let [a, b, c] = [
-            edit.add_representation(Box::new(EmptyRepresentation)),
-            edit.add_representation(Box::new(EmptyRepresentation)),
+            edit.add_representation(Box::new(EmptyRepresentation)).id(),
+            edit.add_representation(Box::new(EmptyRepresentation)).id(),
             edit.new_id(),
         ];
         assert_eq!(organizer.apply_edit(edit), Ok(()));
@@ -188,31 +586,27 @@ mod tests {
             HashSet::from([&a, &b]),
             "apply_edit set incorrect representations"
         );
+        assert_eq!(organizer.version(), 1);
 
         // Nested change creation: create two Edits. Apply the second one, then drop the first.
         let mut edit1 = organizer.new_edit(&[a].into()).expect("new_edit failed");
         let mut edit2 = organizer.new_edit(&[b].into()).expect("new_edit failed");
         assert_eq!(
-            *organizer.shared.in_use.lock().expect("in_use poisoned"),
-            HashSet::from([a, b])
+            locks(&organizer),
+            HashMap::from([(a, Lock::Write), (b, Lock::Write)])
         );
         let [_, _] = [(); 2].map(|_| edit1.add_representation(Box::new(EmptyRepresentation)));
-        let [f, g] = [(); 2].map(|_| edit2.add_representation(Box::new(EmptyRepresentation)));
+        let [f, g] = [(); 2].map(|_| edit2.add_representation(Box::new(EmptyRepresentation)).id());
         assert_eq!(organizer.apply_edit(edit2), Ok(()), "apply_edit failed");
-        assert_eq!(
-            *organizer.shared.in_use.lock().expect("in_use poisoned"),
-            HashSet::from([a])
-        );
+        assert_eq!(locks(&organizer), HashMap::from([(a, Lock::Write)]));
         // a, b, f, and g should be set but d and e should not.
         drop(edit1);
-        assert_eq!(
-            *organizer.shared.in_use.lock().expect("in_use poisoned"),
-            HashSet::from([])
-        );
+        assert_eq!(locks(&organizer), HashMap::from([]));
         assert_eq!(
             HashSet::from_iter(organizer.snapshot().representations.keys().copied()),
             HashSet::from([a, b, f, g])
         );
+        assert_eq!(organizer.version(), 2);
 
         // Swap Edits between Organizer instances.
         assert_eq!(
@@ -221,7 +615,7 @@ mod tests {
                     .new_edit(&[].into())
                     .expect("new_edit failed")
             ),
-            Err(WrongOrganizer),
+            Err(ApplyEditError::WrongOrganizer),
             "apply_edit accepted Edit from another Organizer"
         );
 
@@ -234,7 +628,7 @@ mod tests {
         );
         assert_eq!(
             organizer.new_edit(&[f, g].into()).err(),
-            Some(NewEditError::IdInUse),
+            Some(NewEditError::IdInUse(LockKind::Write)),
             "new_edit accepted in use ID"
         );
         drop(edit);
@@ -243,13 +637,13 @@ mod tests {
     #[test]
     fn edit() {
         let [a, b, c] = Id::new_array();
-        let mut organizer = Organizer::with_harvest_ir(HarvestIR {
-            representations: [a, b]
-                .map(|id| (id, Arc::new(EmptyRepresentation) as Arc<_>))
-                .into(),
-        });
+        let mut ir = HarvestIR::default();
+        [a, b]
+            .into_iter()
+            .for_each(|id| ir.insert(id, Arc::new(EmptyRepresentation) as Arc<_>));
+        let mut organizer = Organizer::with_harvest_ir(ir);
         let mut edit = organizer.new_edit(&[a, b].into()).unwrap();
-        let d = edit.add_representation(Box::new(EmptyRepresentation));
+        let d = edit.add_representation(Box::new(EmptyRepresentation)).id();
         let e = edit.new_id();
         assert_eq!(
             edit.try_write_id(a, Box::new(EmptyRepresentation)),
@@ -267,11 +661,227 @@ mod tests {
             HashSet::from_iter(
                 edit.writable
                     .iter()
-                    .filter(|(_, r)| r.is_some())
+                    .filter(|(_, c)| matches!(c, Change::Write(_)))
                     .map(|(&i, _)| i)
             ),
             HashSet::from([a, d, e]),
             "changed IDs incorrect"
         );
     }
+
+    #[test]
+    fn provenance() {
+        let mut organizer = Organizer::default();
+        let mut edit = organizer.new_edit(&[].into()).unwrap();
+        let a = edit.add_representation(Box::new(EmptyRepresentation)).id();
+        organizer.apply_edit(edit).unwrap();
+        let mut edit = organizer.new_edit(&[a].into()).unwrap();
+        let b = edit.add_representation(Box::new(EmptyRepresentation)).id();
+        organizer.apply_edit(edit).unwrap();
+        let snapshot = organizer.snapshot();
+        assert_eq!(
+            snapshot.provenance(a),
+            Some(&Provenance {
+                tool_run: None,
+                version: 1,
+                inputs: [].into(),
+            })
+        );
+        assert_eq!(
+            snapshot.provenance(b),
+            Some(&Provenance {
+                tool_run: None,
+                version: 2,
+                inputs: [a].into(),
+            })
+        );
+        // Overwriting an ID should replace its provenance.
+        let mut edit = organizer.new_edit(&[a].into()).unwrap();
+        edit.write_id(a, Box::new(EmptyRepresentation));
+        organizer.apply_edit(edit).unwrap();
+        assert_eq!(
+            organizer.snapshot().provenance(a).map(|p| p.version),
+            Some(3)
+        );
+        assert_eq!(
+            organizer.snapshot().provenance(b).map(|p| p.version),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn remove_and_supersede() {
+        let mut organizer = Organizer::default();
+        let mut edit = organizer.new_edit(&[].into()).unwrap();
+        let [a, b, c] =
+            [(); 3].map(|_| edit.add_representation(Box::new(EmptyRepresentation)).id());
+        organizer.apply_edit(edit).unwrap();
+
+        let mut edit = organizer.new_edit(&[a, b].into()).unwrap();
+        assert_eq!(edit.remove_id(a), Ok(()));
+        assert_eq!(edit.remove_id(c), Err(NotWritable));
+        let d = edit
+            .supersede(b, Box::new(EmptyRepresentation))
+            .unwrap()
+            .id();
+        assert_eq!(
+            edit.supersede(c, Box::new(EmptyRepresentation)),
+            Err(NotWritable)
+        );
+        assert_eq!(edit.supersede_with(c, d), Err(NotWritable));
+        organizer.apply_edit(edit).unwrap();
+
+        let snapshot = organizer.snapshot();
+        assert_eq!(Vec::from_iter(snapshot.ids().copied()), [c, d]);
+        assert_eq!(snapshot.count::<EmptyRepresentation>(), 2);
+        assert!(snapshot.provenance(a).is_none());
+        assert_eq!(snapshot.superseded_by(b).map(|(new, _)| new), Some(d));
+        assert!(snapshot.provenance(b).is_some());
+        assert!(snapshot.superseded_by(c).is_none());
+        // Removed and superseded IDs are no longer part of the IR, so they cannot be edited.
+        assert_eq!(
+            organizer.new_edit(&[b].into()).err(),
+            Some(NewEditError::UnknownId)
+        );
+
+        // Superseding c and d with a single new representation.
+        let mut edit = organizer.new_edit(&[c, d].into()).unwrap();
+        let e = edit
+            .supersede(c, Box::new(EmptyRepresentation))
+            .unwrap()
+            .id();
+        assert_eq!(edit.supersede_with(d, e), Ok(()));
+        organizer.apply_edit(edit).unwrap();
+        let snapshot = organizer.snapshot();
+        assert_eq!(Vec::from_iter(snapshot.ids().copied()), [e]);
+        assert_eq!(snapshot.superseded_by(d).map(|(new, _)| new), Some(e));
+        // b was replaced by d, which is no longer live, so it is no longer kept.
+        assert!(snapshot.superseded_by(b).is_none());
+    }
+
+    #[test]
+    fn read_locks() {
+        let mut organizer = Organizer::default();
+        let mut edit = organizer.new_edit(&[].into()).unwrap();
+        let [a, b, c] =
+            [(); 3].map(|_| edit.add_representation(Box::new(EmptyRepresentation)).id());
+        organizer.apply_edit(edit).unwrap();
+
+        // Multiple edits can read-lock the same ID, but nothing can write it until they are done.
+        let mut reader1 = organizer
+            .new_edit_with_reads(&[b].into(), &[a].into())
+            .unwrap();
+        let d = reader1
+            .add_representation(Box::new(EmptyRepresentation))
+            .id();
+        let reader2 = organizer
+            .new_edit_with_reads(&[].into(), &[a, c].into())
+            .unwrap();
+        assert_eq!(
+            locks(&organizer),
+            HashMap::from([(a, Lock::Read(2)), (b, Lock::Write), (c, Lock::Read(1))])
+        );
+        assert_eq!(
+            organizer.new_edit(&[a].into()).err(),
+            Some(NewEditError::IdInUse(LockKind::Read))
+        );
+        // Write locks conflict with read locks, and are reported in preference to them.
+        assert_eq!(
+            organizer.new_edit_with_reads(&[].into(), &[b].into()).err(),
+            Some(NewEditError::IdInUse(LockKind::Write))
+        );
+        assert_eq!(
+            organizer.new_edit(&[a, b].into()).err(),
+            Some(NewEditError::IdInUse(LockKind::Write))
+        );
+        assert_eq!(
+            organizer
+                .new_edit_with_reads(&[].into(), &[Id::new()].into())
+                .err(),
+            Some(NewEditError::UnknownId)
+        );
+        // Read-locked IDs are recorded as inputs.
+        organizer.apply_edit(reader1).unwrap();
+        assert_eq!(
+            organizer.snapshot().provenance(d).map(|p| &p.inputs),
+            Some(&[a].into())
+        );
+        assert_eq!(
+            locks(&organizer),
+            HashMap::from([(a, Lock::Read(1)), (c, Lock::Read(1))])
+        );
+        drop(reader2);
+        assert_eq!(locks(&organizer), HashMap::from([]));
+        assert!(organizer.new_edit(&[a].into()).is_ok());
+    }
+
+    #[test]
+    fn history() {
+        let mut organizer = Organizer::default();
+        organizer.set_history_limit(2);
+        let mut ids = vec![];
+        for _ in 0..3 {
+            let mut edit = organizer.new_edit(&[].into()).unwrap();
+            ids.push(edit.add_representation(Box::new(EmptyRepresentation)).id());
+            organizer.apply_edit(edit).unwrap();
+        }
+        // Version 0 has been discarded from the history.
+        assert!(organizer.snapshot_at(0).is_none());
+        assert!(organizer.snapshot_at(4).is_none());
+        let count = |v| organizer.snapshot_at(v).map(|ir| ir.ids().count());
+        assert_eq!([1, 2, 3].map(count), [Some(1), Some(2), Some(3)]);
+
+        let edit = organizer.new_edit(&[].into()).unwrap();
+        assert_eq!(organizer.rollback(1), Err(RollbackError::EditsInProgress));
+        drop(edit);
+        assert_eq!(organizer.rollback(0), Err(RollbackError::UnknownVersion(0)));
+        assert_eq!(organizer.rollback(1), Ok(()));
+        assert_eq!(organizer.version(), 4);
+        assert_eq!(
+            Vec::from_iter(organizer.snapshot().ids().copied()),
+            [ids[0]]
+        );
+        // The version that was rolled back from can still be restored.
+        assert_eq!(organizer.rollback(3), Ok(()));
+        assert_eq!(organizer.snapshot().ids().count(), 3);
+        assert_eq!(organizer.version(), 5);
+    }
+
+    #[test]
+    fn stale_read() {
+        let mut organizer = Organizer::default();
+        let mut edit = organizer.new_edit(&[].into()).unwrap();
+        let [a, b] = [(); 2].map(|_| edit.add_representation(Box::new(EmptyRepresentation)).id());
+        organizer.apply_edit(edit).unwrap();
+
+        // Two edits read `a`. The one that is applied second computed its result from a version of
+        // `a` that no longer exists, so it is rejected.
+        let mut reader = organizer.new_edit(&[].into()).unwrap();
+        reader.record_read(a);
+        reader.record_read(b);
+        let c = reader
+            .add_representation(Box::new(EmptyRepresentation))
+            .id();
+        let mut writer = organizer.new_edit(&[a].into()).unwrap();
+        writer.record_read(a);
+        writer.write_id(a, Box::new(EmptyRepresentation));
+        organizer.apply_edit(writer).unwrap();
+        assert_eq!(
+            organizer.apply_edit(reader),
+            Err(ApplyEditError::StaleRead(vec![a]))
+        );
+        assert!(!organizer.snapshot().contains_id(c));
+        assert_eq!(organizer.version(), 2);
+
+        // Reads that are unchanged do not prevent the edit from being applied, and are recorded as
+        // its inputs.
+        let mut edit = organizer.new_edit(&[].into()).unwrap();
+        edit.record_read(b);
+        let d = edit.add_representation(Box::new(EmptyRepresentation)).id();
+        organizer.apply_edit(edit).unwrap();
+        assert_eq!(
+            organizer.snapshot().provenance(d).map(|p| &p.inputs),
+            Some(&[b].into())
+        );
+    }
 }
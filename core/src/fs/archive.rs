@@ -0,0 +1,242 @@
+//! Conversion between [RawDir]s and tar archives.
+
+use super::{GetFileError, RawDir, RawEntry, SetFileError};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tar::{Archive, Builder, EntryType, Header};
+use thiserror::Error;
+
+/// The first bytes of a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Compression applied by [RawDir::to_tar].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+impl RawDir {
+    /// Reads a tar archive, which may be gzip-compressed (this is detected automatically).
+    ///
+    /// Entry paths are checked the same way as in [RawDir::set_file], so an archive cannot place
+    /// files outside the directory. Symlinks must be relative and stay inside the directory. File
+    /// permissions and modification times are preserved. Directory entries are skipped, so empty
+    /// directories are not loaded.
+    pub fn from_tar<R: Read>(reader: R) -> Result<RawDir, TarError> {
+        let mut reader = BufReader::new(reader);
+        let reader: Box<dyn Read> = match reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            true => Box::new(GzDecoder::new(reader)),
+            false => Box::new(reader),
+        };
+        let mut dir = RawDir::default();
+        for entry in Archive::new(reader).entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let header = entry.header();
+            let set_error = |error| TarError::InvalidPath(path.clone(), error);
+            match header.entry_type() {
+                EntryType::Regular | EntryType::Continuous => {
+                    let (mode, mtime) = (header.mode()?, header.mtime()?);
+                    let mut contents = vec![];
+                    entry.read_to_end(&mut contents)?;
+                    dir.set_file(&path, contents.into()).map_err(set_error)?;
+                    let file = dir.raw_file_mut(&path).expect("missing new file");
+                    file.mode = Some(mode & 0o7777);
+                    file.mtime = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime));
+                }
+                EntryType::Symlink => {
+                    let target = entry.link_name()?.ok_or(TarError::MissingLinkName)?;
+                    dir.set_symlink(&path, target).map_err(set_error)?;
+                }
+                EntryType::Link => {
+                    let target = entry.link_name()?.ok_or(TarError::MissingLinkName)?;
+                    let contents = dir
+                        .get_file(&target)
+                        .map_err(|error| TarError::InvalidLink(path.clone(), error))?;
+                    dir.set_file(&path, contents.clone()).map_err(set_error)?;
+                }
+                EntryType::Directory | EntryType::XGlobalHeader => {}
+                other => return Err(TarError::UnsupportedEntry(path, format!("{other:?}"))),
+            }
+        }
+        Ok(dir)
+    }
+
+    /// If this directory's only entry is a subdirectory (such as the `project-1.0/` directory
+    /// that source archives usually contain), returns that subdirectory. Otherwise, returns
+    /// `self`.
+    pub fn unwrap_single_dir(mut self) -> RawDir {
+        if self.0.len() != 1 || self.0.values().all(|entry| entry.dir().is_none()) {
+            return self;
+        }
+        match self.0.pop_first() {
+            Some((_, RawEntry::Dir(dir))) => dir,
+            _ => unreachable!("the only entry is not a directory"),
+        }
+    }
+
+    /// Writes this directory to `writer` as a tar archive. Files without recorded metadata are
+    /// written with mode `0o644` and a modification time of 0, so the archive only depends on the
+    /// directory's contents.
+    pub fn to_tar<W: Write>(&self, writer: W, compression: Compression) -> io::Result<()> {
+        match compression {
+            Compression::None => self.write_tar(writer).map(drop),
+            Compression::Gzip => {
+                let encoder = GzEncoder::new(writer, flate2::Compression::default());
+                self.write_tar(encoder)?.finish().map(drop)
+            }
+        }
+    }
+
+    /// Writes this directory to `writer` as an uncompressed tar archive, returning `writer`.
+    fn write_tar<W: Write>(&self, writer: W) -> io::Result<W> {
+        let mut builder = Builder::new(writer);
+        for (path, entry) in self.walk() {
+            let mut header = Header::new_gnu();
+            match entry {
+                RawEntry::File(file) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(file.contents.len() as u64);
+                    header.set_mode(file.mode.unwrap_or(0o644));
+                    header.set_mtime(file.mtime.map_or(0, unix_seconds));
+                    builder.append_data(&mut header, &path, &*file.contents)?;
+                }
+                RawEntry::Symlink(target) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    header.set_size(0);
+                    header.set_mode(0o777);
+                    header.set_mtime(0);
+                    builder.append_link(&mut header, &path, target)?;
+                }
+                RawEntry::Dir(_) => {}
+            }
+        }
+        builder.into_inner()
+    }
+}
+
+/// Error type returned by [RawDir::from_tar].
+#[derive(Debug, Error)]
+pub enum TarError {
+    #[error("I/O error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("{path}: {1}", path = .0.display())]
+    InvalidLink(PathBuf, GetFileError),
+    #[error("{path}: {1}", path = .0.display())]
+    InvalidPath(PathBuf, SetFileError),
+    #[error("link entry without a link name")]
+    MissingLinkName,
+    #[error("{path}: unsupported entry type {1}", path = .0.display())]
+    UnsupportedEntry(PathBuf, String),
+}
+
+/// Converts `time` to seconds since the Unix epoch, clamping times before the epoch to 0.
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut dir = RawDir::default();
+        dir.set_file("src/main.c", b"int main;".into()).unwrap();
+        dir.set_file("test.sh", b"#!/bin/sh".into()).unwrap();
+        dir.set_executable("test.sh", true).unwrap();
+        dir.set_symlink("include/main.c", "../src/main.c").unwrap();
+        for compression in [Compression::None, Compression::Gzip] {
+            let mut archive = vec![];
+            dir.to_tar(&mut archive, compression).unwrap();
+            assert_eq!(
+                archive.starts_with(&GZIP_MAGIC),
+                compression == Compression::Gzip
+            );
+            let loaded = RawDir::from_tar(archive.as_slice()).unwrap();
+            assert_eq!(loaded.diff(&dir), "");
+            assert_eq!(loaded.get_mode("test.sh"), Ok(Some(0o755)));
+            assert_eq!(loaded.get_mode("src/main.c"), Ok(Some(0o644)));
+            assert_eq!(loaded.symlinks_recursive(), dir.symlinks_recursive());
+        }
+    }
+
+    #[test]
+    fn unwrap_single_dir() {
+        let mut dir = RawDir::default();
+        dir.set_file("project-1.0/src/main.c", b"int main;".into())
+            .unwrap();
+        let dir = dir.unwrap_single_dir();
+        assert_eq!(dir.get_file("src/main.c"), Ok(&b"int main;".into()));
+        let dir = dir.unwrap_single_dir();
+        assert_eq!(dir.get_file("main.c"), Ok(&b"int main;".into()));
+        // A lone file is left in place.
+        let dir = dir.unwrap_single_dir();
+        assert_eq!(dir.get_file("main.c"), Ok(&b"int main;".into()));
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        // tar::Builder refuses to write unsafe paths, so write them into the headers directly.
+        fn archive(path: &[u8], entry_type: EntryType, link_name: &str) -> Vec<u8> {
+            let mut header = Header::new_old();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path);
+            header.set_entry_type(entry_type);
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_mtime(0);
+            if !link_name.is_empty() {
+                header.set_link_name(link_name).unwrap();
+            }
+            header.set_cksum();
+            let mut builder = Builder::new(vec![]);
+            builder.append(&header, io::empty()).unwrap();
+            builder.into_inner().unwrap()
+        }
+        for (path, entry_type, link_name, expected) in [
+            (
+                &b"../evil"[..],
+                EntryType::Regular,
+                "",
+                SetFileError::OutsideDir,
+            ),
+            (
+                b"/etc/evil",
+                EntryType::Regular,
+                "",
+                SetFileError::AbsolutePath,
+            ),
+            (
+                b"a/../../evil",
+                EntryType::Regular,
+                "",
+                SetFileError::OutsideDir,
+            ),
+            (
+                b"link",
+                EntryType::Symlink,
+                "../evil",
+                SetFileError::EscapingSymlink,
+            ),
+            (
+                b"link",
+                EntryType::Symlink,
+                "/etc",
+                SetFileError::EscapingSymlink,
+            ),
+        ] {
+            let archive = archive(path, entry_type, link_name);
+            let result = RawDir::from_tar(archive.as_slice());
+            assert!(
+                matches!(&result, Err(TarError::InvalidPath(_, error)) if *error == expected),
+                "{result:?}"
+            );
+        }
+    }
+}
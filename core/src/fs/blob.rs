@@ -0,0 +1,209 @@
+//! Content-addressed storage for file contents.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::ops::Deref;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError, Weak};
+
+/// The contents of every live [Blob], keyed by hash. Entries are removed when their last [Blob]
+/// is dropped.
+static STORE: LazyLock<Mutex<HashMap<ContentHash, Weak<Stored>>>> = LazyLock::new(Default::default);
+
+/// A SHA-256 hash of a [Blob]'s contents, or a Merkle hash of a [RawDir](super::RawDir) (see
+/// [RawDir::hash](super::RawDir::hash)).
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ContentHash([u8; 32]);
+
+impl ContentHash {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub(super) fn from_hasher(hasher: Sha256) -> ContentHash {
+        ContentHash(hasher.finalize().into())
+    }
+}
+
+impl Debug for ContentHash {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "ContentHash({self})")
+    }
+}
+
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+/// Immutable, reference-counted file contents. Blobs with identical contents share a single
+/// allocation, even if they were created independently, so cloning a [RawDir](super::RawDir) or
+/// loading the same file into several representations does not copy the file's contents.
+///
+/// Blobs are compared by hash, so comparing them does not read their contents.
+#[derive(Clone)]
+pub struct Blob(Arc<Stored>);
+
+/// The allocation shared by [Blob]s with identical contents.
+struct Stored {
+    hash: ContentHash,
+    contents: Box<[u8]>,
+}
+
+impl Blob {
+    /// Returns a blob with the given contents, sharing the allocation of any live blob with the
+    /// same contents.
+    pub fn new(contents: &[u8]) -> Blob {
+        let hash = ContentHash(Sha256::digest(contents).into());
+        let mut store = STORE.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(existing) = store.get(&hash).and_then(Weak::upgrade) {
+            return Blob(existing);
+        }
+        let stored = Arc::new(Stored {
+            hash,
+            contents: contents.into(),
+        });
+        store.insert(hash, Arc::downgrade(&stored));
+        Blob(stored)
+    }
+
+    /// The SHA-256 hash of this blob's contents.
+    pub fn hash(&self) -> ContentHash {
+        self.0.hash
+    }
+}
+
+impl Drop for Stored {
+    // Runs once, after the last Blob sharing this allocation is dropped.
+    fn drop(&mut self) {
+        let mut store = STORE.lock().unwrap_or_else(PoisonError::into_inner);
+        // Before STORE was locked, Blob::new may have replaced this entry with a live one, which
+        // must be kept. Any dead entry can be removed, whether or not it is this one.
+        if store
+            .get(&self.hash)
+            .is_some_and(|stored| stored.strong_count() == 0)
+        {
+            store.remove(&self.hash);
+        }
+    }
+}
+
+impl Debug for Blob {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match str::from_utf8(&self.0.contents) {
+            Ok(contents) => write!(f, "Blob({contents:?})"),
+            Err(_) => write!(f, "Blob({} bytes, {})", self.0.contents.len(), self.0.hash),
+        }
+    }
+}
+
+impl Default for Blob {
+    fn default() -> Blob {
+        Blob::new(&[])
+    }
+}
+
+impl Deref for Blob {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0.contents
+    }
+}
+
+impl AsRef<[u8]> for Blob {
+    fn as_ref(&self) -> &[u8] {
+        &self.0.contents
+    }
+}
+
+impl Eq for Blob {}
+
+impl PartialEq for Blob {
+    fn eq(&self, other: &Blob) -> bool {
+        self.0.hash == other.0.hash
+    }
+}
+
+impl PartialEq<[u8]> for Blob {
+    fn eq(&self, other: &[u8]) -> bool {
+        *self.0.contents == *other
+    }
+}
+
+impl From<&[u8]> for Blob {
+    fn from(contents: &[u8]) -> Blob {
+        Blob::new(contents)
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for Blob {
+    fn from(contents: &[u8; N]) -> Blob {
+        Blob::new(contents)
+    }
+}
+
+impl From<Vec<u8>> for Blob {
+    fn from(contents: Vec<u8>) -> Blob {
+        Blob::new(&contents)
+    }
+}
+
+impl From<&str> for Blob {
+    fn from(contents: &str) -> Blob {
+        Blob::new(contents.as_bytes())
+    }
+}
+
+impl From<String> for Blob {
+    fn from(contents: String) -> Blob {
+        Blob::new(contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharing() {
+        // Use contents no other test uses, so that the blob is not kept alive elsewhere.
+        let a = Blob::from(b"blob::tests::sharing");
+        let b = Blob::from(b"blob::tests::sharing".to_vec());
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        assert_eq!(a, b);
+        assert_ne!(a, Blob::from(b"blob::tests"));
+        assert_eq!(
+            a.hash().to_string(),
+            format!("{:x}", Sha256::digest(b"blob::tests::sharing"))
+        );
+        let hash = a.hash();
+        drop((a, b));
+        let store = STORE.lock().unwrap_or_else(PoisonError::into_inner);
+        assert!(!store.contains_key(&hash));
+    }
+
+    #[test]
+    fn concurrent_drop() {
+        use std::sync::Barrier;
+        const THREADS: usize = 4;
+        let contents = b"blob::tests::concurrent_drop";
+        for _ in 0..10 {
+            let blob = Blob::from(contents);
+            let hash = blob.hash();
+            let barrier = Barrier::new(THREADS);
+            let clones = vec![blob; THREADS];
+            std::thread::scope(|scope| {
+                for clone in clones {
+                    let barrier = &barrier;
+                    scope.spawn(move || {
+                        barrier.wait();
+                        drop(clone);
+                    });
+                }
+            });
+            let store = STORE.lock().unwrap_or_else(PoisonError::into_inner);
+            assert!(!store.contains_key(&hash));
+        }
+    }
+}
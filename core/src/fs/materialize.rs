@@ -0,0 +1,227 @@
+//! Alternative ways of writing a [RawDir] to the file system.
+
+use super::{RawDir, RawEntry, symlink};
+use std::ffi::OsString;
+use std::fs::{
+    FileType, create_dir, create_dir_all, read, read_dir, read_link, remove_dir_all, remove_file,
+    rename, symlink_metadata, write,
+};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// How [RawDir::materialize_with] writes a directory.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum MaterializeMode {
+    /// Write every file into a directory that is empty or does not exist, as
+    /// [RawDir::materialize] does.
+    #[default]
+    Fresh,
+    /// Make the directory (which is created if it does not exist) match the [RawDir] exactly.
+    /// Only files whose contents differ are written, so unchanged files keep their modification
+    /// times, and entries that are not in the [RawDir] are removed. Paths in `keep` (relative to
+    /// the directory) are left alone unless the [RawDir] contains them, which is useful for build
+    /// output such as `target/`.
+    Incremental { keep: Vec<PathBuf> },
+    /// Write to a temporary sibling directory, then rename it into place, replacing the existing
+    /// directory (if any). An interrupted write never leaves a partially-written directory at the
+    /// destination, although the destination may briefly not exist.
+    Atomic,
+}
+
+impl RawDir {
+    /// Like [RawDir::materialize], but writes the directory as specified by `mode`.
+    pub fn materialize_with<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mode: &MaterializeMode,
+    ) -> io::Result<()> {
+        let path = path.as_ref();
+        match mode {
+            MaterializeMode::Fresh => self.materialize(path),
+            MaterializeMode::Incremental { keep } => self.sync(path, Path::new(""), keep),
+            MaterializeMode::Atomic => self.materialize_atomic(path),
+        }
+    }
+
+    /// Makes the directory at `path`, which is at `relative` within the directory being
+    /// materialized, match this directory. See [MaterializeMode::Incremental].
+    fn sync(&self, path: &Path, relative: &Path, keep: &[PathBuf]) -> io::Result<()> {
+        match file_type(path)? {
+            Some(file_type) if file_type.is_dir() => {}
+            Some(file_type) => {
+                remove(path, file_type)?;
+                create_dir(path)?;
+            }
+            None => create_dir_all(path)?,
+        }
+        for entry in read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if !self.0.contains_key(&name) && !keep.contains(&relative.join(&name)) {
+                remove(&entry.path(), entry.file_type()?)?;
+            }
+        }
+        for (name, entry) in &self.0 {
+            let path = path.join(name);
+            let existing = file_type(&path)?;
+            match entry {
+                RawEntry::Dir(dir) => dir.sync(&path, &relative.join(name), keep)?,
+                RawEntry::File(file) => {
+                    match existing {
+                        Some(file_type)
+                            if file_type.is_file() && read(&path)? == *file.contents => {}
+                        // Remove changed files rather than overwriting them, in case they are
+                        // read-only.
+                        Some(file_type) => {
+                            remove(&path, file_type)?;
+                            write(&path, &*file.contents)?;
+                        }
+                        None => write(&path, &*file.contents)?,
+                    }
+                    file.restore_metadata(&path)?;
+                }
+                RawEntry::Symlink(target) => {
+                    match existing {
+                        Some(file_type)
+                            if file_type.is_symlink() && read_link(&path)? == *target =>
+                        {
+                            continue;
+                        }
+                        Some(file_type) => remove(&path, file_type)?,
+                        None => {}
+                    }
+                    symlink(target, &path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Materializes this directory at `path`. See [MaterializeMode::Atomic].
+    fn materialize_atomic(&self, path: &Path) -> io::Result<()> {
+        let Some(name) = path.file_name() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} has no file name", path.display()),
+            ));
+        };
+        let sibling = |suffix| {
+            let mut sibling = OsString::from(".");
+            sibling.push(name);
+            sibling.push(suffix);
+            path.with_file_name(sibling)
+        };
+        let (tmp_path, old_path) = (sibling(".tmp"), sibling(".old"));
+        // Clean up after a previous interrupted write.
+        for stale in [&tmp_path, &old_path] {
+            if let Some(file_type) = file_type(stale)? {
+                remove(stale, file_type)?;
+            }
+        }
+        self.materialize(&tmp_path)?;
+        match rename(path, &old_path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        rename(&tmp_path, path)?;
+        match remove_dir_all(&old_path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the type of the file at `path` (without following symlinks), or `None` if it does not
+/// exist.
+fn file_type(path: &Path) -> io::Result<Option<FileType>> {
+    match symlink_metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.file_type())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Removes the file, symlink, or directory (including its contents) at `path`.
+fn remove(path: &Path, file_type: FileType) -> io::Result<()> {
+    match file_type.is_dir() {
+        true => remove_dir_all(path),
+        false => remove_file(path),
+    }
+}
+
+#[cfg(all(test, unix, not(miri)))]
+mod tests {
+    use super::*;
+    use crate::test_util::tempdir;
+    use std::fs::{File, metadata, read_to_string};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn incremental() {
+        let tempdir = tempdir().unwrap();
+        let out = tempdir.path().join("out");
+        let mut dir = RawDir::default();
+        dir.set_file("src/main.rs", b"fn main() {}".into()).unwrap();
+        dir.set_file("src/lib.rs", b"".into()).unwrap();
+        dir.set_file("build.rs", b"".into()).unwrap();
+        dir.set_symlink("main.rs", "src/main.rs").unwrap();
+        let mode = MaterializeMode::Incremental {
+            keep: vec!["target".into(), "src/generated.rs".into()],
+        };
+        dir.materialize_with(&out, &mode).unwrap();
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(out.join("src/main.rs"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        create_dir(out.join("target")).unwrap();
+        write(out.join("src/generated.rs"), "").unwrap();
+        write(out.join("extra.rs"), "").unwrap();
+
+        dir.write_file("src/lib.rs", b"pub fn f() {}".into())
+            .unwrap();
+        dir.remove("build.rs").unwrap();
+        dir.set_file("build.rs/mod.rs", b"".into()).unwrap();
+        dir.remove("main.rs").unwrap();
+        dir.set_symlink("main.rs", "src/lib.rs").unwrap();
+        dir.materialize_with(&out, &mode).unwrap();
+        let modified = metadata(out.join("src/main.rs")).unwrap().modified();
+        assert_eq!(modified.unwrap(), old);
+        assert_eq!(
+            read_to_string(out.join("main.rs")).unwrap(),
+            "pub fn f() {}"
+        );
+        assert!(out.join("build.rs/mod.rs").is_file());
+        assert!(out.join("target").is_dir());
+        assert!(out.join("src/generated.rs").exists());
+        assert!(!out.join("extra.rs").exists());
+        let mut materialized = RawDir::populate_from(read_dir(&out).unwrap()).unwrap().0;
+        materialized.remove("src/generated.rs").unwrap();
+        assert_eq!(materialized.diff(&dir), "");
+        assert_eq!(materialized.symlinks_recursive(), dir.symlinks_recursive());
+    }
+
+    #[test]
+    fn atomic() {
+        let tempdir = tempdir().unwrap();
+        let out = tempdir.path().join("out");
+        let mut dir = RawDir::default();
+        dir.set_file("src/main.rs", b"fn main() {}".into()).unwrap();
+        create_dir(&out).unwrap();
+        write(out.join("stale.rs"), "").unwrap();
+        // Leftovers from an interrupted write.
+        create_dir(tempdir.path().join(".out.tmp")).unwrap();
+        write(tempdir.path().join(".out.tmp/partial.rs"), "").unwrap();
+        dir.materialize_with(&out, &MaterializeMode::Atomic)
+            .unwrap();
+        let materialized = RawDir::populate_from(read_dir(&out).unwrap()).unwrap().0;
+        assert_eq!(materialized.diff(&dir), "");
+        let names: Vec<_> = read_dir(tempdir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["out"]);
+    }
+}
@@ -0,0 +1,1857 @@
+//! Types representing a filesystem. Example use cases: representing a C source project, a Cargo
+//! project, etc.
+
+use globset::GlobBuilder;
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, btree_map};
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{DirEntry, File, ReadDir, canonicalize, metadata, read_link, symlink_metadata};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use tracing::warn;
+
+mod archive;
+mod blob;
+mod materialize;
+mod patch;
+
+pub use archive::{Compression, TarError};
+pub use blob::{Blob, ContentHash};
+pub use materialize::MaterializeMode;
+pub use patch::PatchError;
+
+/// The names of the ignore files honored by [PopulateFilter::ignore_files].
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".harvestignore"];
+
+/// The maximum number of symlinks [RawDir::get_file] will follow while resolving a single path,
+/// after which it assumes the symlinks form a loop. This matches Linux's limit.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// A representation of a file-system directory entry.
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub enum RawEntry {
+    Dir(RawDir),
+    File(RawFile),
+    /// A symbolic link, containing the link's target. The target is a relative path that does not
+    /// point outside the [RawDir] the link was loaded into, even when the symlinks it leads through
+    /// are followed.
+    Symlink(PathBuf),
+}
+
+impl RawEntry {
+    fn dir(&self) -> Option<&RawDir> {
+        match self {
+            RawEntry::Dir(raw_dir) => Some(raw_dir),
+            _ => None,
+        }
+    }
+
+    fn file(&self) -> Option<&RawFile> {
+        match self {
+            RawEntry::File(file) => Some(file),
+            _ => None,
+        }
+    }
+
+    fn symlink(&self) -> Option<&Path> {
+        match self {
+            RawEntry::Symlink(target) => Some(target),
+            _ => None,
+        }
+    }
+}
+
+/// A file in a [RawDir]: its contents, and the metadata that [RawDir::materialize] restores.
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct RawFile {
+    pub contents: Blob,
+    /// Unix permission bits (e.g. `0o755`). If `None`, the file is created with the default
+    /// permissions.
+    pub mode: Option<u32>,
+    /// Modification time. If `None`, the file's modification time is when it was materialized.
+    pub mtime: Option<SystemTime>,
+}
+
+impl RawFile {
+    /// Returns a file with the given contents and no recorded metadata.
+    pub fn new(contents: Blob) -> RawFile {
+        RawFile {
+            contents,
+            mode: None,
+            mtime: None,
+        }
+    }
+}
+
+/// What [RawDir::populate_from_with] does with a symlink whose target is outside the directory
+/// being loaded (including symlinks with absolute targets).
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EscapingSymlinks {
+    /// Return an error.
+    #[default]
+    Reject,
+    /// Load the link's target (recursively, if it is a directory) in place of the link. Symlinks
+    /// inside the target are followed as well.
+    Follow,
+}
+
+/// Options for [RawDir::populate_from_with].
+#[derive(Clone, Debug, Default)]
+pub struct PopulateOptions {
+    pub escaping_symlinks: EscapingSymlinks,
+    /// Whether to record files' modification times. Unix permission bits are always recorded.
+    pub preserve_mtimes: bool,
+    pub filter: PopulateFilter,
+}
+
+/// Determines which paths [RawDir::populate_from_with] skips. The default filter skips nothing.
+#[derive(Clone, Debug, Default)]
+pub struct PopulateFilter {
+    /// Whether to honor `.gitignore` and `.harvestignore` files. As in git, an ignore file applies
+    /// to the directory containing it and its subdirectories, and deeper ignore files take
+    /// precedence.
+    pub ignore_files: bool,
+    /// Additional patterns (in gitignore syntax, relative to the root directory) to skip. These
+    /// take precedence over ignore files.
+    pub exclude: Vec<String>,
+    /// Files larger than this many bytes are skipped.
+    pub max_file_size: Option<u64>,
+}
+
+/// The result of [RawDir::populate_from_with].
+#[derive(Debug)]
+pub struct Populated {
+    pub dir: RawDir,
+    /// The number of directories loaded.
+    pub directories: usize,
+    /// The number of files loaded.
+    pub files: usize,
+    /// The paths (relative to the root) that were skipped by the [PopulateFilter], and why.
+    pub skipped: Vec<(PathBuf, SkipReason)>,
+}
+
+/// Why [RawDir::populate_from_with] skipped a path.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SkipReason {
+    /// The path matches an ignore file or exclude pattern.
+    Ignored,
+    /// The file is larger than the maximum file size. Contains the file's size.
+    TooLarge(u64),
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SkipReason::Ignored => f.write_str("ignored"),
+            SkipReason::TooLarge(size) => write!(f, "too large ({size} bytes)"),
+        }
+    }
+}
+
+/// A representation of a file-system directory tree. File contents are stored as [Blob]s, so
+/// cloning a `RawDir` is cheap.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct RawDir(BTreeMap<OsString, RawEntry>);
+
+impl RawDir {
+    /// Create a [RawDir] from a local file system directory
+    ///
+    /// Returns the [RawDir], number of directories and number of
+    /// files, as a tuple.
+    ///
+    /// # Arguments
+    ///
+    /// * `read_dir` - a [ReadDir] iterator over a file-system
+    ///   directory.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use harvest_core::fs::RawDir;
+    /// # #[cfg(miri)] fn main() {}
+    /// # #[cfg(not(miri))]
+    /// # fn main() -> std::io::Result<()> {
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # let path = dir.path();
+    /// let (raw_dir, num_dirs, num_files) = RawDir::populate_from(std::fs::read_dir(path)?)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn populate_from(read_dir: ReadDir) -> std::io::Result<(Self, usize, usize)> {
+        let populated = RawDir::populate_from_with(read_dir, &PopulateOptions::default())?;
+        Ok((populated.dir, populated.directories, populated.files))
+    }
+
+    /// Like [RawDir::populate_from], but with configurable [PopulateOptions].
+    ///
+    /// Symlinks whose targets are inside the directory are loaded as [RawEntry::Symlink]s, and are
+    /// not followed. `options.escaping_symlinks` determines what happens to other symlinks.
+    /// Paths rejected by `options.filter` are left out, and are listed in [Populated::skipped].
+    pub fn populate_from_with(
+        read_dir: ReadDir,
+        options: &PopulateOptions,
+    ) -> std::io::Result<Populated> {
+        let mut populator = Populator {
+            options,
+            directories: 0,
+            files: 0,
+            ancestors: vec![],
+            path: PathBuf::new(),
+            exclude: exclude_matcher(&options.filter.exclude)?,
+            ignores: vec![],
+            skipped: vec![],
+        };
+        let dir = populator.populate(read_dir, Some(0))?;
+        Ok(Populated {
+            dir,
+            directories: populator.directories,
+            files: populator.files,
+            skipped: populator.skipped,
+        })
+    }
+
+    /// Applies `filter` to this directory the same way [RawDir::populate_from_with] applies it to
+    /// a directory on disk, e.g. to filter a directory loaded with [RawDir::from_tar]. Ignore files
+    /// are read from this directory. The counts in the returned [Populated] are of the directories
+    /// and files that were kept.
+    pub fn filtered(&self, filter: &PopulateFilter) -> io::Result<Populated> {
+        let mut filterer = Filterer {
+            filter,
+            root: self,
+            directories: 0,
+            files: 0,
+            path: PathBuf::new(),
+            exclude: exclude_matcher(&filter.exclude)?,
+            ignores: vec![],
+            skipped: vec![],
+        };
+        let dir = filterer.filter(self)?;
+        Ok(Populated {
+            dir,
+            directories: filterer.directories,
+            files: filterer.files,
+            skipped: filterer.skipped,
+        })
+    }
+
+    /// Print a representation of the directory to standard out.
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - The level of this directory relative to the
+    ///   root. Used to add padding to before entry names.
+    pub fn display(&self, level: usize, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pad = "  ".repeat(level);
+        for (name, entry) in self
+            .0
+            .iter()
+            .filter_map(|(name, entry)| entry.dir().map(|e| (name, e)))
+        {
+            writeln!(f, "{pad}{}", name.to_string_lossy())?;
+            entry.display(1, f)?;
+        }
+
+        for (name, entry) in self
+            .0
+            .iter()
+            .filter_map(|(name, entry)| entry.file().map(|e| (name, e)))
+        {
+            writeln!(
+                f,
+                "{pad}{} ({}B)",
+                name.to_string_lossy(),
+                entry.contents.len()
+            )?;
+        }
+
+        for (name, target) in self
+            .0
+            .iter()
+            .filter_map(|(name, entry)| entry.symlink().map(|e| (name, e)))
+        {
+            writeln!(f, "{pad}{} -> {}", name.to_string_lossy(), target.display())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the entries directly inside this directory, sorted by name.
+    pub fn entries(&self) -> impl Iterator<Item = (&OsStr, &RawEntry)> {
+        self.0.iter().map(|(name, entry)| (name.as_os_str(), entry))
+    }
+
+    /// Returns an iterator over every entry in this directory and its subdirectories, along with
+    /// its path (relative to this directory). Entries are visited in order of path, with each
+    /// directory visited before its contents.
+    pub fn walk(&self) -> Walk<'_> {
+        Walk {
+            stack: vec![self.0.iter()],
+            path: PathBuf::new(),
+        }
+    }
+
+    /// Returns the path and contents of the files in this directory and its subdirectories, in
+    /// order of path. Paths are relative to this directory. Symlinks are not included.
+    pub fn files(&self) -> impl Iterator<Item = (PathBuf, &[u8])> {
+        self.blobs().map(|(path, contents)| (path, &**contents))
+    }
+
+    /// Like [RawDir::files], but returns the files' [Blob]s.
+    pub fn blobs(&self) -> impl Iterator<Item = (PathBuf, &Blob)> {
+        self.walk()
+            .filter_map(|(path, entry)| Some((path, &entry.file()?.contents)))
+    }
+
+    /// Returns the files whose paths (relative to this directory) match `pattern`, in order of
+    /// path. `pattern` uses glob syntax, in which `*` does not match `/` and `**` matches any
+    /// number of directories. For example, `src/**/*.c` matches all C files under `src/`.
+    pub fn glob(&self, pattern: &str) -> Result<impl Iterator<Item = (PathBuf, &[u8])>, GlobError> {
+        let matcher = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+        Ok(self.files().filter(move |(path, _)| matcher.is_match(path)))
+    }
+
+    /// Returns the path and contents of the files in this directory and its subdirectories. Paths
+    /// are relative to this directory. Symlinks are not included. Prefer [RawDir::files] if you
+    /// do not need a `Vec`.
+    pub fn files_recursive(&self) -> Vec<(PathBuf, &[u8])> {
+        self.files().collect()
+    }
+
+    /// Returns the path and target of the symlinks in this directory and its subdirectories.
+    /// Paths are relative to this directory.
+    pub fn symlinks_recursive(&self) -> Vec<(PathBuf, &Path)> {
+        self.walk()
+            .filter_map(|(path, entry)| Some((path, entry.symlink()?)))
+            .collect()
+    }
+
+    /// Gets the contents of a file at the given path. The file must
+    /// exist. On success, returns a reference to file's contents.
+    /// Clone the returned [Blob] to share the contents without copying them.
+    ///
+    /// `path` must be a relative path. Symlinks are followed (including
+    /// symlinks to symlinks, up to a limit that catches symlink loops),
+    /// and `..` refers to the parent of the directory the previous
+    /// components resolved to, as it does in a real file system.
+    pub fn get_file<P: AsRef<Path>>(&self, path: P) -> Result<&Blob, GetFileError> {
+        Ok(&self.raw_file(path.as_ref())?.contents)
+    }
+
+    /// Mutable version of [RawDir::get_file]. [Blob]s are immutable, so change the file by
+    /// replacing the returned [Blob].
+    pub fn get_file_mut<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Blob, GetFileError> {
+        Ok(&mut self.raw_file_mut(path.as_ref())?.contents)
+    }
+
+    /// Returns the directory at `path`, which is resolved the same way as in [RawDir::get_file].
+    /// An empty path or `.` refers to this directory. Use [RawDir::entries] to list the returned
+    /// directory's contents.
+    pub fn get_dir<P: AsRef<Path>>(&self, path: P) -> Result<&RawDir, GetFileError> {
+        let path = path.as_ref();
+        if path.as_os_str().is_empty() {
+            return Ok(self);
+        }
+        let names = self.resolve(path, true)?;
+        match self.entry_at(&names) {
+            None => Ok(self),
+            Some(RawEntry::Dir(dir)) => Ok(dir),
+            Some(_) => Err(GetFileError::NotDirectory),
+        }
+    }
+
+    /// Returns the Unix permission bits of the file at `path`, if they are known. `path` is
+    /// resolved the same way as in [RawDir::get_file].
+    pub fn get_mode<P: AsRef<Path>>(&self, path: P) -> Result<Option<u32>, GetFileError> {
+        Ok(self.raw_file(path.as_ref())?.mode)
+    }
+
+    /// Sets the Unix permission bits of the file at `path`. `path` is resolved the same way as in
+    /// [RawDir::get_file].
+    pub fn set_mode<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> Result<(), GetFileError> {
+        self.raw_file_mut(path.as_ref())?.mode = Some(mode);
+        Ok(())
+    }
+
+    /// Makes the file at `path` executable (or not executable) by everyone who can read it. Files
+    /// with unknown permissions are treated as having mode `0o644`. `path` is resolved the same
+    /// way as in [RawDir::get_file].
+    pub fn set_executable<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        executable: bool,
+    ) -> Result<(), GetFileError> {
+        let file = self.raw_file_mut(path.as_ref())?;
+        let mode = file.mode.unwrap_or(0o644);
+        // Executable by whoever can read the file.
+        let read_bits = (mode & 0o444) >> 2;
+        file.mode = Some(match executable {
+            true => mode | read_bits,
+            false => mode & !0o111,
+        });
+        Ok(())
+    }
+
+    /// Creates a new file at the given path. The file must not already exist. On success, returns
+    /// a reference to the newly-added file.
+    ///
+    /// `path` must be a relative path. `..` is resolved lexically: it just removes the
+    /// previously-specified directory. `set_file` does not follow symlinks, and errors if the path
+    /// leads through one.
+    pub fn set_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        contents: Blob,
+    ) -> Result<&mut Blob, SetFileError> {
+        let entry = RawEntry::File(RawFile::new(contents));
+        let RawEntry::File(out) = self.insert_entry(path.as_ref(), entry)? else {
+            panic!("RawEntry::File stopped being a file");
+        };
+        Ok(&mut out.contents)
+    }
+
+    /// Like [RawDir::set_file], but if there is already a file at `path`, replaces its contents
+    /// (keeping its permissions). A symlink at `path` is replaced with the new file.
+    pub fn write_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        contents: Blob,
+    ) -> Result<&mut Blob, SetFileError> {
+        let (dirs, name) = split_path(path.as_ref())?;
+        let entry = match self.create_dirs(&dirs)?.0.entry(name.into()) {
+            btree_map::Entry::Occupied(entry) => match entry.into_mut() {
+                RawEntry::Dir(_) => return Err(SetFileError::Directory),
+                RawEntry::File(file) => {
+                    file.mtime = None;
+                    file.contents = contents;
+                    return Ok(&mut file.contents);
+                }
+                entry => {
+                    *entry = RawEntry::File(RawFile::new(contents));
+                    entry
+                }
+            },
+            btree_map::Entry::Vacant(entry) => entry.insert(RawEntry::File(RawFile::new(contents))),
+        };
+        let RawEntry::File(file) = entry else {
+            panic!("RawEntry::File stopped being a file");
+        };
+        Ok(&mut file.contents)
+    }
+
+    /// Creates a new symlink at `path` pointing to `target`. `path` is handled the same way as in
+    /// [RawDir::set_file]. `target` must be a relative path that does not lead outside this
+    /// directory.
+    pub fn set_symlink<P: AsRef<Path>, T: Into<PathBuf>>(
+        &mut self,
+        path: P,
+        target: T,
+    ) -> Result<(), SetFileError> {
+        self.insert_entry(path.as_ref(), RawEntry::Symlink(target.into()))?;
+        Ok(())
+    }
+
+    /// Removes the file, symlink, or directory (including its contents) at `path`, returning it.
+    /// Symlinks in `path` are followed as in [RawDir::get_file], except for the last component:
+    /// if `path` names a symlink, the symlink itself is removed.
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> Result<RawEntry, GetFileError> {
+        let names = self.resolve(path.as_ref(), false)?;
+        let (name, dirs) = names.split_last().ok_or(GetFileError::Root)?;
+        Ok(self
+            .resolved_dir_mut(dirs)
+            .0
+            .remove(name)
+            .expect("missing entry"))
+    }
+
+    /// Moves the file, symlink, or directory at `from` to `to`. `from` is resolved as in
+    /// [RawDir::remove], and `to` is handled as in [RawDir::set_file] (so it must not exist). On
+    /// error, nothing is moved.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+    ) -> Result<(), RenameError> {
+        let names = self
+            .resolve(from.as_ref(), false)
+            .map_err(RenameError::From)?;
+        let (name, dirs) = names
+            .split_last()
+            .ok_or(RenameError::From(GetFileError::Root))?;
+        let (to_dirs, to_name) = split_path(to.as_ref()).map_err(RenameError::To)?;
+        let to_names = to_dirs.iter().chain([&to_name]);
+        if to_dirs.len() >= names.len() && to_names.zip(&names).all(|(a, b)| a == b) {
+            return Err(RenameError::IntoItself);
+        }
+        // Check the destination before removing the entry, so that failing leaves self unchanged.
+        // The destination's parent directory, if it already exists.
+        let mut dir = Some(&*self);
+        // The number of the destination's parent directories that already exist.
+        let mut existing = 0;
+        for &dir_name in &to_dirs {
+            let Some(parent) = dir else {
+                break;
+            };
+            dir = match parent.0.get(dir_name) {
+                None => None,
+                Some(RawEntry::Dir(next)) => {
+                    existing += 1;
+                    Some(next)
+                }
+                Some(RawEntry::File(_)) => return Err(RenameError::To(SetFileError::UnderFile)),
+                Some(RawEntry::Symlink(_)) => {
+                    return Err(RenameError::To(SetFileError::UnderSymlink));
+                }
+            };
+        }
+        if dir.is_some_and(|dir| dir.0.contains_key(to_name)) {
+            return Err(RenameError::To(SetFileError::AlreadyExists));
+        }
+        let entry = self
+            .resolved_dir_mut(dirs)
+            .0
+            .remove(name)
+            .expect("missing entry");
+        self.create_dirs(&to_dirs)
+            .expect("destination checked")
+            .0
+            .insert(to_name.into(), entry);
+        // Moving a symlink (or a directory containing symlinks) changes what its target resolves
+        // to, as may moving a directory that other symlinks lead through.
+        if self.escaping_symlink().is_some() {
+            let entry = self
+                .create_dirs(&to_dirs)
+                .expect("destination checked")
+                .0
+                .remove(to_name)
+                .expect("missing entry");
+            self.remove_created_dirs(&to_dirs, existing);
+            self.resolved_dir_mut(dirs).0.insert(name.clone(), entry);
+            return Err(RenameError::To(SetFileError::EscapingSymlink));
+        }
+        Ok(())
+    }
+
+    /// Returns the file at `path`, which is resolved as described in [RawDir::get_file].
+    fn raw_file(&self, path: &Path) -> Result<&RawFile, GetFileError> {
+        let names = self.resolve(path, true)?;
+        match self.entry_at(&names) {
+            Some(RawEntry::File(file)) => Ok(file),
+            _ => Err(GetFileError::Directory),
+        }
+    }
+
+    /// Mutable version of [RawDir::raw_file].
+    fn raw_file_mut(&mut self, path: &Path) -> Result<&mut RawFile, GetFileError> {
+        let names = self.resolve(path, true)?;
+        let Some((name, dirs)) = names.split_last() else {
+            return Err(GetFileError::Directory);
+        };
+        match self.resolved_dir_mut(dirs).0.get_mut(name) {
+            Some(RawEntry::File(file)) => Ok(file),
+            _ => Err(GetFileError::Directory),
+        }
+    }
+
+    /// Returns the entry reached by following `names`, which must have been returned by
+    /// [RawDir::resolve]. Returns `None` if `names` is empty (i.e. it refers to `self`).
+    fn entry_at(&self, names: &[OsString]) -> Option<&RawEntry> {
+        let (name, dirs) = names.split_last()?;
+        let mut dir = self;
+        for dir_name in dirs {
+            dir = dir
+                .0
+                .get(dir_name)
+                .and_then(RawEntry::dir)
+                .expect("missing dir");
+        }
+        Some(dir.0.get(name).expect("missing entry"))
+    }
+
+    /// Returns the first symlink (in order of path) whose target resolves outside this directory,
+    /// following the other symlinks in this directory (see [escapes]).
+    fn escaping_symlink(&self) -> Option<PathBuf> {
+        let lookup = |path: &Path| {
+            let mut dir = self;
+            let mut components = path.iter().peekable();
+            while let Some(name) = components.next() {
+                match (dir.0.get(name), components.peek()) {
+                    (Some(RawEntry::Dir(next)), _) => dir = next,
+                    (Some(RawEntry::Symlink(target)), None) => {
+                        return Node::Symlink(target.clone());
+                    }
+                    _ => return Node::Other,
+                }
+            }
+            Node::Dir
+        };
+        self.walk().find_map(|(path, entry)| {
+            let target = entry.symlink()?;
+            escapes(path.parent()?, target, lookup).then_some(path)
+        })
+    }
+
+    /// Returns the directory reached by following `names`, which must be directory names returned
+    /// by [RawDir::resolve].
+    fn resolved_dir_mut(&mut self, names: &[OsString]) -> &mut RawDir {
+        let mut dir = self;
+        for name in names {
+            let Some(RawEntry::Dir(next)) = dir.0.get_mut(name) else {
+                panic!("missing dir");
+            };
+            dir = next;
+        }
+        dir
+    }
+
+    /// Resolves `path` (following symlinks) to an existing entry, returning the names of the
+    /// directories that lead to the entry followed by the entry's name. The names are empty if
+    /// `path` resolves to `self`. If `follow_last` is false and the last component of `path` is a
+    /// symlink, the symlink itself is returned.
+    ///
+    /// Paths that end in `.` or `..` must resolve to directories.
+    fn resolve(&self, path: &Path, follow_last: bool) -> Result<Vec<OsString>, GetFileError> {
+        let must_be_dir = match path.components().next_back() {
+            None => return Err(GetFileError::DoesNotExist),
+            Some(Component::Prefix(_) | Component::RootDir) => {
+                return Err(GetFileError::AbsolutePath);
+            }
+            Some(Component::CurDir | Component::ParentDir) => true,
+            Some(Component::Normal(_)) => false,
+        };
+        // The components that still need to be resolved, in reverse order.
+        let mut pending: Vec<_> = path.components().rev().collect();
+        if pending
+            .iter()
+            .any(|c| matches!(c, Component::Prefix(_) | Component::RootDir))
+        {
+            return Err(GetFileError::AbsolutePath);
+        }
+        // The directories the resolved components lead through, starting with self, and their
+        // names (not including self).
+        let mut dirs = vec![self];
+        let mut names = vec![];
+        let mut hops = 0;
+        while let Some(component) = pending.pop() {
+            let name = match component {
+                Component::CurDir => continue,
+                Component::ParentDir => {
+                    if dirs.len() == 1 {
+                        return Err(GetFileError::OutsideDir);
+                    }
+                    dirs.pop();
+                    names.pop();
+                    continue;
+                }
+                Component::Normal(name) => name,
+                // Symlink targets are relative, so they cannot contain these either.
+                Component::Prefix(_) | Component::RootDir => {
+                    return Err(GetFileError::AbsolutePath);
+                }
+            };
+            let cur_dir = dirs.last().expect("dirs is never empty");
+            match cur_dir.0.get(name).ok_or(GetFileError::DoesNotExist)? {
+                RawEntry::Dir(dir) => {
+                    dirs.push(dir);
+                    names.push(name);
+                }
+                RawEntry::File(_) if pending.is_empty() => {
+                    names.push(name);
+                    break;
+                }
+                RawEntry::File(_) => return Err(GetFileError::UnderFile),
+                RawEntry::Symlink(_) if pending.is_empty() && !follow_last => {
+                    names.push(name);
+                    break;
+                }
+                RawEntry::Symlink(target) => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(GetFileError::SymlinkLoop);
+                    }
+                    if target.has_root() {
+                        return Err(GetFileError::OutsideDir);
+                    }
+                    pending.extend(target.components().rev());
+                }
+            }
+        }
+        if must_be_dir && dirs.len() != names.len() + 1 {
+            return Err(GetFileError::NotDirectory);
+        }
+        Ok(names.into_iter().map(Into::into).collect())
+    }
+
+    /// Inserts `entry` at `path`, which is handled as described in [RawDir::set_file].
+    fn insert_entry(
+        &mut self,
+        path: &Path,
+        entry: RawEntry,
+    ) -> Result<&mut RawEntry, SetFileError> {
+        let (dirs, name) = split_path(path)?;
+        let is_symlink = matches!(entry, RawEntry::Symlink(_));
+        let existing = self.existing_dirs(&dirs);
+        let btree_map::Entry::Vacant(vacant) = self.create_dirs(&dirs)?.0.entry(name.into()) else {
+            return Err(SetFileError::AlreadyExists);
+        };
+        vacant.insert(entry);
+        // A new symlink can also change where existing symlinks that lead through it resolve.
+        if is_symlink && self.escaping_symlink().is_some() {
+            self.create_dirs(&dirs)?.0.remove(name);
+            self.remove_created_dirs(&dirs, existing);
+            return Err(SetFileError::EscapingSymlink);
+        }
+        Ok(self
+            .create_dirs(&dirs)?
+            .0
+            .get_mut(name)
+            .expect("missing entry"))
+    }
+
+    /// Returns how many of `dirs` (as returned by [split_path]) exist as directories, starting
+    /// from this directory.
+    fn existing_dirs(&self, dirs: &[&OsStr]) -> usize {
+        let mut cur_dir = self;
+        for (i, &dir_name) in dirs.iter().enumerate() {
+            match cur_dir.0.get(dir_name) {
+                Some(RawEntry::Dir(next)) => cur_dir = next,
+                _ => return i,
+            }
+        }
+        dirs.len()
+    }
+
+    /// Removes the directories [RawDir::create_dirs] created for `dirs`, given that the first
+    /// `existing` of them existed beforehand (see [RawDir::existing_dirs]).
+    fn remove_created_dirs(&mut self, dirs: &[&OsStr], existing: usize) {
+        if let Some(&first_created) = dirs.get(existing) {
+            let parent = self.create_dirs(&dirs[..existing]).expect("missing dir");
+            parent.0.remove(first_created);
+        }
+    }
+
+    /// Returns the directory reached by following `dirs` (as returned by [split_path]), creating
+    /// any directories that do not exist.
+    fn create_dirs(&mut self, dirs: &[&OsStr]) -> Result<&mut RawDir, SetFileError> {
+        let mut cur_dir = self;
+        for &dir_name in dirs {
+            let new_dir = cur_dir
+                .0
+                .entry(dir_name.into())
+                .or_insert_with(|| RawEntry::Dir(RawDir::default()));
+            cur_dir = match new_dir {
+                RawEntry::Dir(new_dir) => new_dir,
+                RawEntry::File(_) => return Err(SetFileError::UnderFile),
+                RawEntry::Symlink(_) => return Err(SetFileError::UnderSymlink),
+            };
+        }
+        Ok(cur_dir)
+    }
+
+    /// Returns the files that differ between `self` and `new`, sorted by path. Paths are relative
+    /// to this directory.
+    pub fn changed_files(&self, new: &RawDir) -> Vec<(PathBuf, FileChange)> {
+        // Compare Blobs rather than slices, so that only hashes are compared.
+        let old_files: BTreeMap<_, _> = self.blobs().collect();
+        let new_files: BTreeMap<_, _> = new.blobs().collect();
+        let mut out: Vec<_> = old_files
+            .iter()
+            .filter_map(|(path, contents)| match new_files.get(path) {
+                None => Some((path.clone(), FileChange::Removed)),
+                Some(new_contents) if new_contents != contents => {
+                    Some((path.clone(), FileChange::Modified))
+                }
+                Some(_) => None,
+            })
+            .collect();
+        out.extend(
+            new_files
+                .into_keys()
+                .filter(|path| !old_files.contains_key(path))
+                .map(|path| (path, FileChange::Added)),
+        );
+        out.sort_unstable();
+        out
+    }
+
+    /// Returns a Merkle hash of this directory, computed from the names of its entries, its files'
+    /// contents and permission bits, its symlinks' targets, and (recursively) its subdirectories'
+    /// hashes. Modification times are not included. Directories with the same hash have the same
+    /// contents, so the hash can be used as a cache key. Computing it does not read file
+    /// contents, as each [Blob] already knows its hash.
+    pub fn hash(&self) -> ContentHash {
+        fn update_len_prefixed(hasher: &mut Sha256, bytes: &[u8]) {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+        let mut hasher = Sha256::new();
+        for (name, entry) in &self.0 {
+            update_len_prefixed(&mut hasher, name.as_encoded_bytes());
+            match entry {
+                RawEntry::Dir(dir) => {
+                    hasher.update(b"d");
+                    hasher.update(dir.hash().as_bytes());
+                }
+                RawEntry::File(file) => {
+                    hasher.update(b"f");
+                    hasher.update(file.contents.hash().as_bytes());
+                    match file.mode {
+                        None => hasher.update(b"-"),
+                        Some(mode) => {
+                            hasher.update([b"m".as_slice(), &mode.to_le_bytes()].concat())
+                        }
+                    }
+                }
+                RawEntry::Symlink(target) => {
+                    hasher.update(b"l");
+                    update_len_prefixed(&mut hasher, target.as_os_str().as_encoded_bytes());
+                }
+            }
+        }
+        ContentHash::from_hasher(hasher)
+    }
+
+    /// Materializes the [RawDir] to the file system.
+    ///
+    /// `path` is a path to an empty or non-existent directory noting
+    /// where the file system should be materialized to. See
+    /// [RawDir::materialize_with] for other ways to materialize.
+    pub fn materialize<P: AsRef<Path>>(&self, base_path: P) -> std::io::Result<()> {
+        let base_path = base_path.as_ref();
+        for (path, entry) in self.walk() {
+            let RawEntry::File(file) = entry else {
+                continue;
+            };
+            let file_path = base_path.join(path);
+            if let Some(parent) = file_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&file_path, &*file.contents)?;
+            file.restore_metadata(&file_path)?;
+        }
+        for (link_path, target) in self.symlinks_recursive() {
+            let link_path = base_path.join(link_path);
+            if let Some(parent) = link_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            symlink(target, &link_path)?;
+        }
+        Ok(())
+    }
+}
+
+impl RawFile {
+    /// Sets the metadata of the file at `path` (which contains this file's contents) to match
+    /// this file.
+    fn restore_metadata(&self, path: &Path) -> io::Result<()> {
+        // Set the modification time first, as the permissions may make the file read-only.
+        if let Some(mtime) = self.mtime {
+            File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(mtime)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates a symlink at `link` pointing to `target`.
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Creates a symlink at `link` pointing to `target`.
+#[cfg(not(unix))]
+fn symlink(_target: &Path, link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot create symlink {}", link.display()),
+    ))
+}
+
+/// Splits `path` lexically (see [RawDir::set_file]) into the names of the directories leading to
+/// an entry and the entry's name.
+fn split_path(path: &Path) -> Result<(Vec<&OsStr>, &OsStr), SetFileError> {
+    let mut segments = vec![];
+    // Whether the most-recently-processed entry can be a file.
+    let mut last_can_be_file = true;
+    for component in path.components() {
+        last_can_be_file = match component {
+            Component::CurDir => false,
+            Component::Normal(name) => {
+                segments.push(name);
+                true
+            }
+            Component::ParentDir => {
+                if segments.pop().is_none() {
+                    return Err(SetFileError::OutsideDir);
+                }
+                false
+            }
+            Component::Prefix(_) | Component::RootDir => {
+                return Err(SetFileError::AbsolutePath);
+            }
+        };
+    }
+    if !last_can_be_file {
+        return Err(SetFileError::Directory);
+    }
+    match segments.pop() {
+        None => Err(SetFileError::EmptyFileName),
+        Some(empty) if empty.is_empty() => Err(SetFileError::EmptyFileName),
+        Some(name) => Ok((segments, name)),
+    }
+}
+
+/// What a path in a directory tree refers to, for the purposes of [escapes].
+enum Node {
+    Dir,
+    Symlink(PathBuf),
+    /// A file, or something that does not exist.
+    Other,
+}
+
+/// Returns true if `target`, the target of a symlink in the directory `dir` (a path relative to
+/// the root of a directory tree), resolves to a path outside the root. `lookup` returns what a
+/// path (relative to the root, without symlinks) refers to.
+///
+/// Symlinks the target leads through are followed, as they would be on disk, so a target such as
+/// `sub/link/..` escapes if `sub/link` is a symlink to `..`. Past a component that does not exist,
+/// the target is resolved lexically. Symlink loops are treated as escaping.
+fn escapes(dir: &Path, target: &Path, lookup: impl Fn(&Path) -> Node) -> bool {
+    /// Adds the components of `target` to `pending`. Returns false if `target` is absolute.
+    fn push_target(pending: &mut Vec<Option<OsString>>, target: &Path) -> bool {
+        for component in target.components().rev() {
+            match component {
+                Component::Prefix(_) | Component::RootDir => return false,
+                Component::CurDir => {}
+                Component::ParentDir => pending.push(None),
+                Component::Normal(name) => pending.push(Some(name.to_owned())),
+            }
+        }
+        true
+    }
+    // The components still to resolve, in reverse order. `None` is `..`.
+    let mut pending = vec![];
+    if !push_target(&mut pending, target) {
+        return true;
+    }
+    let mut path = dir.to_path_buf();
+    // The number of trailing components of `path` that are not directories.
+    let mut missing: usize = 0;
+    let mut hops = 0;
+    while let Some(component) = pending.pop() {
+        let Some(name) = component else {
+            if !path.pop() {
+                return true;
+            }
+            missing = missing.saturating_sub(1);
+            continue;
+        };
+        path.push(&name);
+        if missing > 0 {
+            missing += 1;
+            continue;
+        }
+        match lookup(&path) {
+            Node::Dir => {}
+            Node::Other => missing = 1,
+            Node::Symlink(link_target) => {
+                path.pop();
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS || !push_target(&mut pending, &link_target) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+/// State for [RawDir::populate_from_with].
+struct Populator<'o> {
+    options: &'o PopulateOptions,
+    directories: usize,
+    files: usize,
+    // The canonical paths of the directories currently being loaded, used to detect loops when
+    // following symlinks.
+    ancestors: Vec<PathBuf>,
+    // The path (relative to the root) of the entry currently being loaded.
+    path: PathBuf,
+    // Matcher for options.filter.exclude.
+    exclude: Gitignore,
+    // The ignore files that apply to the current entry, outermost first, along with the number of
+    // components in the path of the directory containing them.
+    ignores: Vec<(usize, Gitignore)>,
+    skipped: Vec<(PathBuf, SkipReason)>,
+}
+
+impl Populator<'_> {
+    /// Loads the entries in `read_dir`. `depth` is the directory's depth below the root, or `None`
+    /// if it was reached by following a symlink (in which case all symlinks are escaping).
+    fn populate(&mut self, read_dir: ReadDir, depth: Option<usize>) -> io::Result<RawDir> {
+        let entries = read_dir.collect::<io::Result<Vec<_>>>()?;
+        let ignore = match self.options.filter.ignore_files {
+            false => None,
+            true => ignore_file_matcher(&entries)?,
+        };
+        let has_ignore = ignore.is_some();
+        if let Some(ignore) = ignore {
+            self.ignores.push((self.path.components().count(), ignore));
+        }
+        let mut result = BTreeMap::default();
+        for entry in entries {
+            self.path.push(entry.file_name());
+            let raw_entry = self.entry(&entry, depth);
+            self.path.pop();
+            if let Some(raw_entry) = raw_entry? {
+                result.insert(entry.file_name(), raw_entry);
+            }
+        }
+        if has_ignore {
+            self.ignores.pop();
+        }
+        Ok(RawDir(result))
+    }
+
+    /// Loads `entry`, which is in a directory at `depth` (see [Populator::populate]). Returns
+    /// `None` if the entry was skipped.
+    fn entry(&mut self, entry: &DirEntry, depth: Option<usize>) -> io::Result<Option<RawEntry>> {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        // Symlinks are matched as directories if they point to one, so that e.g. `build/` matches
+        // a symlink to a build directory.
+        let is_dir = file_type.is_dir()
+            || (file_type.is_symlink() && metadata(&path).is_ok_and(|m| m.is_dir()));
+        if self.ignored(is_dir) {
+            self.skipped.push((self.path.clone(), SkipReason::Ignored));
+            return Ok(None);
+        }
+        if file_type.is_symlink() {
+            let target = read_link(&path)?;
+            // Resolve the target against the directory on disk, so that symlinks it leads through
+            // are taken into account.
+            let root = path
+                .ancestors()
+                .nth(self.path.components().count())
+                .expect("entry path shorter than its relative path");
+            let lookup = |relative: &Path| {
+                let path = root.join(relative);
+                match symlink_metadata(&path) {
+                    Ok(m) if m.is_symlink() => read_link(&path).map_or(Node::Other, Node::Symlink),
+                    Ok(m) if m.is_dir() => Node::Dir,
+                    _ => Node::Other,
+                }
+            };
+            let dir = self.path.parent().expect("entry path is empty");
+            match depth {
+                Some(_) if !escapes(dir, &target, lookup) => Ok(Some(RawEntry::Symlink(target))),
+                _ => match self.options.escaping_symlinks {
+                    EscapingSymlinks::Reject => Err(io::Error::other(format!(
+                        "symlink {} points outside the source directory",
+                        path.display()
+                    ))),
+                    EscapingSymlinks::Follow => self.load(&path, is_dir, None),
+                },
+            }
+        } else if file_type.is_dir() || file_type.is_file() {
+            self.load(&path, is_dir, depth.map(|d| d + 1))
+        } else {
+            Err(io::Error::other(format!(
+                "{} is not a file, directory, or symlink",
+                path.display()
+            )))
+        }
+    }
+
+    /// Loads the file or directory at `path`, which is at `depth` (see [Populator::populate]).
+    /// Returns `None` if the file was skipped.
+    fn load(
+        &mut self,
+        path: &Path,
+        is_dir: bool,
+        depth: Option<usize>,
+    ) -> io::Result<Option<RawEntry>> {
+        if !is_dir {
+            let metadata = metadata(path)?;
+            if let Some(max_file_size) = self.options.filter.max_file_size
+                && metadata.len() > max_file_size
+            {
+                let reason = SkipReason::TooLarge(metadata.len());
+                self.skipped.push((self.path.clone(), reason));
+                return Ok(None);
+            }
+            self.files += 1;
+            #[cfg(unix)]
+            let mode = {
+                use std::os::unix::fs::PermissionsExt;
+                Some(metadata.permissions().mode() & 0o7777)
+            };
+            #[cfg(not(unix))]
+            let mode = None;
+            return Ok(Some(RawEntry::File(RawFile {
+                contents: std::fs::read(path)?.into(),
+                mode,
+                mtime: self
+                    .options
+                    .preserve_mtimes
+                    .then(|| metadata.modified())
+                    .transpose()?,
+            })));
+        }
+        let canonical = canonicalize(path)?;
+        if self.ancestors.contains(&canonical) {
+            return Err(io::Error::other(format!(
+                "symlink loop at {}",
+                path.display()
+            )));
+        }
+        self.ancestors.push(canonical);
+        let dir = self.populate(std::fs::read_dir(path)?, depth);
+        self.ancestors.pop();
+        self.directories += 1;
+        Ok(Some(RawEntry::Dir(dir?)))
+    }
+
+    /// Returns true if the current path should be skipped according to the exclude patterns and
+    /// ignore files.
+    fn ignored(&self, is_dir: bool) -> bool {
+        is_ignored(&self.path, is_dir, &self.exclude, &self.ignores)
+    }
+}
+
+/// State for [RawDir::filtered]. Mirrors [Populator], but reads from a [RawDir].
+struct Filterer<'f> {
+    filter: &'f PopulateFilter,
+    // The directory being filtered, used to resolve symlinks.
+    root: &'f RawDir,
+    directories: usize,
+    files: usize,
+    // The path (relative to the root) of the entry currently being filtered.
+    path: PathBuf,
+    exclude: Gitignore,
+    // As in Populator.
+    ignores: Vec<(usize, Gitignore)>,
+    skipped: Vec<(PathBuf, SkipReason)>,
+}
+
+impl Filterer<'_> {
+    /// Filters the entries of `dir`, which is at the current path.
+    fn filter(&mut self, dir: &RawDir) -> io::Result<RawDir> {
+        let ignore = match self.filter.ignore_files {
+            false => None,
+            true => self.ignore_file_matcher()?,
+        };
+        let has_ignore = ignore.is_some();
+        if let Some(ignore) = ignore {
+            self.ignores.push((self.path.components().count(), ignore));
+        }
+        let mut result = BTreeMap::default();
+        for (name, entry) in &dir.0 {
+            self.path.push(name);
+            let entry = self.entry(entry);
+            self.path.pop();
+            if let Some(entry) = entry? {
+                result.insert(name.clone(), entry);
+            }
+        }
+        if has_ignore {
+            self.ignores.pop();
+        }
+        Ok(RawDir(result))
+    }
+
+    /// Filters `entry`, which is at the current path. Returns `None` if the entry was skipped.
+    fn entry(&mut self, entry: &RawEntry) -> io::Result<Option<RawEntry>> {
+        // As in Populator::entry, symlinks to directories are matched as directories.
+        let is_dir = match entry {
+            RawEntry::Dir(_) => true,
+            RawEntry::File(_) => false,
+            RawEntry::Symlink(_) => self.root.get_dir(&self.path).is_ok(),
+        };
+        if is_ignored(&self.path, is_dir, &self.exclude, &self.ignores) {
+            self.skipped.push((self.path.clone(), SkipReason::Ignored));
+            return Ok(None);
+        }
+        match entry {
+            RawEntry::Dir(dir) => {
+                let dir = self.filter(dir)?;
+                self.directories += 1;
+                Ok(Some(RawEntry::Dir(dir)))
+            }
+            RawEntry::File(file) => {
+                let size = file.contents.len() as u64;
+                if self.filter.max_file_size.is_some_and(|max| size > max) {
+                    let reason = SkipReason::TooLarge(size);
+                    self.skipped.push((self.path.clone(), reason));
+                    return Ok(None);
+                }
+                self.files += 1;
+                Ok(Some(entry.clone()))
+            }
+            RawEntry::Symlink(_) => Ok(Some(entry.clone())),
+        }
+    }
+
+    /// Builds a matcher from the ignore files in the directory at the current path. Returns `None`
+    /// if there are no ignore files.
+    fn ignore_file_matcher(&self) -> io::Result<Option<Gitignore>> {
+        let mut builder = GitignoreBuilder::new(".");
+        let mut found = false;
+        for name in IGNORE_FILES {
+            let path = self.path.join(name);
+            let Ok(contents) = self.root.get_file(&path) else {
+                continue;
+            };
+            found = true;
+            for line in String::from_utf8_lossy(contents).lines() {
+                // Invalid lines are skipped, as git does.
+                if let Err(error) = builder.add_line(None, line) {
+                    warn!("{}: {error}", path.display());
+                }
+            }
+        }
+        match found {
+            false => Ok(None),
+            true => builder.build().map(Some).map_err(io::Error::other),
+        }
+    }
+}
+
+/// Builds a matcher for [PopulateFilter::exclude].
+fn exclude_matcher(patterns: &[String]) -> io::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(".");
+    for pattern in patterns {
+        builder.add_line(None, pattern).map_err(io::Error::other)?;
+    }
+    builder.build().map_err(io::Error::other)
+}
+
+/// Returns true if `path` (relative to the root) should be skipped according to the `exclude`
+/// matcher and the `ignores` that apply to it (see [Populator::ignores]).
+fn is_ignored(
+    path: &Path,
+    is_dir: bool,
+    exclude: &Gitignore,
+    ignores: &[(usize, Gitignore)],
+) -> bool {
+    let ignores = ignores.iter().rev().map(|(base, ignore)| (*base, ignore));
+    for (base, matcher) in [(0, exclude)].into_iter().chain(ignores) {
+        let relative: PathBuf = path.components().skip(base).collect();
+        match matcher.matched(relative, is_dir) {
+            Match::None => continue,
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+        }
+    }
+    false
+}
+
+/// Builds a matcher from the ignore files in `entries`, which are the contents of one directory.
+/// Returns `None` if there are no ignore files.
+fn ignore_file_matcher(entries: &[DirEntry]) -> io::Result<Option<Gitignore>> {
+    let mut builder = GitignoreBuilder::new(".");
+    let mut found = false;
+    for entry in entries {
+        if IGNORE_FILES.iter().any(|name| entry.file_name() == *name) {
+            found = true;
+            // Invalid lines are skipped, as git does.
+            if let Some(error) = builder.add(entry.path()) {
+                warn!("{}: {error}", entry.path().display());
+            }
+        }
+    }
+    match found {
+        false => Ok(None),
+        true => builder.build().map(Some).map_err(io::Error::other),
+    }
+}
+
+/// Iterator returned by [RawDir::walk].
+pub struct Walk<'d> {
+    // Iterators over the directories being walked, outermost first.
+    stack: Vec<btree_map::Iter<'d, OsString, RawEntry>>,
+    // The path of the innermost directory being walked.
+    path: PathBuf,
+}
+
+impl<'d> Iterator for Walk<'d> {
+    type Item = (PathBuf, &'d RawEntry);
+
+    fn next(&mut self) -> Option<(PathBuf, &'d RawEntry)> {
+        loop {
+            let Some((name, entry)) = self.stack.last_mut()?.next() else {
+                self.stack.pop();
+                self.path.pop();
+                continue;
+            };
+            let path = self.path.join(name);
+            if let RawEntry::Dir(dir) = entry {
+                self.stack.push(dir.0.iter());
+                self.path.push(name);
+            }
+            return Some((path, entry));
+        }
+    }
+}
+
+/// How a file changed between two [RawDir]s. Returned by [RawDir::changed_files].
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum FileChange {
+    Added,
+    Modified,
+    Removed,
+}
+
+impl std::fmt::Display for FileChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FileChange::Added => "A",
+            FileChange::Modified => "M",
+            FileChange::Removed => "D",
+        })
+    }
+}
+
+/// Error type returned by [RawDir::set_file].
+#[derive(Debug, Eq, Hash, PartialEq, thiserror::Error)]
+pub enum SetFileError {
+    #[error("tried to set file at absolute path")]
+    AbsolutePath,
+    #[error("file already exists")]
+    AlreadyExists,
+    #[error("tried to set file at directory path")]
+    Directory,
+    #[error("empty file name")]
+    EmptyFileName,
+    #[error("symlink target is outside this directory")]
+    EscapingSymlink,
+    #[error("tried to write file outside this directory")]
+    OutsideDir,
+    #[error("tried to set a file that is under another file")]
+    UnderFile,
+    #[error("tried to set a file that is under a symlink")]
+    UnderSymlink,
+}
+
+/// Error type returned by [RawDir::get_file].
+#[derive(Debug, Eq, Hash, PartialEq, thiserror::Error)]
+pub enum GetFileError {
+    #[error("tried to get file at absolute path")]
+    AbsolutePath,
+    #[error("tried to get file at directory path")]
+    Directory,
+    #[error("tried to get a file outside this directory")]
+    OutsideDir,
+    #[error("tried to get a file that is under another file")]
+    UnderFile,
+    #[error("tried to get a file that does not exist")]
+    DoesNotExist,
+    #[error("too many levels of symlinks")]
+    SymlinkLoop,
+    #[error("tried to list a path that is not a directory")]
+    NotDirectory,
+    #[error("tried to remove or move the root directory")]
+    Root,
+}
+
+/// Error type returned by [RawDir::rename].
+#[derive(Debug, Eq, Hash, PartialEq, thiserror::Error)]
+pub enum RenameError {
+    #[error("invalid source: {0}")]
+    From(GetFileError),
+    #[error("invalid destination: {0}")]
+    To(SetFileError),
+    #[error("tried to move a directory into itself")]
+    IntoItself,
+}
+
+/// Error type returned by [RawDir::glob].
+#[derive(Debug, thiserror::Error)]
+#[error("invalid glob pattern: {0}")]
+pub struct GlobError(#[from] globset::Error);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_recursive() {
+        #[rustfmt::skip]
+        let dir = RawDir([
+            ("dir1".into(), RawEntry::Dir(RawDir([
+                ("dir2".into(), RawEntry::Dir(RawDir([
+                    ("file2.txt".into(), RawEntry::File(RawFile::new(b"B".into()))),
+                ].into_iter().collect()))),
+                ("file3.txt".into(), RawEntry::File(RawFile::new(b"C".into()))),
+            ].into_iter().collect()))),
+            ("file1.txt".into(), RawEntry::File(RawFile::new(b"A".into()))),
+        ].into_iter().collect());
+        assert_eq!(
+            dir.files_recursive(),
+            [
+                (PathBuf::from("dir1/dir2/file2.txt"), b"B".as_slice()),
+                (PathBuf::from("dir1/file3.txt"), b"C".as_slice()),
+                (PathBuf::from("file1.txt"), b"A".as_slice())
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_and_glob() {
+        let mut dir = RawDir::default();
+        dir.set_file("Makefile", b"all:".into()).unwrap();
+        dir.set_file("src/main.c", b"A".into()).unwrap();
+        dir.set_file("src/util/util.c", b"B".into()).unwrap();
+        dir.set_file("src/util/util.h", b"C".into()).unwrap();
+        dir.set_symlink("src/util.h", "util/util.h").unwrap();
+        let walked: Vec<_> = dir.walk().map(|(path, _)| path).collect();
+        assert_eq!(
+            walked,
+            [
+                "Makefile",
+                "src",
+                "src/main.c",
+                "src/util",
+                "src/util/util.c",
+                "src/util/util.h",
+                "src/util.h"
+            ]
+            .map(PathBuf::from)
+        );
+        let c_files: Vec<_> = dir.glob("src/**/*.c").unwrap().collect();
+        assert_eq!(
+            c_files,
+            [
+                (PathBuf::from("src/main.c"), b"A".as_slice()),
+                (PathBuf::from("src/util/util.c"), b"B".as_slice())
+            ]
+        );
+        assert_eq!(dir.glob("src/*.c").unwrap().count(), 1);
+        assert!(dir.glob("src/[").is_err());
+
+        let names: Vec<_> = dir.get_dir("src").unwrap().entries().map(|e| e.0).collect();
+        assert_eq!(names, ["main.c", "util", "util.h"]);
+        assert_eq!(dir.get_dir("src/util/..").unwrap().entries().count(), 3);
+        assert_eq!(dir.get_dir("").unwrap().entries().count(), 2);
+        assert_eq!(
+            dir.get_dir("Makefile").err(),
+            Some(GetFileError::NotDirectory)
+        );
+        assert_eq!(
+            dir.get_dir("Makefile/..").err(),
+            Some(GetFileError::UnderFile)
+        );
+    }
+
+    #[test]
+    fn write_remove_rename() {
+        let mut dir = RawDir::default();
+        dir.set_file("src/main.c", b"A".into()).unwrap();
+        dir.set_executable("src/main.c", true).unwrap();
+        dir.set_symlink("main.c", "src/main.c").unwrap();
+
+        // write_file overwrites files (keeping their mode) and symlinks.
+        dir.write_file("src/main.c", b"B".into()).unwrap();
+        assert_eq!(dir.get_file("main.c"), Ok(&b"B".into()));
+        assert_eq!(dir.get_mode("src/main.c"), Ok(Some(0o755)));
+        let file = dir.get_file_mut("main.c").unwrap();
+        *file = [file, b"C".as_slice()].concat().into();
+        assert_eq!(dir.get_file("src/main.c"), Ok(&b"BC".into()));
+        dir.write_file("main.c", b"D".into()).unwrap();
+        assert!(dir.symlinks_recursive().is_empty());
+        assert_eq!(
+            dir.write_file("src", Blob::default()),
+            Err(SetFileError::Directory)
+        );
+
+        assert_eq!(
+            dir.rename("src/main.c", "main.c"),
+            Err(RenameError::To(SetFileError::AlreadyExists))
+        );
+        assert_eq!(dir.rename("src", "src/lib"), Err(RenameError::IntoItself));
+        assert_eq!(
+            dir.rename("missing", "new"),
+            Err(RenameError::From(GetFileError::DoesNotExist))
+        );
+        assert_eq!(
+            dir.rename("src", "main.c/src"),
+            Err(RenameError::To(SetFileError::UnderFile))
+        );
+        dir.rename("src", "lib/src").unwrap();
+        assert_eq!(dir.get_file("lib/src/main.c"), Ok(&b"BC".into()));
+        assert_eq!(dir.get_dir("src").err(), Some(GetFileError::DoesNotExist));
+
+        dir.set_symlink("lib/link", "../lib/src").unwrap();
+        assert_eq!(
+            dir.rename("lib/link", "link"),
+            Err(RenameError::To(SetFileError::EscapingSymlink))
+        );
+        assert_eq!(dir.get_file("lib/link/main.c"), Ok(&b"BC".into()));
+        // Removing a symlink removes the symlink, not its target.
+        assert!(matches!(dir.remove("lib/link"), Ok(RawEntry::Symlink(_))));
+        assert!(matches!(dir.remove("lib/src"), Ok(RawEntry::Dir(_))));
+        assert_eq!(
+            dir.remove("lib/src").err(),
+            Some(GetFileError::DoesNotExist)
+        );
+        assert_eq!(dir.remove(".").err(), Some(GetFileError::Root));
+        assert_eq!(
+            dir.files_recursive(),
+            [(PathBuf::from("main.c"), b"D".as_slice())]
+        );
+    }
+
+    #[test]
+    fn set_file() {
+        let mut root = RawDir::default();
+        assert!(root.set_file("file1.txt", b"A".into()).is_ok());
+        assert!(root.set_file("dir1/dir2/file2.txt", b"B".into()).is_ok());
+        assert!(root.set_file("dir1/file3.txt", b"C".into()).is_ok());
+        assert_eq!(
+            root.set_file("/etc/passwd", b"D".into()),
+            Err(SetFileError::AbsolutePath)
+        );
+        assert_eq!(
+            root.set_file("dir1/file3.txt", b"E".into()),
+            Err(SetFileError::AlreadyExists)
+        );
+        assert_eq!(
+            root.set_file(".", b"F".into()),
+            Err(SetFileError::Directory)
+        );
+        assert_eq!(
+            root.set_file("dir1/dir2/..", b"G".into()),
+            Err(SetFileError::Directory)
+        );
+        assert_eq!(
+            root.set_file("", b"H".into()),
+            Err(SetFileError::EmptyFileName)
+        );
+        assert_eq!(
+            root.set_file("../", b"I".into()),
+            Err(SetFileError::OutsideDir)
+        );
+        assert_eq!(
+            root.set_file("dir1/../../", b"J".into()),
+            Err(SetFileError::OutsideDir)
+        );
+        assert_eq!(
+            root.set_file("file1.txt/file4.txt", b"K".into()),
+            Err(SetFileError::UnderFile)
+        );
+        #[rustfmt::skip]
+        assert_eq!(root, RawDir([
+            ("dir1".into(), RawEntry::Dir(RawDir([
+                ("dir2".into(), RawEntry::Dir(RawDir([
+                    ("file2.txt".into(), RawEntry::File(RawFile::new(b"B".into()))),
+                ].into_iter().collect()))),
+                ("file3.txt".into(), RawEntry::File(RawFile::new(b"C".into()))),
+            ].into_iter().collect()))),
+            ("file1.txt".into(), RawEntry::File(RawFile::new(b"A".into()))),
+        ].into_iter().collect()));
+    }
+
+    #[test]
+    fn changed_files() {
+        let mut old = RawDir::default();
+        old.set_file("same.txt", b"A".into()).unwrap();
+        old.set_file("dir/changed.txt", b"B".into()).unwrap();
+        old.set_file("dir/removed.txt", b"C".into()).unwrap();
+        let mut new = RawDir::default();
+        new.set_file("same.txt", b"A".into()).unwrap();
+        new.set_file("dir/changed.txt", b"D".into()).unwrap();
+        new.set_file("added/file.txt", b"E".into()).unwrap();
+        assert_eq!(
+            old.changed_files(&new),
+            [
+                (PathBuf::from("added/file.txt"), FileChange::Added),
+                (PathBuf::from("dir/changed.txt"), FileChange::Modified),
+                (PathBuf::from("dir/removed.txt"), FileChange::Removed),
+            ]
+        );
+        assert_eq!(new.changed_files(&new), []);
+    }
+
+    #[test]
+    fn get_file_symlinks() {
+        #[rustfmt::skip]
+        let dir = RawDir([
+            ("include".into(), RawEntry::Dir(RawDir([
+                ("lib.h".into(), RawEntry::File(RawFile::new(b"A".into()))),
+                ("lib-1.0.h".into(), RawEntry::Symlink("lib.h".into())),
+            ].into_iter().collect()))),
+            ("src".into(), RawEntry::Dir(RawDir([
+                ("include".into(), RawEntry::Symlink("../include".into())),
+                ("loop1".into(), RawEntry::Symlink("loop2".into())),
+                ("loop2".into(), RawEntry::Symlink("./loop1".into())),
+                ("outside".into(), RawEntry::Symlink("../..".into())),
+                ("root".into(), RawEntry::Symlink("..".into())),
+            ].into_iter().collect()))),
+        ].into_iter().collect());
+        assert_eq!(dir.get_file("include/lib-1.0.h"), Ok(&b"A".into()));
+        assert_eq!(dir.get_file("src/include/lib-1.0.h"), Ok(&b"A".into()));
+        assert_eq!(dir.get_file("src/root/src/include/lib.h"), Ok(&b"A".into()));
+        // .. after a symlink refers to the parent of the symlink's target.
+        assert_eq!(
+            dir.get_file("src/include/../include/lib.h"),
+            Ok(&b"A".into())
+        );
+        assert_eq!(dir.get_file("src/include"), Err(GetFileError::Directory));
+        assert_eq!(dir.get_file("src/loop1"), Err(GetFileError::SymlinkLoop));
+        assert_eq!(
+            dir.get_file("src/outside/file"),
+            Err(GetFileError::OutsideDir)
+        );
+        assert_eq!(
+            dir.get_file("src/include/missing.h"),
+            Err(GetFileError::DoesNotExist)
+        );
+        assert_eq!(
+            dir.symlinks_recursive().first(),
+            Some(&(PathBuf::from("include/lib-1.0.h"), Path::new("lib.h")))
+        );
+    }
+
+    /// Verifies that symlinks that escape through other symlinks are rejected.
+    #[test]
+    fn chained_escaping_symlinks() {
+        let mut dir = RawDir::default();
+        dir.set_file("sub/a.h", b"A".into()).unwrap();
+        dir.set_symlink("sub/d", "..").unwrap();
+        // `sub/d/..` is the root's parent, not `sub`.
+        let escaping = Err(SetFileError::EscapingSymlink);
+        assert_eq!(dir.set_symlink("l", "sub/d/.."), escaping);
+        dir.set_symlink("l", "sub/d/sub").unwrap();
+        assert_eq!(dir.get_file("l/a.h"), Ok(&b"A".into()));
+        // Directories created for a rejected symlink are removed.
+        assert_eq!(dir.set_symlink("x/y/l", "../../sub/d/.."), escaping);
+        assert_eq!(dir.get_dir("x").err(), Some(GetFileError::DoesNotExist));
+        // Adding a symlink cannot make an existing symlink escape.
+        dir.set_symlink("n", "sub/e/..").unwrap();
+        assert_eq!(dir.set_symlink("sub/e", ".."), escaping);
+        assert_eq!(dir.set_symlink("loop", "loop/x"), escaping);
+        assert_eq!(
+            dir.rename("sub/d", "d"),
+            Err(RenameError::To(SetFileError::EscapingSymlink))
+        );
+        assert!(matches!(dir.remove("sub/d"), Ok(RawEntry::Symlink(_))));
+        assert_eq!(dir.symlinks_recursive().len(), 2);
+    }
+
+    #[cfg(all(unix, not(miri)))]
+    #[test]
+    fn populate_symlinks() {
+        use crate::test_util::tempdir;
+        use std::fs::{create_dir, read_dir, write};
+        use std::os::unix::fs::symlink;
+        let tempdir = tempdir().unwrap();
+        let [root, outside] = ["root", "outside"].map(|name| tempdir.path().join(name));
+        create_dir(&root).unwrap();
+        create_dir(&outside).unwrap();
+        create_dir(root.join("include")).unwrap();
+        write(root.join("include/lib.h"), "A").unwrap();
+        symlink("lib.h", root.join("include/lib-1.0.h")).unwrap();
+        symlink("include", root.join("inc")).unwrap();
+        write(outside.join("ext.h"), "B").unwrap();
+        symlink("../outside", root.join("ext")).unwrap();
+
+        let options = |escaping_symlinks| PopulateOptions {
+            escaping_symlinks,
+            ..Default::default()
+        };
+        let reject = options(EscapingSymlinks::Reject);
+        assert!(RawDir::populate_from_with(read_dir(&root).unwrap(), &reject).is_err());
+        let follow = options(EscapingSymlinks::Follow);
+        let populated = RawDir::populate_from_with(read_dir(&root).unwrap(), &follow).unwrap();
+        let dir = populated.dir;
+        assert_eq!((populated.directories, populated.files), (2, 2));
+        assert_eq!(dir.get_file("inc/lib-1.0.h"), Ok(&b"A".into()));
+        assert_eq!(dir.get_file("ext/ext.h"), Ok(&b"B".into()));
+        assert_eq!(dir.symlinks_recursive().len(), 2);
+
+        // A followed symlink that leads back to one of its ancestors is a loop.
+        symlink("..", outside.join("back")).unwrap();
+        assert!(RawDir::populate_from_with(read_dir(&root).unwrap(), &follow).is_err());
+
+        let copy = tempdir.path().join("copy");
+        dir.materialize(&copy).unwrap();
+        let (copied, _, _) = RawDir::populate_from(read_dir(&copy).unwrap()).unwrap();
+        assert_eq!(copied, dir);
+
+        // A symlink that escapes through another symlink is rejected.
+        let chained = tempdir.path().join("chained");
+        create_dir(&chained).unwrap();
+        create_dir(chained.join("sub")).unwrap();
+        symlink("..", chained.join("sub/d")).unwrap();
+        let populated = RawDir::populate_from_with(read_dir(&chained).unwrap(), &reject).unwrap();
+        assert_eq!(populated.dir.symlinks_recursive().len(), 1);
+        symlink("sub/d/..", chained.join("l")).unwrap();
+        assert!(RawDir::populate_from_with(read_dir(&chained).unwrap(), &reject).is_err());
+    }
+
+    #[test]
+    fn set_mode() {
+        let mut dir = RawDir::default();
+        dir.set_file("configure", b"#!/bin/sh".into()).unwrap();
+        assert_eq!(dir.get_mode("configure"), Ok(None));
+        dir.set_executable("configure", true).unwrap();
+        assert_eq!(dir.get_mode("configure"), Ok(Some(0o755)));
+        dir.set_mode("configure", 0o640).unwrap();
+        dir.set_executable("configure", true).unwrap();
+        assert_eq!(dir.get_mode("configure"), Ok(Some(0o750)));
+        dir.set_executable("configure", false).unwrap();
+        assert_eq!(dir.get_mode("configure"), Ok(Some(0o640)));
+        assert_eq!(
+            dir.set_mode("missing", 0o644),
+            Err(GetFileError::DoesNotExist)
+        );
+    }
+
+    #[test]
+    fn hash() {
+        let mut dir = RawDir::default();
+        dir.set_file("src/main.c", b"int main;".into()).unwrap();
+        dir.set_symlink("main.c", "src/main.c").unwrap();
+        let hash = dir.hash();
+        let mut copy = dir.clone();
+        assert_eq!(copy.hash(), hash);
+        assert!(std::ptr::eq(
+            copy.get_file("main.c").unwrap().as_ptr(),
+            dir.get_file("main.c").unwrap().as_ptr()
+        ));
+        copy.raw_file_mut(Path::new("main.c")).unwrap().mtime = Some(SystemTime::UNIX_EPOCH);
+        assert_eq!(copy.hash(), hash);
+        copy.set_executable("main.c", true).unwrap();
+        assert_ne!(copy.hash(), hash);
+
+        let mut changed = dir.clone();
+        changed
+            .write_file("src/main.c", b"int main();".into())
+            .unwrap();
+        assert_ne!(changed.hash(), hash);
+        let mut changed = dir.clone();
+        changed.remove("main.c").unwrap();
+        changed.set_symlink("main.c", "src/../src/main.c").unwrap();
+        assert_ne!(changed.hash(), hash);
+        let mut changed = dir.clone();
+        changed.rename("src", "source").unwrap();
+        assert_ne!(changed.hash(), hash);
+        // A file and a directory with the same name and contents hash differently.
+        let (mut file, mut subdir) = (RawDir::default(), RawDir::default());
+        file.set_file("a", b"".into()).unwrap();
+        subdir.create_dirs(&["a".as_ref()]).unwrap();
+        assert_ne!(file.hash(), subdir.hash());
+    }
+
+    #[cfg(all(unix, not(miri)))]
+    #[test]
+    fn metadata() {
+        use crate::test_util::tempdir;
+        use std::fs::read_dir;
+        use std::time::Duration;
+        let tempdir = tempdir().unwrap();
+        let copy = tempdir.path().join("copy");
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let mut dir = RawDir::default();
+        dir.set_file("test.sh", b"#!/bin/sh".into()).unwrap();
+        dir.set_mode("test.sh", 0o750).unwrap();
+        dir.raw_file_mut(Path::new("test.sh")).unwrap().mtime = Some(mtime);
+        dir.materialize(&copy).unwrap();
+
+        let options = PopulateOptions {
+            preserve_mtimes: true,
+            ..Default::default()
+        };
+        let copied = RawDir::populate_from_with(read_dir(&copy).unwrap(), &options).unwrap();
+        assert_eq!(copied.dir, dir);
+        let (copied, _, _) = RawDir::populate_from(read_dir(&copy).unwrap()).unwrap();
+        assert_eq!(copied.get_mode("test.sh"), Ok(Some(0o750)));
+        assert_eq!(copied.raw_file(Path::new("test.sh")).unwrap().mtime, None);
+    }
+
+    #[cfg(not(miri))]
+    #[test]
+    fn populate_filter() {
+        use crate::test_util::tempdir;
+        use std::fs::{create_dir_all, read_dir, write};
+        let tempdir = tempdir().unwrap();
+        let root = tempdir.path();
+        create_dir_all(root.join(".git")).unwrap();
+        create_dir_all(root.join("build")).unwrap();
+        create_dir_all(root.join("src/gen")).unwrap();
+        write(root.join(".git/HEAD"), "ref").unwrap();
+        write(root.join(".gitignore"), "build/\n*.o\n").unwrap();
+        write(root.join("build/main.o"), "").unwrap();
+        write(root.join("fixture.bin"), [0; 100]).unwrap();
+        write(root.join("src/main.c"), "int main;").unwrap();
+        write(root.join("src/main.o"), "").unwrap();
+        write(root.join("src/.harvestignore"), "gen\n!keep.o\n").unwrap();
+        write(root.join("src/keep.o"), "").unwrap();
+        write(root.join("src/gen/out.c"), "").unwrap();
+
+        let options = PopulateOptions {
+            filter: PopulateFilter {
+                ignore_files: true,
+                exclude: vec![".git/".into()],
+                max_file_size: Some(50),
+            },
+            ..Default::default()
+        };
+        let populated = RawDir::populate_from_with(read_dir(root).unwrap(), &options).unwrap();
+        let mut files = populated.dir.files_recursive();
+        files.sort();
+        let files: Vec<_> = files.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            files,
+            [
+                ".gitignore",
+                "src/.harvestignore",
+                "src/keep.o",
+                "src/main.c"
+            ]
+            .map(PathBuf::from)
+        );
+        let mut skipped = populated.skipped.clone();
+        skipped.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            skipped,
+            [
+                (".git".into(), SkipReason::Ignored),
+                ("build".into(), SkipReason::Ignored),
+                ("fixture.bin".into(), SkipReason::TooLarge(100)),
+                ("src/gen".into(), SkipReason::Ignored),
+                ("src/main.o".into(), SkipReason::Ignored),
+            ]
+        );
+
+        // The default filter loads everything.
+        let (all, _, files) = RawDir::populate_from(read_dir(root).unwrap()).unwrap();
+        assert_eq!(files, 9);
+
+        // Filtering the loaded directory has the same result as filtering while loading.
+        let filtered = all.filtered(&options.filter).unwrap();
+        assert_eq!(filtered.dir, populated.dir);
+        assert_eq!(filtered.directories, populated.directories);
+        assert_eq!(filtered.files, populated.files);
+        let mut filtered_skipped = filtered.skipped;
+        filtered_skipped.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(filtered_skipped, skipped);
+    }
+}
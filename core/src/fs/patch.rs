@@ -0,0 +1,356 @@
+//! Unified diffs between [RawDir]s.
+
+use super::{GetFileError, RawDir, SetFileError};
+use diffy::{DiffOptions, Patch, apply};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// The name used in place of a file name for the missing side of an added or removed file.
+const DEV_NULL: &str = "/dev/null";
+
+impl RawDir {
+    /// Returns a unified diff that transforms `self` into `new`, with `a/` and `b/` prefixes on
+    /// the file names (as git uses). Added and removed files are diffed against `/dev/null`.
+    ///
+    /// Only file contents are compared: symlinks and permissions are not included. Files that are
+    /// not valid UTF-8 are listed as `Binary files ... differ` lines, which [RawDir::apply_patch]
+    /// cannot apply.
+    pub fn diff(&self, new: &RawDir) -> String {
+        let old_files: BTreeMap<_, _> = self.files().collect();
+        let new_files: BTreeMap<_, _> = new.files().collect();
+        let paths: BTreeSet<_> = old_files.keys().chain(new_files.keys()).collect();
+        let mut out = String::new();
+        for path in paths {
+            let (old, new) = (old_files.get(path), new_files.get(path));
+            if old == new {
+                continue;
+            }
+            let name = |prefix, contents: Option<_>| match contents {
+                None => DEV_NULL.to_owned(),
+                Some(_) => format!("{prefix}{}", path.display()),
+            };
+            let (old_name, new_name) = (name("a/", old), name("b/", new));
+            let old = str::from_utf8(old.copied().unwrap_or_default());
+            let new = str::from_utf8(new.copied().unwrap_or_default());
+            let (Ok(old), Ok(new)) = (old, new) else {
+                _ = writeln!(out, "Binary files {old_name} and {new_name} differ");
+                continue;
+            };
+            let patch = DiffOptions::new()
+                .set_original_filename(old_name)
+                .set_modified_filename(new_name)
+                .create_patch(old, new);
+            _ = write!(out, "{patch}");
+        }
+        out
+    }
+
+    /// Applies a unified diff (such as one returned by [RawDir::diff]) to this directory. File
+    /// names may have `a/` and `b/` prefixes. A file whose original name is `/dev/null` is added,
+    /// and a file whose modified name is `/dev/null` is removed. Lines outside the file diffs
+    /// (such as git's `diff --git` and `index` lines) are ignored.
+    ///
+    /// Each file may only appear once in the patch. The patch is applied to a copy of this
+    /// directory, which replaces it only if every file diff applied, so a patch that does not
+    /// apply leaves `self` unchanged.
+    pub fn apply_patch(&mut self, patch: &str) -> Result<(), PatchError> {
+        let mut changes = vec![];
+        let mut paths = BTreeSet::new();
+        for section in split_patch(patch)? {
+            let text = match section {
+                Section::Binary(line) => return Err(PatchError::Binary(line.into())),
+                Section::File(text) => text,
+            };
+            let file_patch =
+                Patch::from_str(text).map_err(|e| PatchError::InvalidPatch(e.to_string()))?;
+            let (Some(old_name), Some(new_name)) = (file_patch.original(), file_patch.modified())
+            else {
+                return Err(PatchError::InvalidPatch("missing file name".into()));
+            };
+            let old_path = (old_name != DEV_NULL).then(|| strip_prefix(old_name, "a/"));
+            let new_path = (new_name != DEV_NULL).then(|| strip_prefix(new_name, "b/"));
+            let path = match (old_path, new_path) {
+                (Some(old), Some(new)) if old != new => {
+                    return Err(PatchError::InvalidPatch(format!(
+                        "renames are not supported ({old_name} -> {new_name})"
+                    )));
+                }
+                (Some(path), _) | (None, Some(path)) => path,
+                (None, None) => {
+                    return Err(PatchError::InvalidPatch(format!(
+                        "{text:?} has no file name"
+                    )));
+                }
+            };
+            if !paths.insert(path.to_owned()) {
+                return Err(PatchError::InvalidPatch(format!(
+                    "{} is patched more than once",
+                    path.display()
+                )));
+            }
+            let base = match old_path {
+                None => match self.get_file(path) {
+                    Err(GetFileError::DoesNotExist) => "",
+                    Ok(_) => {
+                        return Err(PatchError::SetFile(
+                            path.into(),
+                            SetFileError::AlreadyExists,
+                        ));
+                    }
+                    Err(error) => return Err(PatchError::GetFile(path.into(), error)),
+                },
+                Some(_) => {
+                    let contents = self
+                        .get_file(path)
+                        .map_err(|error| PatchError::GetFile(path.into(), error))?;
+                    str::from_utf8(contents).map_err(|_| {
+                        PatchError::Binary(format!("{} is not UTF-8", path.display()))
+                    })?
+                }
+            };
+            let contents =
+                apply(base, &file_patch).map_err(|_| PatchError::DoesNotApply(path.into()))?;
+            changes.push((
+                path.to_owned(),
+                old_path.is_none(),
+                new_path.map(|_| contents),
+            ));
+        }
+        // Applying a change can still fail (e.g. adding `a/b` after adding a file `a`).
+        let mut patched = self.clone();
+        for (path, added, contents) in changes {
+            let error = |error| PatchError::GetFile(path.clone(), error);
+            match (added, contents) {
+                (true, Some(contents)) => {
+                    patched
+                        .set_file(&path, contents.into())
+                        .map_err(|error| PatchError::SetFile(path.clone(), error))?;
+                }
+                (false, Some(contents)) => {
+                    *patched.get_file_mut(&path).map_err(error)? = contents.into();
+                }
+                (_, None) => _ = patched.remove(&path).map_err(error)?,
+            }
+        }
+        *self = patched;
+        Ok(())
+    }
+}
+
+/// Error type returned by [RawDir::apply_patch].
+#[derive(Debug, Error)]
+pub enum PatchError {
+    #[error("cannot apply binary patch: {0}")]
+    Binary(String),
+    #[error("patch does not apply to {}", .0.display())]
+    DoesNotApply(PathBuf),
+    #[error("{path}: {1}", path = .0.display())]
+    GetFile(PathBuf, GetFileError),
+    #[error("invalid patch: {0}")]
+    InvalidPatch(String),
+    #[error("{path}: {1}", path = .0.display())]
+    SetFile(PathBuf, SetFileError),
+}
+
+/// A part of a multi-file unified diff.
+enum Section<'p> {
+    /// The `---` and `+++` lines and hunks for one file.
+    File(&'p str),
+    /// A `Binary files ... differ` line.
+    Binary(&'p str),
+}
+
+/// Splits a multi-file unified diff into its parts. The hunks' contents are not checked here;
+/// that is left to [Patch::from_str].
+fn split_patch(patch: &str) -> Result<Vec<Section<'_>>, PatchError> {
+    let invalid = |message: &str| PatchError::InvalidPatch(message.into());
+    // Each line, along with its offset in `patch`.
+    let mut offset = 0;
+    let lines: Vec<_> = patch
+        .split_inclusive('\n')
+        .map(|line| {
+            offset += line.len();
+            (offset - line.len(), line)
+        })
+        .collect();
+    let mut sections = vec![];
+    let mut i = 0;
+    while let Some(&(start, line)) = lines.get(i) {
+        i += 1;
+        if line.starts_with("Binary files ") {
+            sections.push(Section::Binary(line.trim_end()));
+            continue;
+        }
+        if !line.starts_with("--- ") {
+            continue;
+        }
+        if !lines
+            .get(i)
+            .is_some_and(|(_, line)| line.starts_with("+++ "))
+        {
+            return Err(invalid("`---` line not followed by a `+++` line"));
+        }
+        i += 1;
+        while let Some((_, header)) = lines.get(i)
+            && header.starts_with("@@ ")
+        {
+            i += 1;
+            let (mut old, mut new) = hunk_lengths(header).ok_or_else(|| invalid(header))?;
+            // Consume the hunk's lines, as counted by its header.
+            while old > 0 || new > 0 {
+                let (_, line) = lines.get(i).ok_or_else(|| invalid("truncated hunk"))?;
+                i += 1;
+                let (old_lines, new_lines) = match line.as_bytes()[0] {
+                    b' ' | b'\n' => (1, 1),
+                    b'-' => (1, 0),
+                    b'+' => (0, 1),
+                    b'\\' => (0, 0),
+                    _ => return Err(invalid(line)),
+                };
+                old = old.checked_sub(old_lines).ok_or_else(|| invalid(line))?;
+                new = new.checked_sub(new_lines).ok_or_else(|| invalid(line))?;
+            }
+            // A hunk's last line may be followed by "\ No newline at end of file".
+            while lines.get(i).is_some_and(|(_, line)| line.starts_with('\\')) {
+                i += 1;
+            }
+        }
+        let end = lines.get(i).map_or(patch.len(), |&(end, _)| end);
+        sections.push(Section::File(&patch[start..end]));
+    }
+    Ok(sections)
+}
+
+/// Parses a hunk header (`@@ -1,2 +1,3 @@`), returning the number of lines the hunk spans in the
+/// original and modified files.
+fn hunk_lengths(header: &str) -> Option<(usize, usize)> {
+    let (ranges, _) = header.strip_prefix("@@ -")?.split_once(" @@")?;
+    let (old, new) = ranges.split_once(" +")?;
+    let length = |range: &str| match range.split_once(',') {
+        None => Some(1),
+        Some((_, length)) => length.parse().ok(),
+    };
+    Some((length(old)?, length(new)?))
+}
+
+/// Removes `prefix` from the start of `name`, if present, and converts it to a path.
+fn strip_prefix<'n>(name: &'n str, prefix: &str) -> &'n Path {
+    name.strip_prefix(prefix).unwrap_or(name).as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(files: &[(&str, &str)]) -> RawDir {
+        let mut dir = RawDir::default();
+        for (path, contents) in files {
+            dir.set_file(path, contents.as_bytes().into()).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn diff_and_apply() {
+        let old = dir(&[
+            ("Cargo.toml", "[package]\nname = \"a\"\n"),
+            ("src/main.rs", "fn main() {\n    println!(\"hi\");\n}\n"),
+            ("src/old.rs", "// old\n"),
+        ]);
+        let new = dir(&[
+            ("Cargo.toml", "[package]\nname = \"a\"\n"),
+            ("src/main.rs", "fn main() {\n    println!(\"hello\");\n}"),
+            ("src/new.rs", "// new\n"),
+            ("src/empty.rs", ""),
+        ]);
+        let diff = old.diff(&new);
+        assert!(!diff.contains("Cargo.toml"));
+        assert!(diff.contains("--- /dev/null\n+++ b/src/new.rs\n@@ -0,0 +1 @@\n+// new\n"));
+        assert!(diff.contains("--- a/src/old.rs\n+++ /dev/null\n"));
+
+        let mut patched = dir(&[
+            ("Cargo.toml", "[package]\nname = \"a\"\n"),
+            ("src/main.rs", "fn main() {\n    println!(\"hi\");\n}\n"),
+            ("src/old.rs", "// old\n"),
+        ]);
+        // Git's extended header lines are ignored.
+        patched
+            .apply_patch(&format!("diff --git a/x b/x\nindex 0..1\n{diff}"))
+            .unwrap();
+        assert_eq!(patched, new);
+        assert_eq!(patched.diff(&new), "");
+
+        // A patch that does not apply leaves the directory unchanged.
+        assert!(matches!(
+            patched.apply_patch(&diff),
+            Err(PatchError::SetFile(_, SetFileError::AlreadyExists))
+        ));
+        assert_eq!(patched, new);
+        let mut patched = dir(&[
+            ("src/main.rs", "fn main() {}\n"),
+            ("src/old.rs", "// old\n"),
+        ]);
+        let reverse = new.diff(&old);
+        assert!(matches!(
+            patched.apply_patch(&reverse),
+            Err(PatchError::GetFile(_, GetFileError::DoesNotExist))
+        ));
+        assert!(matches!(
+            patched.apply_patch(&old.diff(&new)),
+            Err(PatchError::DoesNotApply(path)) if path == Path::new("src/main.rs")
+        ));
+        assert_eq!(patched.get_file("src/old.rs"), Ok(&b"// old\n".into()));
+    }
+
+    #[test]
+    fn binary() {
+        let mut old = RawDir::default();
+        old.set_file("data.bin", b"\xff\0".into()).unwrap();
+        let diff = old.diff(&RawDir::default());
+        assert_eq!(diff, "Binary files a/data.bin and /dev/null differ\n");
+        assert!(matches!(old.apply_patch(&diff), Err(PatchError::Binary(_))));
+    }
+
+    #[test]
+    fn invalid_patches() {
+        let mut dir = dir(&[("a.c", "a\n")]);
+        for patch in [
+            "--- a/a.c\n@@ -1 +1 @@\n-a\n+b\n",
+            "--- a/a.c\n+++ b/a.c\n@@ -1 +1 @@\n-a\n",
+            "--- a/a.c\n+++ b/b.c\n@@ -1 +1 @@\n-a\n+b\n",
+        ] {
+            assert!(matches!(
+                dir.apply_patch(patch),
+                Err(PatchError::InvalidPatch(_))
+            ));
+        }
+        dir.apply_patch("--- a.c\n+++ a.c\n@@ -1 +1 @@\n-a\n+b\n")
+            .unwrap();
+        assert_eq!(dir.get_file("a.c"), Ok(&b"b\n".into()));
+        // Two diffs of the same file.
+        let twice =
+            "--- a.c\n+++ a.c\n@@ -1 +1 @@\n-b\n+c\n--- a.c\n+++ a.c\n@@ -1 +1 @@\n-b\n+d\n";
+        assert!(matches!(
+            dir.apply_patch(twice),
+            Err(PatchError::InvalidPatch(message)) if message.contains("more than once")
+        ));
+        assert_eq!(dir.get_file("a.c"), Ok(&b"b\n".into()));
+    }
+
+    /// Verifies that a patch that fails while its changes are being made leaves the directory
+    /// unchanged.
+    #[test]
+    fn partial_failure() {
+        let mut dir = dir(&[("x.c", "x\n")]);
+        let patch = "--- /dev/null\n+++ b/a\n@@ -0,0 +1 @@\n+a\n\
+                     --- a/x.c\n+++ b/x.c\n@@ -1 +1 @@\n-x\n+y\n\
+                     --- /dev/null\n+++ b/a/b\n@@ -0,0 +1 @@\n+b\n";
+        assert!(matches!(
+            dir.apply_patch(patch),
+            Err(PatchError::SetFile(path, _)) if path == Path::new("a/b")
+        ));
+        assert_eq!(dir, self::dir(&[("x.c", "x\n")]));
+    }
+}
@@ -1,7 +1,17 @@
-use std::fmt::{self, Display, Formatter};
+use crate::Representation;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
 use std::num::NonZeroU64;
 use std::process::abort;
 use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex, PoisonError};
+
+// The highest ID allocated so far. Each new_array() call starts allocating IDs at HIGHEST_ID + 1.
+static HIGHEST_ID: AtomicU64 = AtomicU64::new(0);
 
 /// An opaque type that refers to a particular representation instance in
 /// HarvestIR.
@@ -32,11 +42,122 @@ impl Id {
     /// # }
     /// ```
     pub fn new_array<const LEN: usize>() -> [Id; LEN] {
-        // The highest ID allocated so far. Each new_array() call starts
-        // allocating IDs at HIGHEST_ID + 1.
-        static HIGHEST_ID: AtomicU64 = AtomicU64::new(0);
         new_array_testable(&HIGHEST_ID)
     }
+
+    /// Returns the ID with number `num`, which was read from a saved IR. IDs allocated afterwards
+    /// by [Id::new] and [Id::new_array] will not collide with it.
+    pub(crate) fn from_saved(num: NonZeroU64) -> Id {
+        // IDs allocated by IdAllocator are in a separate range from Id::new's IDs, so they do not
+        // need to be skipped.
+        if num.get() & RUN_ID_BIT == 0 {
+            HIGHEST_ID.fetch_max(num.get(), Relaxed);
+        }
+        Id(num)
+    }
+}
+
+// IDs allocated by IdAllocator have this bit set. Id::new's counter will never reach it in
+// practice, so the two kinds of ID cannot collide.
+const RUN_ID_BIT: u64 = 1 << 63;
+// The number of low bits of an IdAllocator ID that hold the index within the tool run. The bits
+// between these and RUN_ID_BIT hold a hash of the tool run.
+const RUN_INDEX_BITS: u32 = 20;
+// The bits of an IdAllocator ID that hold the index within the tool run.
+const RUN_INDEX_MASK: u64 = (1 << RUN_INDEX_BITS) - 1;
+
+/// Allocates IDs for the edits made by tool runs. Rather than coming from a process-wide counter,
+/// each ID is derived from the tool run that created it and the number of IDs that run created
+/// before it. This makes IDs independent of thread timing and of anything else the process has
+/// done, so two runs with the same tool results produce the same IDs.
+///
+/// Each tool run gets a range of 2^20 IDs, chosen by hashing the tool run. If the range is already
+/// in use (by an earlier tool run, or by an ID in the initial IR), the hash is extended until it
+/// picks an unused range. Tool runs are assigned ranges in the order they start, so this remains
+/// reproducible.
+#[derive(Clone, Debug)]
+pub(crate) struct IdAllocator {
+    // Distinguishes the IDs of sessions that start from different IRs (e.g. a resumed IR), so that
+    // their tool runs do not reuse IDs already present in the IR.
+    seed: u64,
+    // The bases of the ranges that are in use. Shared by every edit made from the same Organizer.
+    used: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl IdAllocator {
+    /// Returns an allocator for a session whose initial IR contains `ids`.
+    pub(crate) fn new<'i>(ids: impl IntoIterator<Item = &'i Id>) -> IdAllocator {
+        let mut seed = Fnv1a::default();
+        let mut used = HashSet::new();
+        for id in ids {
+            seed.write_u64(id.0.get());
+            if id.0.get() & RUN_ID_BIT != 0 {
+                used.insert(id.0.get() & !RUN_INDEX_MASK);
+            }
+        }
+        IdAllocator {
+            seed: seed.finish(),
+            used: Arc::new(Mutex::new(used)),
+        }
+    }
+
+    /// Returns the allocator for the IDs created by the tool run `tool_run`. Each tool run should
+    /// only be passed once: a second call returns a different range of IDs.
+    pub(crate) fn run_ids(&self, tool_run: impl Display) -> RunIds {
+        let mut hash = Fnv1a::default();
+        hash.write_u64(self.seed);
+        hash.write(tool_run.to_string().as_bytes());
+        let mut used = self.used.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let base = RUN_ID_BIT | (hash.finish() >> (RUN_INDEX_BITS + 1) << RUN_INDEX_BITS);
+            if used.insert(base) {
+                return RunIds { base, next: 0 };
+            }
+            hash.write_u8(0);
+        }
+    }
+}
+
+/// Allocates the IDs for a single tool run. Returned by [IdAllocator::run_ids].
+#[derive(Debug)]
+pub(crate) struct RunIds {
+    base: u64,
+    next: u64,
+}
+
+impl RunIds {
+    /// Returns the next ID for this tool run. Once the tool run has used up its 2^20 IDs, further
+    /// IDs come from [Id::new]: they are still unique, but are not reproducible.
+    pub(crate) fn next(&mut self) -> Id {
+        if self.next >> RUN_INDEX_BITS != 0 {
+            return Id::new();
+        }
+        self.next += 1;
+        // base has RUN_ID_BIT set, so this is never zero.
+        Id(NonZeroU64::new(self.base | (self.next - 1)).expect("zero run ID"))
+    }
+}
+
+/// The 64-bit FNV-1a hash. Unlike std's hashers, this is guaranteed to be stable, which IDs
+/// require to be reproducible.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Fnv1a {
+        Fnv1a(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100000001b3);
+        }
+    }
 }
 
 impl Display for Id {
@@ -45,12 +166,110 @@ impl Display for Id {
     }
 }
 
+/// IDs serialize as their number, so that representations that refer to other representations can
+/// be saved.
+impl Serialize for Id {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+/// Deserialized IDs are treated like IDs read from a saved IR: IDs allocated afterwards will not
+/// collide with them.
+impl<'de> Deserialize<'de> for Id {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Id, D::Error> {
+        NonZeroU64::deserialize(deserializer).map(Id::from_saved)
+    }
+}
+
 impl From<Id> for u64 {
     fn from(value: Id) -> u64 {
         value.0.into()
     }
 }
 
+/// An [Id] that refers to a representation of type `R`. [HarvestIR](crate::HarvestIR) can resolve
+/// a `TypedId` directly to an `&R`, so tools that hold on to `TypedId`s do not need to downcast.
+///
+/// `TypedId`s are returned when adding a concrete representation type (e.g. by
+/// [Edit::add_representation](crate::Edit::add_representation)) and by typed lookups such as
+/// [HarvestIR::get_by_representation](crate::HarvestIR::get_by_representation). They convert into
+/// plain `Id`s using `From`, and an `Id` can be checked against an IR and converted into a
+/// `TypedId` with [HarvestIR::typed_id](crate::HarvestIR::typed_id).
+pub struct TypedId<R: Representation> {
+    id: Id,
+    // fn() -> R makes TypedId Send + Sync regardless of R, and does not imply ownership of an R.
+    _representation: PhantomData<fn() -> R>,
+}
+
+impl<R: Representation> TypedId<R> {
+    /// Constructs a TypedId. The caller is responsible for making sure `id` refers to an `R`.
+    pub(crate) fn new_unchecked(id: Id) -> TypedId<R> {
+        TypedId {
+            id,
+            _representation: PhantomData,
+        }
+    }
+
+    /// Returns the untyped ID.
+    pub fn id(self) -> Id {
+        self.id
+    }
+}
+
+// These traits are implemented manually because deriving them would require R to implement them.
+impl<R: Representation> Clone for TypedId<R> {
+    fn clone(&self) -> TypedId<R> {
+        *self
+    }
+}
+
+impl<R: Representation> Copy for TypedId<R> {}
+
+impl<R: Representation> Debug for TypedId<R> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Debug::fmt(&self.id, f)
+    }
+}
+
+impl<R: Representation> Display for TypedId<R> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Display::fmt(&self.id, f)
+    }
+}
+
+impl<R: Representation> PartialEq for TypedId<R> {
+    fn eq(&self, other: &TypedId<R>) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<R: Representation> Eq for TypedId<R> {}
+
+impl<R: Representation> Hash for TypedId<R> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl<R: Representation> PartialOrd for TypedId<R> {
+    fn partial_cmp(&self, other: &TypedId<R>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<R: Representation> Ord for TypedId<R> {
+    fn cmp(&self, other: &TypedId<R>) -> Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+impl<R: Representation> From<TypedId<R>> for Id {
+    fn from(value: TypedId<R>) -> Id {
+        value.id
+    }
+}
+
 // `Id::new_array`, but with an injected AtomicU64. This allows `tests::new` to
 // use its own AtomicU64, which prevents other tests that are run in parallel
 // from interfering with it.
@@ -99,6 +318,47 @@ mod tests {
         assert_eq!(ids.len(), deduplicated.len(), "duplicate ID");
     }
 
+    #[test]
+    fn run_ids() {
+        let [a, b] = Id::new_array();
+        let allocator = IdAllocator::new(&[a, b]);
+        let ids = |run| {
+            let mut run_ids = allocator.run_ids(run);
+            [(); 3].map(|_| run_ids.next())
+        };
+        let first = ids("tool_001");
+        let second = ids("tool_002");
+        let reused = ids("tool_001");
+        let all: HashSet<_> = first.into_iter().chain(second).chain(reused).collect();
+        assert_eq!(all.len(), 9, "IDs are not unique");
+        let allocator = IdAllocator::new(&[a, b]);
+        assert_eq!(
+            allocator.run_ids("tool_001").next(),
+            first[0],
+            "IDs are not reproducible"
+        );
+        // IDs depend on the initial IR.
+        let mut run_ids = IdAllocator::new(&[a]).run_ids("tool_001");
+        assert!(!all.contains(&run_ids.next()));
+        // The ranges of run IDs in the initial IR are not reused.
+        let used = IdAllocator::new(&[a, first[2]]).used;
+        assert!(
+            used.lock()
+                .unwrap()
+                .contains(&(first[2].0.get() & !RUN_INDEX_MASK))
+        );
+        // Saved run IDs do not affect Id::new.
+        Id::from_saved(first[0].0);
+        assert!(Id::new().0.get() & RUN_ID_BIT == 0);
+    }
+
+    #[test]
+    fn from_saved() {
+        let saved = Id::new().0.checked_add(1000).unwrap();
+        assert_eq!(Id::from_saved(saved), Id(saved));
+        assert!(Id::new().0 > saved, "new ID collides with saved ID");
+    }
+
     // Verifies that new_array_testable works as designed. The contract of Id is
     // simply that each generated ID is unique, but if we simply generate N
     // random u64s (for a reasonably-sized N) then uniqueness is likely to
@@ -0,0 +1,82 @@
+//! Invariants that a [HarvestIR] is expected to uphold. The tool runner checks these after every
+//! applied edit, so that the tool run that broke an invariant can be identified.
+
+use crate::HarvestIR;
+use std::fmt::{self, Display, Formatter};
+
+/// A check for a single invariant. Returns a description of each violation found (or an empty
+/// list if the invariant holds).
+type Check = Box<dyn Fn(&HarvestIR) -> Vec<String> + Send + Sync>;
+
+/// A collection of invariant checks.
+#[derive(Default)]
+pub struct Invariants {
+    checks: Vec<(&'static str, Check)>,
+}
+
+impl Invariants {
+    /// Adds an invariant named `name`. `check` should return a description of each violation of
+    /// the invariant that it finds in the IR.
+    pub fn add<F: Fn(&HarvestIR) -> Vec<String> + Send + Sync + 'static>(
+        &mut self,
+        name: &'static str,
+        check: F,
+    ) {
+        self.checks.push((name, Box::new(check)));
+    }
+
+    /// Runs every check against `ir`, returning all the violations found.
+    pub fn check(&self, ir: &HarvestIR) -> Vec<Violation> {
+        self.checks
+            .iter()
+            .flat_map(|(invariant, check)| {
+                check(ir)
+                    .into_iter()
+                    .map(|message| Violation { invariant, message })
+            })
+            .collect()
+    }
+}
+
+/// A violation of an invariant, found by [Invariants::check].
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    /// Name of the violated invariant.
+    pub invariant: &'static str,
+    /// Description of the violation.
+    pub message: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.invariant, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::tests::{EmptyRepresentation, IdRepresentation};
+
+    #[test]
+    fn check() {
+        let mut invariants = Invariants::default();
+        invariants.add("no_empty", |ir| {
+            ir.get_by_representation::<EmptyRepresentation>()
+                .map(|(id, _)| format!("found empty representation {id}"))
+                .collect()
+        });
+        invariants.add("always_ok", |_| vec![]);
+        let mut ir = HarvestIR::default();
+        ir.add_representation(Box::new(IdRepresentation(1)));
+        assert_eq!(invariants.check(&ir), []);
+        let a = ir.add_representation(Box::new(EmptyRepresentation));
+        assert_eq!(
+            invariants.check(&ir),
+            [Violation {
+                invariant: "no_empty",
+                message: format!("found empty representation {a}"),
+            }]
+        );
+    }
+}
@@ -1,8 +1,18 @@
 use std::{
-    any::Any, collections::BTreeMap, fmt::Display, fs::File, io::Write, path::Path, sync::Arc,
+    any::{Any, TypeId},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{Display, Write as _},
+    fs::{File, create_dir, read_to_string, write},
+    io::{self, Write},
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
+use thiserror::Error;
+use tracing::warn;
 
-use crate::Id;
+use crate::diagnostics::ToolRunId;
+use crate::{Id, TypedId};
 
 /// An abstract representation of a program
 pub trait Representation: Any + Display + Send + Sync {
@@ -23,6 +33,68 @@ pub trait Representation: Any + Display + Send + Sync {
     fn materialize(&self, path: &Path) -> std::io::Result<()> {
         writeln!(File::create_new(path)?, "{self}")
     }
+
+    /// Returns this representation as a [SerializableRepresentation], if it supports
+    /// serialization. Representations that implement [SerializableRepresentation] should override
+    /// this to return `Some(self)`.
+    fn as_serializable(&self) -> Option<&dyn SerializableRepresentation> {
+        None
+    }
+
+    /// Describes how this representation differs from `old`, a previous representation at the
+    /// same ID, for use in diagnostics (e.g. a list of changed files). Returns `None` if this
+    /// representation cannot describe the difference (the default), such as when `old` has a
+    /// different type.
+    fn diff(&self, _old: &dyn Representation) -> Option<String> {
+        None
+    }
+}
+
+/// A [Representation] that can be saved to disk and loaded back later (e.g. to resume a previous
+/// run from an intermediate IR). This is opt-in: representations that do not implement it are
+/// skipped by [HarvestIR::save].
+///
+/// To be loadable, a type's [SerializableRepresentation::load] must be added to a [Registry] under
+/// the type's [Representation::name].
+pub trait SerializableRepresentation: Representation {
+    /// Saves this representation to `path`, which does not exist yet. The representation may
+    /// create either a file or a directory at `path`.
+    fn save(&self, path: &Path) -> io::Result<()>;
+
+    /// Loads a representation previously written by [SerializableRepresentation::save].
+    fn load(path: &Path) -> io::Result<Self>
+    where
+        Self: Sized;
+}
+
+/// A function that loads a saved representation from the given path.
+type Loader = fn(&Path) -> io::Result<Box<dyn Representation>>;
+
+/// Maps representation names to the functions that load them. Used by [HarvestIR::load].
+#[derive(Default)]
+pub struct Registry {
+    loaders: HashMap<&'static str, Loader>,
+}
+
+impl Registry {
+    /// Registers `R`'s loader under `name`, which must match the name `R` returns from
+    /// [Representation::name].
+    pub fn register<R: SerializableRepresentation>(&mut self, name: &'static str) {
+        self.loaders
+            .insert(name, |path| Ok(Box::new(R::load(path)?)));
+    }
+
+    /// Loads the representation named `name` from `path`.
+    pub(crate) fn load(
+        &self,
+        name: &str,
+        path: &Path,
+    ) -> Result<Box<dyn Representation>, LoadError> {
+        let Some(loader) = self.loaders.get(name) else {
+            return Err(LoadError::UnknownRepresentation(name.into()));
+        };
+        Ok(loader(path)?)
+    }
 }
 
 /// Harvest Intermediate Representation
@@ -37,46 +109,304 @@ pub struct HarvestIR {
     // particular run of the pipeline). There may or may not be a
     // useful ordering for [Id]s, but for now using an ordered map at
     // least gives us a stable ordering when iterating, e.g. to print
-    // the IR.
+    // the IR. Only live representations are in this map; superseded
+    // representations are moved to `superseded`.
     pub(crate) representations: BTreeMap<Id, Arc<dyn Representation>>,
+
+    // Representations that have been replaced by a newer representation, along with the ID of
+    // the representation that replaced them. These are kept so that their history can still be
+    // inspected, but are not returned by `iter`, `get_by_representation`, etc. Only the
+    // representations replaced by a live representation are kept: once the replacement is itself
+    // superseded or removed, they are dropped, so this does not grow without bound.
+    superseded: BTreeMap<Id, (Arc<dyn Representation>, Id)>,
+
+    // Index from each representation type to the IDs of the representations of that type. This
+    // lets typed lookups (e.g. `get_by_representation`) avoid scanning the entire IR. Must be kept
+    // in sync with `representations`, which is why all insertions go through `insert`.
+    by_type: HashMap<TypeId, BTreeSet<Id>>,
+
+    // Where each representation came from. Representations that were not written by an applied
+    // Edit (e.g. ones loaded from a saved IR) have no entry. Shared between all IDs written by the
+    // same Edit.
+    pub(crate) provenance: BTreeMap<Id, Arc<Provenance>>,
+
+    // The IDs of the live representations that were carried over from a previous run (loaded from
+    // a saved IR or recovered from a journal) and have not been written since.
+    resumed: BTreeSet<Id>,
+}
+
+/// Records where a representation in a [HarvestIR] came from. Set by
+/// [Organizer::apply_edit](crate::edit::Organizer::apply_edit) for each ID an edit writes.
+#[derive(Debug, PartialEq)]
+pub struct Provenance {
+    /// The tool run whose edit wrote the representation. `None` if the edit was not made by a
+    /// tool run.
+    pub tool_run: Option<ToolRunId>,
+
+    /// The IR version produced by applying the edit that wrote the representation.
+    pub version: u64,
+
+    /// The IDs in the IR snapshot that the tool read from.
+    pub inputs: BTreeSet<Id>,
 }
 
 impl HarvestIR {
     pub(crate) fn insert<R: Into<Arc<dyn Representation>>>(&mut self, id: Id, representation: R) {
-        self.representations.insert(id, representation.into());
+        let representation = representation.into();
+        let type_id = (&*representation as &dyn Any).type_id();
+        self.provenance.remove(&id);
+        self.superseded.remove(&id);
+        self.resumed.remove(&id);
+        if let Some(old) = self.representations.insert(id, representation) {
+            self.unindex(id, (&*old as &dyn Any).type_id());
+        }
+        self.by_type.entry(type_id).or_default().insert(id);
+    }
+
+    /// Removes the representation with ID `id` (and its provenance), if it exists.
+    pub(crate) fn remove(&mut self, id: Id) {
+        self.provenance.remove(&id);
+        self.superseded.remove(&id);
+        self.resumed.remove(&id);
+        if let Some(old) = self.representations.remove(&id) {
+            self.unindex(id, (&*old as &dyn Any).type_id());
+            self.prune_superseded(id);
+        }
+    }
+
+    /// Marks the representation with ID `old` as superseded by the representation with ID `new`.
+    /// `old` will no longer be live, but its representation and provenance are retained until
+    /// `new` stops being live. Does nothing if `old` is not a live representation.
+    pub(crate) fn supersede(&mut self, old: Id, new: Id) {
+        if let Some(representation) = self.representations.remove(&old) {
+            self.resumed.remove(&old);
+            self.unindex(old, (&*representation as &dyn Any).type_id());
+            self.prune_superseded(old);
+            self.superseded.insert(old, (representation, new));
+        }
+    }
+
+    /// Marks every live representation as carried over from a previous run (see
+    /// [HarvestIR::is_resumed]).
+    pub(crate) fn mark_resumed(&mut self) {
+        self.resumed = self.representations.keys().copied().collect();
+    }
+
+    /// Drops the superseded representations that were replaced by `id`, which is no longer live.
+    fn prune_superseded(&mut self, id: Id) {
+        let pruned: Vec<Id> = self
+            .superseded
+            .iter()
+            .filter(|(_, (_, new))| *new == id)
+            .map(|(&old, _)| old)
+            .collect();
+        for old in pruned {
+            self.superseded.remove(&old);
+            self.provenance.remove(&old);
+        }
     }
 
-    /// Returns an iterator over all [Representation] [Id]s
+    /// Removes `id` from the type index entry for `type_id`.
+    fn unindex(&mut self, id: Id, type_id: TypeId) {
+        if let Some(ids) = self.by_type.get_mut(&type_id) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.by_type.remove(&type_id);
+            }
+        }
+    }
+
+    /// Returns an iterator over all live [Representation] [Id]s
     pub fn ids(&self) -> impl Iterator<Item = &Id> {
         self.representations.keys()
     }
 
     /// Adds a representation with a new ID and returns the new ID.
-    pub fn add_representation(&mut self, representation: Box<dyn Representation>) -> Id {
+    pub fn add_representation<R: Representation>(&mut self, representation: Box<R>) -> TypedId<R> {
         let id = Id::new();
-        self.insert(id, representation);
-        id
+        self.insert(id, representation as Box<dyn Representation>);
+        TypedId::new_unchecked(id)
     }
 
-    /// Returns `true` if this `HarvestIR` contains a representation under ID `id`, `false`
+    /// Returns `true` if this `HarvestIR` contains a live representation under ID `id`, `false`
     /// otherwise.
     pub fn contains_id(&self, id: Id) -> bool {
         self.representations.contains_key(&id)
     }
 
-    /// Returns all contained Representations of the given type.
-    pub fn get_by_representation<R: Representation>(&self) -> impl Iterator<Item = (Id, &R)> {
-        // TODO: Add a `TypeId -> Id` map to HarvestIR that allows us to look these up without
-        // scanning through all the other representations.
-        self.representations
-            .iter()
-            .filter_map(|(&i, r)| <dyn Any>::downcast_ref(&**r).map(|r| (i, r)))
+    /// Returns the representation `id` refers to, or `None` if it is not live (or has since been
+    /// overwritten with a representation of a different type).
+    pub fn get<R: Representation>(&self, id: TypedId<R>) -> Option<&R> {
+        <dyn Any>::downcast_ref(&**self.representations.get(&id.id())?)
+    }
+
+    /// Returns the live representation with ID `id`, if there is one.
+    pub fn get_untyped(&self, id: Id) -> Option<&dyn Representation> {
+        self.representations.get(&id).map(|r| &**r)
+    }
+
+    /// Returns `id` as a [TypedId] if it refers to a live representation of type `R`.
+    pub fn typed_id<R: Representation>(&self, id: Id) -> Option<TypedId<R>> {
+        self.by_type
+            .get(&TypeId::of::<R>())?
+            .contains(&id)
+            .then(|| TypedId::new_unchecked(id))
     }
 
-    /// Returns an iterator over the IDs and representations in this IR.
+    /// Returns all contained live Representations of the given type.
+    pub fn get_by_representation<R: Representation>(
+        &self,
+    ) -> impl Iterator<Item = (TypedId<R>, &R)> {
+        self.by_type
+            .get(&TypeId::of::<R>())
+            .into_iter()
+            .flatten()
+            .map(|&id| {
+                let representation = <dyn Any>::downcast_ref(&*self.representations[&id])
+                    .expect("type index out of sync with representations");
+                (TypedId::new_unchecked(id), representation)
+            })
+    }
+
+    /// Returns the number of contained live Representations of the given type.
+    pub fn count<R: Representation>(&self) -> usize {
+        self.by_type
+            .get(&TypeId::of::<R>())
+            .map_or(0, BTreeSet::len)
+    }
+
+    /// Returns the only live Representation of the given type. Errors if the IR contains zero or
+    /// several live Representations of that type.
+    pub fn get_one<R: Representation>(&self) -> Result<(TypedId<R>, &R), GetOneError> {
+        let mut iter = self.get_by_representation::<R>();
+        match (iter.next(), self.count::<R>()) {
+            (Some(found), 1) => Ok(found),
+            (_, 0) => Err(GetOneError::NotFound),
+            (_, count) => Err(GetOneError::Multiple(count)),
+        }
+    }
+
+    /// Returns where the representation with ID `id` came from. Returns `None` if there is no
+    /// such representation or it was not written by an applied [Edit](crate::Edit).
+    pub fn provenance(&self, id: Id) -> Option<&Provenance> {
+        self.provenance.get(&id).map(|p| &**p)
+    }
+
+    /// Returns true if the live representation with ID `id` was carried over from a previous run,
+    /// i.e. loaded by [HarvestIR::load] or recovered by
+    /// [Organizer::recover](crate::edit::Organizer::recover), and has not been written since.
+    pub fn is_resumed(&self, id: Id) -> bool {
+        self.resumed.contains(&id)
+    }
+
+    /// If the representation with ID `id` has been superseded, returns the ID of the
+    /// representation that superseded it and the superseded representation.
+    pub fn superseded_by(&self, id: Id) -> Option<(Id, &dyn Representation)> {
+        self.superseded.get(&id).map(|(repr, new)| (*new, &**repr))
+    }
+
+    /// Returns an iterator over the IDs and live representations in this IR.
     pub fn iter(&self) -> impl Iterator<Item = (Id, &dyn Representation)> {
         self.representations.iter().map(|(&id, repr)| (id, &**repr))
     }
+
+    /// Returns the IDs that were added, removed, or replaced between this IR and `new` (a later
+    /// version of this IR). Only live representations are compared, so superseded
+    /// representations are reported as removed.
+    pub fn diff(&self, new: &HarvestIR) -> IrDiff {
+        let mut diff = IrDiff::default();
+        for (&id, old_repr) in &self.representations {
+            match new.representations.get(&id) {
+                None => diff.removed.push(id),
+                // Unmodified representations are shared between IR versions, so a different
+                // allocation means the representation was written.
+                Some(new_repr) if !Arc::ptr_eq(old_repr, new_repr) => diff.replaced.push(id),
+                Some(_) => {}
+            }
+        }
+        diff.added = new
+            .ids()
+            .filter(|id| !self.contains_id(**id))
+            .copied()
+            .collect();
+        diff
+    }
+
+    /// Saves this IR into a new directory at `dir`. Each serializable representation is saved to
+    /// `dir/$id`, and `dir/index` lists the saved IDs and representation names. Representations
+    /// that do not implement [SerializableRepresentation] are skipped (with a warning).
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        create_dir(dir)?;
+        let mut index = String::new();
+        for (id, repr) in self.iter() {
+            let Some(serializable) = repr.as_serializable() else {
+                warn!("Not saving {id}: {} is not serializable", repr.name());
+                continue;
+            };
+            let id_string = format!("{:03}", u64::from(id));
+            serializable.save(&PathBuf::from_iter([dir, id_string.as_ref()]))?;
+            let _ = writeln!(index, "{id_string}: {}", repr.name());
+        }
+        write(PathBuf::from_iter([dir, "index".as_ref()]), index)
+    }
+
+    /// Loads an IR that was previously written by [HarvestIR::save], using `registry` to find the
+    /// loader for each representation.
+    pub fn load(dir: &Path, registry: &Registry) -> Result<HarvestIR, LoadError> {
+        let mut ir = HarvestIR::default();
+        for line in read_to_string(PathBuf::from_iter([dir, "index".as_ref()]))?.lines() {
+            let Some((id_string, name)) = line.split_once(": ") else {
+                return Err(LoadError::InvalidIndex(line.into()));
+            };
+            let Some(num) = id_string.parse().ok().and_then(NonZeroU64::new) else {
+                return Err(LoadError::InvalidIndex(line.into()));
+            };
+            let representation =
+                registry.load(name, &PathBuf::from_iter([dir, id_string.as_ref()]))?;
+            ir.insert(Id::from_saved(num), representation);
+        }
+        ir.mark_resumed();
+        Ok(ir)
+    }
+}
+
+/// The changes between two versions of a [HarvestIR]. Returned by [HarvestIR::diff]. Each list
+/// is sorted by ID.
+#[derive(Debug, Default, PartialEq)]
+pub struct IrDiff {
+    /// IDs that are only in the newer IR.
+    pub added: Vec<Id>,
+    /// IDs that are only in the older IR.
+    pub removed: Vec<Id>,
+    /// IDs that are in both IRs, but whose representation was rewritten.
+    pub replaced: Vec<Id>,
+}
+
+impl IrDiff {
+    /// Returns `true` if there are no changes.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.replaced.is_empty()
+    }
+}
+
+/// Error type returned by [HarvestIR::load].
+#[derive(Debug, Error)]
+pub enum LoadError {
+    #[error("I/O error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("invalid index line {0:?}")]
+    InvalidIndex(String),
+    #[error("no loader registered for representation {0:?}")]
+    UnknownRepresentation(String),
+}
+
+/// Error type returned by [HarvestIR::get_one].
+#[derive(Debug, Error, Hash, PartialEq)]
+pub enum GetOneError {
+    #[error("no representation of this type found")]
+    NotFound,
+    #[error("found {0} representations of this type, expected 1")]
+    Multiple(usize),
 }
 
 impl Display for HarvestIR {
@@ -119,6 +449,22 @@ pub(crate) mod tests {
         fn name(&self) -> &'static str {
             "id"
         }
+
+        fn as_serializable(&self) -> Option<&dyn SerializableRepresentation> {
+            Some(self)
+        }
+    }
+    impl SerializableRepresentation for IdRepresentation {
+        fn save(&self, path: &Path) -> io::Result<()> {
+            write(path, self.0.to_string())
+        }
+
+        fn load(path: &Path) -> io::Result<Self> {
+            read_to_string(path)?
+                .parse()
+                .map(IdRepresentation)
+                .map_err(io::Error::other)
+        }
     }
 
     #[test]
@@ -133,4 +479,129 @@ pub(crate) mod tests {
             HashSet::from([(b, &IdRepresentation(1)), (d, &IdRepresentation(2))])
         );
     }
+
+    #[test]
+    fn diff() {
+        let mut old = HarvestIR::default();
+        let a = old.add_representation(Box::new(IdRepresentation(1))).id();
+        let b = old.add_representation(Box::new(IdRepresentation(2))).id();
+        let c = old.add_representation(Box::new(IdRepresentation(3))).id();
+        let mut new = old.clone();
+        assert!(old.diff(&new).is_empty());
+        new.remove(a);
+        new.insert(b, Box::new(IdRepresentation(4)) as Box<dyn Representation>);
+        let d = new.add_representation(Box::new(EmptyRepresentation)).id();
+        new.supersede(c, d);
+        assert_eq!(
+            old.diff(&new),
+            IrDiff {
+                added: vec![d],
+                removed: vec![a, c],
+                replaced: vec![b],
+            }
+        );
+    }
+
+    #[test]
+    fn prune_superseded() {
+        let mut ir = HarvestIR::default();
+        let [a, b, c, d] =
+            [1, 2, 3, 4].map(|n| ir.add_representation(Box::new(IdRepresentation(n))).id());
+        ir.supersede(a, b);
+        ir.supersede(b, c);
+        // Only the representation c directly replaced is kept.
+        assert!(ir.superseded_by(a).is_none());
+        assert_eq!(ir.superseded_by(b).map(|(new, _)| new), Some(c));
+        ir.supersede(d, c);
+        ir.remove(c);
+        assert!(ir.superseded_by(b).is_none());
+        assert!(ir.superseded_by(d).is_none());
+        assert_eq!(ir.superseded.len(), 0);
+    }
+
+    #[test]
+    fn typed_id() {
+        let mut ir = HarvestIR::default();
+        let a = ir.add_representation(Box::new(IdRepresentation(1)));
+        let b = ir.add_representation(Box::new(EmptyRepresentation));
+        assert_eq!(ir.get(a), Some(&IdRepresentation(1)));
+        assert_eq!(ir.typed_id::<IdRepresentation>(a.into()), Some(a));
+        assert_eq!(ir.typed_id::<IdRepresentation>(b.into()), None);
+        assert_eq!(ir.typed_id::<IdRepresentation>(Id::new()), None);
+        // A TypedId whose ID was overwritten with a different type no longer resolves.
+        ir.insert(
+            a.id(),
+            Box::new(EmptyRepresentation) as Box<dyn Representation>,
+        );
+        assert_eq!(ir.get(a), None);
+    }
+
+    #[test]
+    fn type_index() {
+        let mut ir = HarvestIR::default();
+        assert_eq!(ir.count::<IdRepresentation>(), 0);
+        assert_eq!(
+            ir.get_one::<IdRepresentation>().err(),
+            Some(GetOneError::NotFound)
+        );
+        let a = ir.add_representation(Box::new(IdRepresentation(1)));
+        ir.add_representation(Box::new(EmptyRepresentation));
+        assert_eq!(ir.count::<IdRepresentation>(), 1);
+        assert_eq!(ir.count::<EmptyRepresentation>(), 1);
+        assert_eq!(
+            ir.get_one::<IdRepresentation>(),
+            Ok((a, &IdRepresentation(1)))
+        );
+        let b = ir.add_representation(Box::new(IdRepresentation(2)));
+        assert_eq!(
+            ir.get_one::<IdRepresentation>().err(),
+            Some(GetOneError::Multiple(2))
+        );
+        // Overwriting a representation with one of a different type must move it in the index.
+        ir.insert(
+            b.id(),
+            Box::new(EmptyRepresentation) as Box<dyn Representation>,
+        );
+        assert_eq!(ir.count::<IdRepresentation>(), 1);
+        assert_eq!(ir.count::<EmptyRepresentation>(), 2);
+        assert_eq!(
+            ir.get_one::<IdRepresentation>(),
+            Ok((a, &IdRepresentation(1)))
+        );
+    }
+
+    #[cfg(not(miri))]
+    #[test]
+    fn save_load() {
+        let tempdir = crate::test_util::tempdir().unwrap();
+        let dir = PathBuf::from_iter([tempdir.path(), "ir".as_ref()]);
+        let mut ir = HarvestIR::default();
+        let a = ir.add_representation(Box::new(IdRepresentation(1)));
+        ir.add_representation(Box::new(EmptyRepresentation));
+        let c = ir.add_representation(Box::new(IdRepresentation(3)));
+        ir.save(&dir).unwrap();
+
+        // Without a loader for IdRepresentation, loading should fail.
+        assert!(matches!(
+            HarvestIR::load(&dir, &Registry::default()),
+            Err(LoadError::UnknownRepresentation(name)) if name == "id"
+        ));
+        let mut registry = Registry::default();
+        registry.register::<IdRepresentation>("id");
+        let mut loaded = HarvestIR::load(&dir, &registry).unwrap();
+        // EmptyRepresentation is not serializable, so it should be skipped.
+        assert_eq!(
+            Vec::from_iter(loaded.get_by_representation::<IdRepresentation>()),
+            [(a, &IdRepresentation(1)), (c, &IdRepresentation(3))]
+        );
+        assert_eq!(loaded.iter().count(), 2);
+        assert!(loaded.is_resumed(a.into()) && loaded.is_resumed(c.into()));
+        assert!(!ir.is_resumed(a.into()));
+        // Writing a loaded representation clears the mark.
+        loaded.insert(
+            a.into(),
+            Box::new(IdRepresentation(2)) as Box<dyn Representation>,
+        );
+        assert!(!loaded.is_resumed(a.into()) && loaded.is_resumed(c.into()));
+    }
 }
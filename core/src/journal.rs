@@ -0,0 +1,342 @@
+//! An on-disk, write-ahead journal of the changes made to an [Organizer]'s IR. If
+//! harvest_translate is interrupted, [Organizer::recover] can rebuild the IR from the journal.
+//!
+//! The journal is a directory containing one entry per IR version, named after the version
+//! (`000`, `001`, ...). Each entry contains a `record` file describing how the version was
+//! created:
+//!
+//! - `snapshot`: the version's complete IR was saved (using [HarvestIR::save]) to `snapshot/` in
+//!   the entry. The first entry is always a snapshot.
+//! - `edit`: the version was created by applying an edit to the previous version. The following
+//!   lines list the edit's tool run and inputs, followed by one line per change. Written
+//!   representations that are serializable are saved in the entry under their ID.
+//!
+//! Entries are written to a temporary directory and renamed into place once complete, so an
+//! interrupted write leaves no partial entry behind.
+
+use crate::diagnostics::ToolRunId;
+use crate::edit::Organizer;
+use crate::ir::{LoadError, Provenance, Registry};
+use crate::{HarvestIR, Id, Representation};
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::fs::{create_dir, read_dir, read_to_string, remove_dir_all, rename, write};
+use std::io;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::warn;
+
+/// A write-ahead journal of the changes made to an IR. See the [module docs](self) for the
+/// on-disk format.
+pub struct Journal {
+    dir: PathBuf,
+}
+
+impl Journal {
+    /// Creates a new journal in `dir`, which must not exist, starting from `ir` at `version`.
+    pub fn create(dir: &Path, ir: &HarvestIR, version: u64) -> io::Result<Journal> {
+        create_dir(dir)?;
+        let journal = Journal { dir: dir.into() };
+        journal.record_snapshot(version, ir)?;
+        Ok(journal)
+    }
+
+    /// Records that `version` was created by applying an edit to the previous version.
+    pub(crate) fn record_edit(
+        &self,
+        version: u64,
+        tool_run: Option<ToolRunId>,
+        inputs: &BTreeSet<Id>,
+        changes: &[JournalChange],
+    ) -> io::Result<()> {
+        self.write_entry(version, |entry| {
+            let mut record = String::from("edit\n");
+            match tool_run {
+                None => record.push_str("tool_run:\n"),
+                Some(tool_run) => _ = writeln!(record, "tool_run: {tool_run}"),
+            }
+            record.push_str("inputs:");
+            inputs
+                .iter()
+                .for_each(|&id| _ = write!(record, " {}", id_string(id)));
+            record.push('\n');
+            for change in changes {
+                match *change {
+                    JournalChange::Write(id, representation) => {
+                        let id_string = id_string(id);
+                        let name = representation.name();
+                        let Some(serializable) = representation.as_serializable() else {
+                            warn!("Not journaling {id}: {name} is not serializable");
+                            _ = writeln!(record, "unsaved {id_string} {name}");
+                            continue;
+                        };
+                        serializable.save(&PathBuf::from_iter([entry, id_string.as_ref()]))?;
+                        _ = writeln!(record, "write {id_string} {name}");
+                    }
+                    JournalChange::Remove(id) => _ = writeln!(record, "remove {}", id_string(id)),
+                    JournalChange::Supersede(old, new) => {
+                        _ = writeln!(record, "supersede {} {}", id_string(old), id_string(new))
+                    }
+                }
+            }
+            write(PathBuf::from_iter([entry, "record".as_ref()]), record)
+        })
+    }
+
+    /// Records that `version`'s IR is `ir`, e.g. because the IR was rolled back.
+    pub(crate) fn record_snapshot(&self, version: u64, ir: &HarvestIR) -> io::Result<()> {
+        self.write_entry(version, |entry| {
+            ir.save(&PathBuf::from_iter([entry, "snapshot".as_ref()]))?;
+            write(PathBuf::from_iter([entry, "record".as_ref()]), "snapshot\n")
+        })
+    }
+
+    /// Creates the entry for `version`, using `f` to fill in its contents.
+    fn write_entry<F: FnOnce(&Path) -> io::Result<()>>(
+        &self,
+        version: u64,
+        f: F,
+    ) -> io::Result<()> {
+        let path = PathBuf::from_iter([self.dir.as_path(), format!("{version:03}").as_ref()]);
+        let tmp_path = path.with_extension("tmp");
+        if tmp_path.exists() {
+            remove_dir_all(&tmp_path)?;
+        }
+        create_dir(&tmp_path)?;
+        f(&tmp_path)?;
+        rename(tmp_path, path)
+    }
+
+    /// Reads the journal in `dir`, returning it (so that new changes can be appended), the IR
+    /// it describes, and that IR's version. Representations are loaded using `registry`.
+    /// Representations that were not serializable cannot be recovered: their IDs (and anything
+    /// they superseded) are left out of the IR.
+    pub(crate) fn recover(
+        dir: &Path,
+        registry: &Registry,
+    ) -> Result<(Journal, HarvestIR, u64), RecoverError> {
+        let mut versions = vec![];
+        for entry in read_dir(dir)? {
+            let entry = entry?;
+            // Skip anything that is not a complete entry, such as a partially-written entry.
+            if let Some(version) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+                versions.push(version);
+            }
+        }
+        versions.sort_unstable();
+        let mut ir = None;
+        for &version in &versions {
+            let entry = PathBuf::from_iter([dir, format!("{version:03}").as_ref()]);
+            let record = read_to_string(PathBuf::from_iter([entry.as_path(), "record".as_ref()]))?;
+            let mut lines = record.lines();
+            match (lines.next(), &mut ir) {
+                (Some("snapshot"), _) => {
+                    let snapshot = PathBuf::from_iter([entry.as_path(), "snapshot".as_ref()]);
+                    ir = Some(HarvestIR::load(&snapshot, registry)?);
+                }
+                (Some("edit"), Some(ir)) => replay_edit(ir, version, &entry, lines, registry)?,
+                (Some("edit"), None) => return Err(RecoverError::MissingSnapshot),
+                (_, _) => return Err(RecoverError::InvalidRecord(version, record)),
+            }
+        }
+        let (Some(mut ir), Some(&version)) = (ir, versions.last()) else {
+            return Err(RecoverError::MissingSnapshot);
+        };
+        ir.mark_resumed();
+        Ok((Journal { dir: dir.into() }, ir, version))
+    }
+}
+
+/// A single change made by an edit, as recorded in the journal.
+pub(crate) enum JournalChange<'r> {
+    Write(Id, &'r dyn Representation),
+    Remove(Id),
+    Supersede(Id, Id),
+}
+
+/// Error type returned by [Organizer::recover].
+#[derive(Debug, Error)]
+pub enum RecoverError {
+    #[error("I/O error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("invalid journal record for version {0}: {1:?}")]
+    InvalidRecord(u64, String),
+    #[error("failed to load representation: {0}")]
+    LoadError(#[from] LoadError),
+    #[error("the journal does not start with a snapshot")]
+    MissingSnapshot,
+}
+
+/// Applies the edit described by the remaining lines of a record to `ir`.
+fn replay_edit<'l>(
+    ir: &mut HarvestIR,
+    version: u64,
+    entry: &Path,
+    mut lines: impl Iterator<Item = &'l str>,
+    registry: &Registry,
+) -> Result<(), RecoverError> {
+    let invalid = |line: &str| RecoverError::InvalidRecord(version, line.into());
+    // The tool run cannot be recovered, as it belongs to the interrupted run.
+    match lines.next() {
+        Some(line) if line.starts_with("tool_run:") => {}
+        line => return Err(invalid(line.unwrap_or_default())),
+    }
+    let inputs = lines.next().unwrap_or_default();
+    let inputs = inputs
+        .strip_prefix("inputs:")
+        .ok_or_else(|| invalid(inputs))?;
+    let inputs = inputs
+        .split_whitespace()
+        .map(|s| parse_id(s).ok_or_else(|| invalid(s)));
+    let provenance = Arc::new(Provenance {
+        tool_run: None,
+        version,
+        inputs: inputs.collect::<Result<_, _>>()?,
+    });
+    let mut superseded = vec![];
+    for line in lines {
+        let words: Vec<_> = line.split(' ').collect();
+        let id = |i: usize| parse_id(words[i]).ok_or_else(|| invalid(line));
+        match words.as_slice() {
+            ["write", id_string, name] => {
+                let path = PathBuf::from_iter([entry, id_string.as_ref()]);
+                ir.insert(id(1)?, registry.load(name, &path)?);
+                ir.provenance.insert(id(1)?, provenance.clone());
+            }
+            // Remove whatever the ID held before, so that tools re-derive it instead of reading a
+            // stale representation.
+            ["unsaved", _, name] => {
+                warn!("Cannot recover {}: {name} was not serializable", id(1)?);
+                ir.remove(id(1)?);
+            }
+            ["remove", _] => ir.remove(id(1)?),
+            ["supersede", _, _] => superseded.push((id(1)?, id(2)?)),
+            _ => return Err(invalid(line)),
+        }
+    }
+    // As in Organizer::apply_edit, superseding is applied after all writes. If the replacement
+    // could not be recovered, the superseded representation is dropped along with it.
+    for (old, new) in superseded {
+        match ir.contains_id(new) {
+            true => ir.supersede(old, new),
+            false => ir.remove(old),
+        }
+    }
+    Ok(())
+}
+
+/// Formats an ID the same way [HarvestIR::save] does.
+fn id_string(id: Id) -> String {
+    format!("{:03}", u64::from(id))
+}
+
+/// Parses an ID formatted by [id_string].
+fn parse_id(s: &str) -> Option<Id> {
+    s.parse().ok().and_then(NonZeroU64::new).map(Id::from_saved)
+}
+
+impl Organizer {
+    /// Rebuilds an `Organizer` from the journal in `dir` (see [Journal]), using `registry` to load
+    /// the journaled representations. The returned `Organizer` continues to append to the
+    /// journal.
+    pub fn recover(dir: &Path, registry: &Registry) -> Result<Organizer, RecoverError> {
+        let (journal, ir, version) = Journal::recover(dir, registry)?;
+        let mut organizer = Organizer::with_harvest_ir(ir);
+        organizer.set_recovered(version, journal);
+        Ok(organizer)
+    }
+}
+
+#[cfg(all(test, not(miri)))]
+mod tests {
+    use super::*;
+    use crate::ir::tests::{EmptyRepresentation, IdRepresentation};
+    use crate::test_util::tempdir;
+
+    #[test]
+    fn recover() {
+        let tempdir = tempdir().unwrap();
+        let dir = PathBuf::from_iter([tempdir.path(), "journal".as_ref()]);
+        let mut registry = Registry::default();
+        registry.register::<IdRepresentation>("id");
+        let mut organizer = Organizer::default();
+        let mut edit = organizer.new_edit(&[].into()).unwrap();
+        let a = edit.add_representation(Box::new(IdRepresentation(1))).id();
+        organizer.apply_edit(edit).unwrap();
+        organizer.start_journal(&dir).unwrap();
+
+        let mut edit = organizer.new_edit(&[a].into()).unwrap();
+        edit.record_read(a);
+        let b = edit.add_representation(Box::new(IdRepresentation(2))).id();
+        let c = edit
+            .supersede(a, Box::new(IdRepresentation(3)))
+            .unwrap()
+            .id();
+        organizer.apply_edit(edit).unwrap();
+        let mut edit = organizer.new_edit(&[b].into()).unwrap();
+        edit.remove_id(b).unwrap();
+        organizer.apply_edit(edit).unwrap();
+
+        let recovered = Organizer::recover(&dir, &registry).unwrap();
+        assert_eq!(recovered.version(), 3);
+        let snapshot = recovered.snapshot();
+        assert_eq!(snapshot.to_string(), organizer.snapshot().to_string());
+        assert_eq!(Vec::from_iter(snapshot.ids().copied()), [c]);
+        assert!(snapshot.is_resumed(c) && !organizer.snapshot().is_resumed(c));
+        assert_eq!(snapshot.superseded_by(a).map(|(new, _)| new), Some(c));
+        assert_eq!(
+            snapshot.provenance(c),
+            Some(&Provenance {
+                tool_run: None,
+                version: 2,
+                inputs: [a].into(),
+            })
+        );
+        drop(recovered);
+
+        organizer.rollback(2).unwrap();
+        let mut edit = organizer.new_edit(&[b].into()).unwrap();
+        edit.write_id(b, Box::new(IdRepresentation(4)));
+        organizer.apply_edit(edit).unwrap();
+        // An interrupted write should be ignored.
+        create_dir(dir.join("006.tmp")).unwrap();
+
+        let recovered = Organizer::recover(&dir, &registry).unwrap();
+        assert_eq!(recovered.version(), 5);
+        let snapshot = recovered.snapshot();
+        assert_eq!(snapshot.to_string(), organizer.snapshot().to_string());
+        assert_eq!(Vec::from_iter(snapshot.ids().copied()), [b, c]);
+    }
+
+    #[test]
+    fn recover_unsaved() {
+        let tempdir = tempdir().unwrap();
+        let dir = PathBuf::from_iter([tempdir.path(), "journal".as_ref()]);
+        let mut registry = Registry::default();
+        registry.register::<IdRepresentation>("id");
+        let mut organizer = Organizer::default();
+        organizer.start_journal(&dir).unwrap();
+        let mut edit = organizer.new_edit(&[].into()).unwrap();
+        let a = edit.add_representation(Box::new(IdRepresentation(1))).id();
+        let b = edit.add_representation(Box::new(IdRepresentation(2))).id();
+        organizer.apply_edit(edit).unwrap();
+
+        // Overwrite a and supersede b with representations that cannot be journaled.
+        let mut edit = organizer.new_edit(&[a, b].into()).unwrap();
+        edit.write_id(a, Box::new(EmptyRepresentation));
+        let c = edit
+            .supersede(b, Box::new(EmptyRepresentation))
+            .unwrap()
+            .id();
+        organizer.apply_edit(edit).unwrap();
+        assert_eq!(Vec::from_iter(organizer.snapshot().ids().copied()), [a, c]);
+
+        let recovered = Organizer::recover(&dir, &registry).unwrap();
+        let snapshot = recovered.snapshot();
+        assert_eq!(snapshot.ids().count(), 0);
+        assert!(snapshot.superseded_by(b).is_none());
+        assert!(snapshot.provenance(a).is_none());
+    }
+}
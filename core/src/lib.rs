@@ -5,14 +5,16 @@ pub mod diagnostics;
 pub mod edit;
 pub mod fs;
 mod id;
+pub mod invariants;
 pub mod ir;
+pub mod journal;
 pub mod tools;
 pub mod utils;
 
 pub mod test_util;
 
 pub use edit::Edit;
-pub use id::Id;
+pub use id::{Id, TypedId};
 pub use ir::{HarvestIR, Representation};
 
 pub mod config;
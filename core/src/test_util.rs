@@ -24,6 +24,7 @@ pub struct MockTool {
     might_write: Box<dyn FnMut(MightWriteContext) -> MightWriteOutcome + Send>,
     #[allow(clippy::type_complexity)]
     run: Box<dyn FnOnce(RunContext) -> Result<(), Box<dyn Error>> + Send>,
+    retry: Option<Box<dyn Fn() -> MockTool + Send>>,
 }
 
 impl Default for MockTool {
@@ -50,6 +51,7 @@ impl MockTool {
             name: "mock_tool",
             might_write: Box::new(|_| MightWriteOutcome::Runnable([].into())),
             run: Box::new(|_| Ok(())),
+            retry: None,
         }
     }
 
@@ -81,6 +83,13 @@ impl MockTool {
         self.run = Box::new(f);
         self
     }
+
+    /// Sets a closure that constructs the tool returned by `Tool::retry`. By default, the
+    /// MockTool is not retried.
+    pub fn retry<F: Fn() -> MockTool + Send + 'static>(mut self, f: F) -> MockTool {
+        self.retry = Some(Box::new(f));
+        self
+    }
 }
 
 impl Tool for MockTool {
@@ -95,4 +104,8 @@ impl Tool for MockTool {
     fn run(self: Box<Self>, context: RunContext) -> Result<(), Box<dyn Error>> {
         (self.run)(context)
     }
+
+    fn retry(&self) -> Option<Box<dyn Tool>> {
+        self.retry.as_ref().map(|f| f().boxed() as Box<dyn Tool>)
+    }
 }
@@ -1,9 +1,10 @@
 //! Individual tools (and their interfaces) used by HARVEST to translate C to Rust.
 
-use crate::config::Config;
+use crate::config::{Config, ToolConfig, ToolConfigErrors};
 use crate::diagnostics::ToolReporter;
 use crate::{Edit, HarvestIR, Id};
-use std::collections::HashSet;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
 
 /// Trait implemented by each tool. Used by the scheduler to decide what tools
@@ -30,11 +31,29 @@ pub trait Tool: Send + 'static {
     /// `MightWriteOutcome::Runnable` does not guarantee that this tool will be executed.
     fn might_write(&mut self, context: MightWriteContext) -> MightWriteOutcome;
 
+    /// Returns the IDs of representations that must not change while this tool runs. This is
+    /// called after `might_write` returns `MightWriteOutcome::Runnable`, with the same IR. While
+    /// the tool is running, no tool that might write one of these IDs will be started.
+    ///
+    /// The default implementation returns an empty set, in which case other tools may replace the
+    /// tool's inputs while it runs (see [Edit::record_read] for how that is detected).
+    fn might_read(&mut self, _context: MightWriteContext) -> HashSet<Id> {
+        HashSet::new()
+    }
+
     /// Runs the tool logic. IR access and edits are made using `context`.
     ///
     /// If `Ok` is returned the changes will be applied to the IR, and if `Err`
     /// is returned the changes will not be applied.
     fn run(self: Box<Self>, context: RunContext) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Returns a fresh copy of this tool invocation, which the scheduler will retry if this
+    /// invocation's edit is rejected because a representation it read (see
+    /// [Edit::record_read](crate::edit::Edit::record_read)) changed while it was running. Tools
+    /// that return `None` (the default) are not retried.
+    fn retry(&self) -> Option<Box<dyn Tool>> {
+        None
+    }
 }
 
 /// Context passed to `Tool::might_write`. This is a struct so that new values may be added without
@@ -54,7 +73,6 @@ impl<'a> MightWriteContext<'a> {
 /// Result of a `Tool::might_write` execution.
 pub enum MightWriteOutcome {
     /// This tool is not and will not be runnable. Tells the scheduler to discard the tool.
-    #[allow(unused)] // TODO: Remove when we have a tool that returns this.
     NotRunnable,
 
     /// This tool is runnable. The set of IDs returned are the IDs for representations in the
@@ -76,7 +94,9 @@ pub struct RunContext<'a> {
     pub ir_edit: &'a mut Edit,
 
     /// Read access to the IR. This will be the same IR as `might_write` was
-    /// most recently called with.
+    /// most recently called with. Tools should record the IDs they read from it using
+    /// [Edit::record_read], so that their edit is not applied if those IDs change before the tool
+    /// completes.
     pub ir_snapshot: Arc<HarvestIR>,
 
     /// Configuration for the current harvest_translate run.
@@ -86,3 +106,111 @@ pub struct RunContext<'a> {
     /// inside the diagnostics directory).
     pub reporter: ToolReporter,
 }
+
+impl RunContext<'_> {
+    /// Returns the config of the tool named `tool`, which was parsed and validated when the
+    /// configuration was loaded (see [ToolConfigRegistry](crate::config::ToolConfigRegistry)).
+    /// Returns `None` if no config of type `C` was registered for `tool`.
+    pub fn tool_config<C: ToolConfig>(&self, tool: &str) -> Option<Arc<C>> {
+        self.config.tool_configs.get(tool)
+    }
+}
+
+/// Constructs an invocation of a tool from the configuration and the invocation's arguments (see
+/// [Config::pipeline]). Returns a description of each problem with the arguments.
+pub type ToolConstructor = fn(&Config, &Map<String, Value>) -> Result<Box<dyn Tool>, Vec<String>>;
+
+/// Maps tool names to their constructors. Used to create the invocations listed in
+/// [Config::pipeline].
+#[derive(Default)]
+pub struct ToolRegistry {
+    constructors: BTreeMap<&'static str, ToolConstructor>,
+}
+
+impl ToolRegistry {
+    /// Registers `constructor` as the constructor of the tool named `tool`.
+    pub fn register(&mut self, tool: &'static str, constructor: ToolConstructor) {
+        self.constructors.insert(tool, constructor);
+    }
+
+    /// Constructs the invocations listed in `config.pipeline`, in order. If any invocation names
+    /// an unregistered tool or has invalid arguments, returns every problem found.
+    pub fn pipeline(&self, config: &Config) -> Result<Vec<Box<dyn Tool>>, ToolConfigErrors> {
+        let mut invocations = vec![];
+        let mut errors = vec![];
+        for (i, invocation) in config.pipeline.iter().enumerate() {
+            let tool = &invocation.tool;
+            let Some(constructor) = self.constructors.get(tool.as_str()) else {
+                errors.push(format!("pipeline[{i}]: unknown tool {tool:?}"));
+                continue;
+            };
+            match constructor(config, &invocation.args) {
+                Ok(invocation) => invocations.push(invocation),
+                Err(tool_errors) => errors.extend(
+                    tool_errors
+                        .into_iter()
+                        .map(|error| format!("pipeline[{i}] ({tool}): {error}")),
+                ),
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(invocations),
+            false => Err(ToolConfigErrors(errors)),
+        }
+    }
+}
+
+/// Returns an error for each argument in `args`. For use by the constructors of tools that take
+/// no arguments.
+pub fn no_arguments(args: &Map<String, Value>) -> Result<(), Vec<String>> {
+    match args.is_empty() {
+        true => Ok(()),
+        false => Err(args
+            .keys()
+            .map(|arg| format!("unknown argument {arg:?}"))
+            .collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Invocation;
+    use crate::test_util::MockTool;
+    use serde_json::json;
+
+    #[test]
+    fn tool_registry() {
+        let mut registry = ToolRegistry::default();
+        registry.register("a", |_, args| {
+            no_arguments(args)?;
+            Ok(MockTool::new().name("a").boxed())
+        });
+        registry.register("b", |_, _| Ok(MockTool::new().name("b").boxed()));
+        let invocation = |tool: &str, args: Value| Invocation {
+            tool: tool.into(),
+            args: serde_json::from_value(args).unwrap(),
+        };
+        let mut config = Config::mock();
+        config.pipeline = vec![
+            invocation("b", json!({"model": "m"})),
+            invocation("a", json!({})),
+            invocation("b", json!({})),
+        ];
+        let names: Vec<_> = registry
+            .pipeline(&config)
+            .unwrap()
+            .iter()
+            .map(|t| t.name())
+            .collect();
+        assert_eq!(names, ["b", "a", "b"]);
+        config.pipeline = vec![invocation("c", json!({})), invocation("a", json!({"x": 1}))];
+        assert_eq!(
+            registry.pipeline(&config).err().unwrap().0,
+            [
+                "pipeline[0]: unknown tool \"c\"",
+                "pipeline[1] (a): unknown argument \"x\""
+            ]
+        );
+    }
+}
@@ -1,5 +1,10 @@
+use std::any::Any;
+use std::fmt::Write as _;
+use std::fs::{create_dir, read_dir};
+use std::io;
 use std::path::Path;
 
+use harvest_core::ir::SerializableRepresentation;
 use harvest_core::{Representation, fs::RawDir};
 
 /// A raw C project passed as input.
@@ -22,6 +27,27 @@ impl Representation for RawSource {
     fn materialize(&self, path: &Path) -> std::io::Result<()> {
         self.dir.materialize(path)
     }
+
+    fn as_serializable(&self) -> Option<&dyn SerializableRepresentation> {
+        Some(self)
+    }
+
+    fn diff(&self, old: &dyn Representation) -> Option<String> {
+        let old: &RawSource = <dyn Any>::downcast_ref(old)?;
+        Some(dir_diff(&old.dir, &self.dir))
+    }
+}
+
+impl SerializableRepresentation for RawSource {
+    fn save(&self, path: &Path) -> io::Result<()> {
+        save_dir(&self.dir, path)
+    }
+
+    fn load(path: &Path) -> io::Result<Self> {
+        Ok(RawSource {
+            dir: load_dir(path)?,
+        })
+    }
 }
 
 /// A cargo project representation (Cargo.toml, src/, etc).
@@ -44,4 +70,46 @@ impl Representation for CargoPackage {
     fn materialize(&self, path: &Path) -> std::io::Result<()> {
         self.dir.materialize(path)
     }
+
+    fn as_serializable(&self) -> Option<&dyn SerializableRepresentation> {
+        Some(self)
+    }
+
+    fn diff(&self, old: &dyn Representation) -> Option<String> {
+        let old: &CargoPackage = <dyn Any>::downcast_ref(old)?;
+        Some(dir_diff(&old.dir, &self.dir))
+    }
+}
+
+impl SerializableRepresentation for CargoPackage {
+    fn save(&self, path: &Path) -> io::Result<()> {
+        save_dir(&self.dir, path)
+    }
+
+    fn load(path: &Path) -> io::Result<Self> {
+        Ok(CargoPackage {
+            dir: load_dir(path)?,
+        })
+    }
+}
+
+/// Saves `dir` as a new directory at `path`. Unlike [RawDir::materialize], this creates `path`
+/// even if `dir` contains no files, so that [load_dir] can read it back.
+fn save_dir(dir: &RawDir, path: &Path) -> io::Result<()> {
+    create_dir(path)?;
+    dir.materialize(path)
+}
+
+/// Loads a directory written by [save_dir].
+fn load_dir(path: &Path) -> io::Result<RawDir> {
+    Ok(RawDir::populate_from(read_dir(path)?)?.0)
+}
+
+/// Lists the files that changed between `old` and `new`, one per line.
+fn dir_diff(old: &RawDir, new: &RawDir) -> String {
+    let mut out = String::new();
+    for (path, change) in old.changed_files(new) {
+        let _ = writeln!(out, "{change} {}", path.display());
+    }
+    out
 }
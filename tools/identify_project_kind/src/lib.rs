@@ -1,6 +1,11 @@
+use std::collections::HashSet;
 use std::fmt::Display;
+use std::fs::{read_to_string, write};
+use std::io;
+use std::path::Path;
 
-use harvest_core::Representation;
+use harvest_core::ir::SerializableRepresentation;
+use harvest_core::{Id, Representation};
 
 use full_source::RawSource;
 use harvest_core::tools::{MightWriteContext, MightWriteOutcome, RunContext, Tool};
@@ -23,6 +28,24 @@ impl Representation for ProjectKind {
     fn name(&self) -> &'static str {
         "KindAndName"
     }
+
+    fn as_serializable(&self) -> Option<&dyn SerializableRepresentation> {
+        Some(self)
+    }
+}
+
+impl SerializableRepresentation for ProjectKind {
+    fn save(&self, path: &Path) -> io::Result<()> {
+        write(path, self.to_string())
+    }
+
+    fn load(path: &Path) -> io::Result<Self> {
+        match read_to_string(path)?.trim() {
+            "Library" => Ok(ProjectKind::Library),
+            "Executable" => Ok(ProjectKind::Executable),
+            other => Err(io::Error::other(format!("unknown project kind {other:?}"))),
+        }
+    }
 }
 
 pub struct IdentifyProjectKind;
@@ -33,6 +56,9 @@ impl Tool for IdentifyProjectKind {
     }
 
     fn might_write(&mut self, context: MightWriteContext) -> MightWriteOutcome {
+        if context.ir.count::<ProjectKind>() > 0 {
+            return MightWriteOutcome::NotRunnable;
+        }
         // We need a raw_source to be available, but we won't write any existing IDs.
         match context.ir.get_by_representation::<RawSource>().next() {
             None => MightWriteOutcome::TryAgain,
@@ -40,8 +66,14 @@ impl Tool for IdentifyProjectKind {
         }
     }
 
+    fn might_read(&mut self, context: MightWriteContext) -> HashSet<Id> {
+        let raw_sources = context.ir.get_by_representation::<RawSource>();
+        raw_sources.map(|(id, _)| id.into()).collect()
+    }
+
     fn run(self: Box<Self>, context: RunContext) -> Result<(), Box<dyn std::error::Error>> {
-        for (_, repr) in context.ir_snapshot.get_by_representation::<RawSource>() {
+        for (id, repr) in context.ir_snapshot.get_by_representation::<RawSource>() {
+            context.ir_edit.record_read(id);
             if let Ok(cmakelists) = repr.dir.get_file("CMakeLists.txt") {
                 if String::from_utf8_lossy(cmakelists)
                     .lines()
@@ -62,4 +94,8 @@ impl Tool for IdentifyProjectKind {
         }
         Ok(())
     }
+
+    fn retry(&self) -> Option<Box<dyn Tool>> {
+        Some(Box::new(IdentifyProjectKind))
+    }
 }
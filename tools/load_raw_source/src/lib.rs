@@ -1,22 +1,44 @@
-//! Lifts a source code project into a RawSource representation.
+//! Lifts a source code project (a directory or a tar archive) into a RawSource representation.
 
 use full_source::RawSource;
-use harvest_core::fs::RawDir;
+use harvest_core::config::{self, ToolConfig};
+use harvest_core::fs::{EscapingSymlinks, PopulateFilter, PopulateOptions, RawDir};
 use harvest_core::tools::{MightWriteContext, MightWriteOutcome, RunContext, Tool};
-use std::fs::read_dir;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs::{File, metadata, read_dir};
 use std::path::{Path, PathBuf};
-use tracing::info;
+use std::sync::Arc;
+use tracing::{debug, info};
 
 pub struct LoadRawSource {
-    directory: PathBuf,
+    /// The directory or tar archive (optionally gzip-compressed) to load.
+    input: PathBuf,
+    /// This invocation's config, if its arguments override the `tools.load_raw_source` section.
+    config: Option<Arc<Config>>,
 }
 
 impl LoadRawSource {
-    pub fn new(directory: &Path) -> LoadRawSource {
+    pub fn new(input: &Path) -> LoadRawSource {
         LoadRawSource {
-            directory: directory.into(),
+            input: input.into(),
+            config: None,
         }
     }
+
+    /// Constructs an invocation from a `pipeline` entry, which loads `config.input`. `args`
+    /// override values of the `tools.load_raw_source` section.
+    pub fn construct(
+        config: &config::Config,
+        args: &Map<String, Value>,
+    ) -> Result<Box<dyn Tool>, Vec<String>> {
+        let mut tool = LoadRawSource::new(&config.input);
+        if !args.is_empty() {
+            tool.config = Some(config.invocation_config("load_raw_source", args)?);
+        }
+        Ok(Box::new(tool))
+    }
 }
 
 impl Tool for LoadRawSource {
@@ -25,21 +47,100 @@ impl Tool for LoadRawSource {
     }
 
     // LoadRawSource will create a new representation, not modify an existing
-    // one.
-    fn might_write(&mut self, _context: MightWriteContext) -> MightWriteOutcome {
-        MightWriteOutcome::Runnable([].into())
+    // one. If the IR already has a RawSource (e.g. it was resumed from a saved
+    // IR), there is nothing to do.
+    fn might_write(&mut self, context: MightWriteContext) -> MightWriteOutcome {
+        match context.ir.count::<RawSource>() {
+            0 => MightWriteOutcome::Runnable([].into()),
+            _ => MightWriteOutcome::NotRunnable,
+        }
     }
 
     fn run(self: Box<Self>, context: RunContext) -> Result<(), Box<dyn std::error::Error>> {
-        let dir = read_dir(self.directory.clone())?;
-        let (rawdir, directories, files) = RawDir::populate_from(dir)?;
+        let config = self
+            .config
+            .or_else(|| context.tool_config::<Config>("load_raw_source"))
+            .unwrap_or_default();
+        debug!("load_raw_source configuration {config:?}");
+        let options = PopulateOptions {
+            escaping_symlinks: config.escaping_symlinks,
+            preserve_mtimes: config.preserve_mtimes,
+            filter: PopulateFilter {
+                ignore_files: config.ignore_files,
+                exclude: config.exclude.clone(),
+                max_file_size: config.max_file_size,
+            },
+        };
+        let populated = match metadata(&self.input)?.is_dir() {
+            true => RawDir::populate_from_with(read_dir(&self.input)?, &options)?,
+            false => RawDir::from_tar(File::open(&self.input)?)?
+                .unwrap_single_dir()
+                .filtered(&options.filter)?,
+        };
+        for (path, reason) in &populated.skipped {
+            info!("Skipped {}: {reason}", path.display());
+        }
         info!(
-            "Loaded {directories} directories and {files} files from {}.",
-            self.directory.display()
+            "Loaded {} directories and {} files from {} ({} paths skipped).",
+            populated.directories,
+            populated.files,
+            self.input.display(),
+            populated.skipped.len()
         );
         context
             .ir_edit
-            .add_representation(Box::new(RawSource { dir: rawdir }));
+            .add_representation(Box::new(RawSource { dir: populated.dir }));
         Ok(())
     }
 }
+
+/// Configuration for loading a directory or archive. Archives may not contain symlinks that point
+/// outside the archive, and their files' modification times are always recorded; the other
+/// settings apply to archives as they do to directories. Missing values default to the values in
+/// `translate/default_config.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// What to do with symlinks that point outside the input directory: "reject" them (the tool
+    /// fails), or "follow" them and load their targets in their place.
+    pub escaping_symlinks: EscapingSymlinks,
+
+    /// Whether to record the input files' modification times, so that they are restored when the
+    /// source is materialized.
+    pub preserve_mtimes: bool,
+
+    /// Whether to honor `.gitignore` and `.harvestignore` files in the input.
+    pub ignore_files: bool,
+
+    /// Additional gitignore-style patterns, relative to the input's root directory, for paths to
+    /// skip.
+    pub exclude: Vec<String>,
+
+    /// Files larger than this many bytes are skipped.
+    pub max_file_size: Option<u64>,
+
+    #[serde(flatten)]
+    unknown: HashMap<String, Value>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let exclude = [
+            ".git/", "*.o", "*.obj", "*.a", "*.so", "*.dylib", "*.dll", "*.exe",
+        ];
+        Config {
+            escaping_symlinks: EscapingSymlinks::Reject,
+            preserve_mtimes: false,
+            ignore_files: true,
+            exclude: exclude.map(String::from).into(),
+            max_file_size: Some(1024 * 1024),
+            unknown: HashMap::new(),
+        }
+    }
+}
+
+impl ToolConfig for Config {
+    fn unknown_fields(&self) -> Option<&HashMap<String, Value>> {
+        Some(&self.unknown)
+    }
+}
@@ -2,17 +2,21 @@
 //! an LLM via the `llm` crate.
 
 use full_source::{CargoPackage, RawSource};
-use harvest_core::config::unknown_field_warning;
+use harvest_core::config::{self, ToolConfig};
+use harvest_core::edit::NotWritable;
 use harvest_core::fs::RawDir;
 use harvest_core::tools::{MightWriteContext, MightWriteOutcome, RunContext, Tool};
+use harvest_core::{Edit, HarvestIR, Id, TypedId};
 use llm::builder::{LLMBackend, LLMBuilder};
 use llm::chat::{ChatMessage, StructuredOutputFormat};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::collections::HashMap;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 use tracing::{debug, info, trace};
+use try_cargo_build::CargoBuildResult;
 
 use identify_project_kind::ProjectKind;
 
@@ -22,7 +26,38 @@ const STRUCTURED_OUTPUT_SCHEMA: &str = include_str!("structured_schema.json");
 const SYSTEM_PROMPT_EXECUTABLE: &str = include_str!("system_prompt_executable.txt");
 const SYSTEM_PROMPT_LIBRARY: &str = include_str!("system_prompt_library.txt");
 
-pub struct RawSourceToCargoLlm;
+#[derive(Clone, Default)]
+pub struct RawSourceToCargoLlm {
+    /// This invocation's config, if its arguments override the `tools.raw_source_to_cargo_llm`
+    /// section.
+    config: Option<Arc<Config>>,
+    /// This invocation's [Config::replace_resumed_package].
+    replace_resumed_package: bool,
+}
+
+impl RawSourceToCargoLlm {
+    /// Constructs an invocation from a `pipeline` entry. `args` override values of the
+    /// `tools.raw_source_to_cargo_llm` section, so that (for example) several invocations can use
+    /// different models.
+    pub fn construct(
+        config: &config::Config,
+        args: &Map<String, Value>,
+    ) -> Result<Box<dyn Tool>, Vec<String>> {
+        let section = config.tool_configs.get::<Config>("raw_source_to_cargo_llm");
+        let config = match args.is_empty() {
+            true => None,
+            false => Some(config.invocation_config("raw_source_to_cargo_llm", args)?),
+        };
+        let replace_resumed_package = config
+            .as_ref()
+            .or(section.as_ref())
+            .is_some_and(|config| config.replace_resumed_package);
+        Ok(Box::new(RawSourceToCargoLlm {
+            config,
+            replace_resumed_package,
+        }))
+    }
+}
 
 impl Tool for RawSourceToCargoLlm {
     fn name(&self) -> &'static str {
@@ -30,33 +65,52 @@ impl Tool for RawSourceToCargoLlm {
     }
 
     fn might_write(&mut self, context: MightWriteContext) -> MightWriteOutcome {
-        // We need a raw_source to be available, but we won't write any existing IDs.
+        // A package carried over from a resumed or recovered IR is the finished translation,
+        // unless this invocation was configured to replace it.
+        let mut packages = context.ir.get_by_representation::<CargoPackage>();
+        if !self.replace_resumed_package && packages.any(|(id, _)| context.ir.is_resumed(id.into()))
+        {
+            return MightWriteOutcome::NotRunnable;
+        }
+        // We need a raw_source to be available. Packages written by earlier invocations are
+        // superseded (see replace_package).
         match (
             context.ir.get_by_representation::<ProjectKind>().next(),
             context.ir.get_by_representation::<RawSource>().next(),
         ) {
-            (Some(_), Some(_)) => MightWriteOutcome::Runnable([].into()),
+            (Some(_), Some(_)) => MightWriteOutcome::Runnable(replaced_ids(context.ir)),
             _ => MightWriteOutcome::TryAgain,
         }
     }
 
+    fn might_read(&mut self, context: MightWriteContext) -> HashSet<Id> {
+        // run uses the first ProjectKind and RawSource.
+        let project_kind = context.ir.get_by_representation::<ProjectKind>();
+        let raw_source = context.ir.get_by_representation::<RawSource>();
+        let project_kind = project_kind.map(|(id, _)| Id::from(id));
+        let raw_source = raw_source.map(|(id, _)| Id::from(id));
+        project_kind.take(1).chain(raw_source.take(1)).collect()
+    }
+
     fn run(self: Box<Self>, context: RunContext) -> Result<(), Box<dyn std::error::Error>> {
-        let config =
-            Config::deserialize(context.config.tools.get("raw_source_to_cargo_llm").unwrap())?;
+        let config = self
+            .config
+            .or_else(|| context.tool_config::<Config>("raw_source_to_cargo_llm"))
+            .ok_or("missing raw_source_to_cargo_llm configuration")?;
         debug!("LLM Configuration {config:?}");
-        let in_dir = &context
+        let (raw_source, in_dir) = context
             .ir_snapshot
             .get_by_representation::<RawSource>()
             .next()
-            .unwrap()
-            .1
-            .dir;
-        let project_kind = context
+            .map(|(id, r)| (id, &r.dir))
+            .unwrap();
+        let (project_kind_id, project_kind) = context
             .ir_snapshot
             .get_by_representation::<ProjectKind>()
             .next()
-            .unwrap()
-            .1;
+            .unwrap();
+        context.ir_edit.record_read(raw_source);
+        context.ir_edit.record_read(project_kind_id);
 
         // Use the llm crate to connect to Ollama.
 
@@ -106,9 +160,9 @@ impl Tool for RawSourceToCargoLlm {
         // Assemble the Ollama request.
         let mut request = vec!["Please translate the following C project into a Rust project including Cargo manifest:".into()];
         request.push(
-            serde_json::json!({"files": (&in_dir.files_recursive().iter().map(|(path, contents)| {
+            serde_json::json!({"files": (&in_dir.files().map(|(path, contents)| {
                 OutputFile {
-                    path: path.clone(),
+                    path,
                     contents: String::from_utf8_lossy(contents).into(),
                 }
         }).collect::<Vec<OutputFile>>())})
@@ -145,13 +199,21 @@ impl Tool for RawSourceToCargoLlm {
         info!("LLM response contains {} files.", files.files.len());
         let mut out_dir = RawDir::default();
         for file in files.files {
+            // Scripts (e.g. build or test helpers) need to be executable to be run.
+            let executable = file.contents.starts_with("#!");
             out_dir.set_file(&file.path, file.contents.into())?;
+            if executable {
+                out_dir.set_executable(&file.path, true)?;
+            }
         }
-        context
-            .ir_edit
-            .add_representation(Box::new(CargoPackage { dir: out_dir }));
+        let package = CargoPackage { dir: out_dir };
+        replace_package(context.ir_edit, &context.ir_snapshot, package)?;
         Ok(())
     }
+
+    fn retry(&self) -> Option<Box<dyn Tool>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Deserialize)]
@@ -180,15 +242,40 @@ pub struct Config {
     /// Maximum output tokens.
     pub max_tokens: u32,
 
+    /// Whether to run even if the IR already contains a Cargo package carried over from a resumed
+    /// or recovered run, replacing it. Otherwise, such a package counts as the finished
+    /// translation. Set this on a later `pipeline` entry (e.g. one using a different model) for it
+    /// to run after resuming.
+    #[serde(default)]
+    pub replace_resumed_package: bool,
+
     #[serde(flatten)]
     unknown: HashMap<String, Value>,
 }
 
-impl Config {
-    pub fn validate(&self) {
-        unknown_field_warning("tools.raw_source_to_cargo_llm", &self.unknown);
+impl ToolConfig for Config {
+    const SECRET_FIELDS: &'static [&'static str] = &["api_key"];
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if self.backend != "openrouter" && LLMBackend::from_str(&self.backend).is_err() {
+            errors.push(format!("unknown backend {:?}", self.backend));
+        }
+        if self.model.is_empty() {
+            errors.push("model must not be empty".into());
+        }
+        if self.max_tokens == 0 {
+            errors.push("max_tokens must be positive".into());
+        }
+        errors
     }
 
+    fn unknown_fields(&self) -> Option<&HashMap<String, Value>> {
+        Some(&self.unknown)
+    }
+}
+
+impl Config {
     /// Returns a mock config for testing.
     pub fn mock() -> Self {
         Self {
@@ -197,11 +284,42 @@ impl Config {
             backend: "mock_llm".into(),
             model: "mock_model".into(),
             max_tokens: 1000,
+            replace_resumed_package: false,
             unknown: HashMap::new(),
         }
     }
 }
 
+/// Adds `package` to `edit`. The live packages in `ir`, which were written by earlier invocations
+/// of this tool, are superseded by it, as are their build results (which do not describe the new
+/// package). `edit` must be able to write those IDs, which it can if it was created from
+/// [RawSourceToCargoLlm]'s `might_write`.
+pub fn replace_package(
+    edit: &mut Edit,
+    ir: &HarvestIR,
+    package: CargoPackage,
+) -> Result<TypedId<CargoPackage>, NotWritable> {
+    let new = edit.add_representation(Box::new(package));
+    for old in replaced_ids(ir) {
+        edit.supersede_with(old, new)?;
+    }
+    Ok(new)
+}
+
+/// Returns the IDs of the live packages in `ir` and of their build results.
+fn replaced_ids(ir: &HarvestIR) -> HashSet<Id> {
+    let packages: HashSet<Id> = ir
+        .get_by_representation::<CargoPackage>()
+        .map(|(id, _)| id.into())
+        .collect();
+    let results: Vec<Id> = ir
+        .get_by_representation::<CargoBuildResult>()
+        .filter(|(_, result)| packages.contains(&result.package))
+        .map(|(id, _)| id.into())
+        .collect();
+    packages.into_iter().chain(results).collect()
+}
+
 /// Structure representing a file created by the LLM.
 #[derive(Debug, Deserialize, Serialize)]
 struct OutputFile {
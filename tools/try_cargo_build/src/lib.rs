@@ -1,8 +1,14 @@
 //! Checks if a generated Rust project builds by materializing
-//! it to a tempdir and running `cargo build --release`.
+//! it to the output directory and running `cargo build --release`.
 use full_source::CargoPackage;
+use harvest_core::fs::{MaterializeMode, RawDir};
+use harvest_core::ir::SerializableRepresentation;
 use harvest_core::tools::{MightWriteContext, MightWriteOutcome, RunContext, Tool};
-use harvest_core::{HarvestIR, Representation, fs::RawDir};
+use harvest_core::{HarvestIR, Id, Representation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{read, write};
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use tracing::info;
@@ -78,23 +84,19 @@ fn try_cargo_build(project_path: &PathBuf) -> Result<BuildResult, Box<dyn std::e
         Ok(Err(error_message))
     }
 }
-/// Returns the CargoPackage representation in IR.
+/// Returns `true` if the IR contains a CargoBuildResult for the CargoPackage `package`.
+fn is_built(ir: &HarvestIR, package: Id) -> bool {
+    ir.get_by_representation::<CargoBuildResult>()
+        .any(|(_, result)| result.package == package)
+}
+
+/// Returns the ID and contents of the CargoPackage representation in IR.
 /// If there is not exactly 1 CargoPackage representation,
 /// return an error.
-fn raw_cargo_package(ir: &HarvestIR) -> Result<&RawDir, Box<dyn std::error::Error>> {
-    let cargo_packages: Vec<&RawDir> = ir
-        .get_by_representation::<CargoPackage>()
-        .map(|(_, r)| &r.dir)
-        .collect();
-
-    match cargo_packages.len() {
-        0 => Err("No CargoPackage representation found in IR".into()),
-        1 => Ok(cargo_packages[0]),
-        n => Err(format!(
-            "Found {} CargoPackage representations, expected at most 1",
-            n
-        )
-        .into()),
+fn raw_cargo_package(ir: &HarvestIR) -> Result<(Id, &RawDir), Box<dyn std::error::Error>> {
+    match ir.get_one::<CargoPackage>() {
+        Ok((id, package)) => Ok((id.into(), &package.dir)),
+        Err(error) => Err(format!("CargoPackage: {error}").into()),
     }
 }
 
@@ -104,18 +106,32 @@ impl Tool for TryCargoBuild {
     }
 
     fn might_write(&mut self, context: MightWriteContext) -> MightWriteOutcome {
-        // We need a cargo_package to be available, but we won't write any existing IDs.
+        // We need a cargo_package that has not been built yet, but we won't write any existing
+        // IDs. If the package has already been built, a later tool may still replace it.
         match raw_cargo_package(context.ir) {
-            Err(_) => MightWriteOutcome::TryAgain,
-            Ok(_) => MightWriteOutcome::Runnable([].into()),
+            Ok((id, _)) if !is_built(context.ir, id) => MightWriteOutcome::Runnable([].into()),
+            _ => MightWriteOutcome::TryAgain,
         }
     }
 
+    fn might_read(&mut self, context: MightWriteContext) -> HashSet<Id> {
+        raw_cargo_package(context.ir)
+            .map(|(id, _)| id)
+            .into_iter()
+            .collect()
+    }
+
     fn run(self: Box<Self>, context: RunContext) -> Result<(), Box<dyn std::error::Error>> {
         // Get cargo package representation
-        let cargo_package = raw_cargo_package(&context.ir_snapshot)?;
+        let (package, cargo_package) = raw_cargo_package(&context.ir_snapshot)?;
+        context.ir_edit.record_read(package);
         let output_path = context.config.output.clone();
-        cargo_package.materialize(&output_path)?;
+        // Only rewrite changed files, and keep cargo's outputs, so that cargo can reuse the
+        // previous build's artifacts.
+        let mode = MaterializeMode::Incremental {
+            keep: vec!["target".into(), "Cargo.lock".into()],
+        };
+        cargo_package.materialize_with(&output_path, &mode)?;
 
         // Validate that the Rust project builds
         let compilation_result = try_cargo_build(&output_path)?;
@@ -123,21 +139,29 @@ impl Tool for TryCargoBuild {
         context
             .ir_edit
             .add_representation(Box::new(CargoBuildResult {
+                package,
                 result: compilation_result,
             }));
 
         Ok(())
     }
+
+    fn retry(&self) -> Option<Box<dyn Tool>> {
+        Some(Box::new(TryCargoBuild))
+    }
 }
 
 /// A Representation that contains the results of running `cargo build`.
+#[derive(Deserialize, Serialize)]
 pub struct CargoBuildResult {
+    /// ID of the CargoPackage that was built.
+    pub package: Id,
     pub result: Result<Vec<PathBuf>, String>,
 }
 
 impl std::fmt::Display for CargoBuildResult {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        writeln!(f, "Built Rust artifact:")?;
+        writeln!(f, "Built Rust artifact from {}:", self.package)?;
         let artifact_filenames = match &self.result {
             Err(err) => return writeln!(f, "  Build failed: {err}"),
             Ok(filenames) => filenames,
@@ -158,4 +182,18 @@ impl Representation for CargoBuildResult {
     fn materialize(&self, _path: &Path) -> std::io::Result<()> {
         Ok(())
     }
+
+    fn as_serializable(&self) -> Option<&dyn SerializableRepresentation> {
+        Some(self)
+    }
+}
+
+impl SerializableRepresentation for CargoBuildResult {
+    fn save(&self, path: &Path) -> io::Result<()> {
+        write(path, serde_json::to_vec(self)?)
+    }
+
+    fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&read(path)?)?)
+    }
 }
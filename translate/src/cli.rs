@@ -3,12 +3,16 @@
 
 use clap::Parser;
 use config::FileFormat::Toml;
+use config::{ConfigError, Environment, Map, Source, ValueKind};
 use directories::ProjectDirs;
-use harvest_core::config::Config;
+use harvest_core::config::{Config, ToolConfigErrors, ToolConfigRegistry};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use thiserror::Error;
 
 /// Command-line arguments for HARVEST's `translate` binary.
 #[derive(Debug, Parser)]
@@ -21,17 +25,32 @@ pub struct Args {
     #[arg(long, short)]
     pub force: bool,
 
-    /// Path to the directory containing the C code to translate.
+    /// Path to the directory containing the C code to translate, or to a tar archive (optionally
+    /// gzip-compressed) of that directory.
     // Should always be present unless using a subcommand like --print-config-path
     pub input: Option<PathBuf>,
 
+    /// Prints the merged config as TOML, noting where each value came from, then exits. Secrets
+    /// are redacted.
+    #[arg(long)]
+    pub print_config: bool,
+
     /// Prints out the location of the config file.
     #[arg(long)]
     pub print_config_path: bool,
 
+    /// Apply the named `[profile.<name>]` config table (overrides the `default_profile` config
+    /// value).
+    #[arg(long)]
+    pub profile: Option<String>,
+
     /// Path to output directory containing the translated Rust code.
     #[arg(short, long)]
     pub output: Option<PathBuf>,
+
+    /// Rebuild the IR from the journal of an interrupted run, then continue translating.
+    #[arg(long)]
+    pub recover: Option<PathBuf>,
 }
 
 /// Prints out a warning message for every field in `unknown`.
@@ -51,69 +70,362 @@ pub(crate) fn unknown_field_warning(prefix: &str, unknown: &HashMap<String, Valu
 /// that tries to retrieve the config.
 ///
 /// Returns the config, or None if a command line flag that calls for an early exit (such as
-/// --print_config_path) was provided.
-pub fn initialize(args: Arc<Args>) -> Option<Config> {
+/// --print_config_path) was provided. Every tool's config section is parsed and validated (see
+/// [crate::tool_config_registry]), as is every invocation in the pipeline (see
+/// [crate::tool_registry]), and all the problems found are returned together.
+pub fn initialize(args: Arc<Args>) -> Result<Option<Config>, InitializeError> {
     let dirs = ProjectDirs::from("", "", "harvest").expect("no home directory");
     if args.print_config_path {
         println!("Config file location: {:?}", config_file(dirs.config_dir()));
-        return None;
+        return Ok(None);
     }
-    let config = load_config(&args, dirs.config_dir());
+    if args.print_config {
+        let settings = merge_config(&args, dirs.config_dir(), environment())?;
+        print!("{}", config_toml(&settings, &crate::tool_config_registry()));
+        return Ok(None);
+    }
+    let mut config = load_config(&args, dirs.config_dir(), environment())?;
     unknown_field_warning("", &config.unknown);
-    Some(config)
+    let mut errors = vec![];
+    match crate::tool_config_registry().load(&config.tools) {
+        Ok(tool_configs) => config.tool_configs = tool_configs,
+        Err(ToolConfigErrors(tool_errors)) => errors.extend(tool_errors),
+    }
+    if let Err(ToolConfigErrors(pipeline_errors)) = crate::tool_registry().pipeline(&config) {
+        errors.extend(pipeline_errors);
+    }
+    match errors.is_empty() {
+        true => Ok(Some(config)),
+        false => Err(ToolConfigErrors(errors).into()),
+    }
+}
+
+/// Error type returned by [initialize].
+#[derive(Debug, Error)]
+pub enum InitializeError {
+    #[error(transparent)]
+    ToolConfig(#[from] ToolConfigErrors),
+    #[error("{0}: {1}")]
+    SecretFile(String, String),
+    #[error("unknown config profile {0:?}")]
+    UnknownProfile(String),
+}
+
+/// Returns the source for config values set with environment variables. Variable names are the
+/// `HARVEST__` prefix followed by the config value's path, with `__` separating path segments. For
+/// example, `HARVEST__TOOLS__RAW_SOURCE_TO_CARGO_LLM__API_KEY` sets
+/// `tools.raw_source_to_cargo_llm.api_key`. Values are strings, which (like `--config` values)
+/// are converted to booleans or numbers only where the config expects one.
+fn environment() -> Environment {
+    Environment::with_prefix("HARVEST").separator("__")
+}
+
+/// Loads the config. From lowest to highest precedence, the layers are: the default config, the
+/// user's config file, `config.toml` in the working directory, the selected profile,
+/// `environment`, `--config` flags, and the other command-line arguments.
+fn load_config(
+    args: &Args,
+    config_dir: &Path,
+    environment: Environment,
+) -> Result<Config, InitializeError> {
+    let mut config: Config = merge_config(args, config_dir, environment)?
+        .try_deserialize()
+        .expect("config deserialization failed");
+    read_secret_files(&mut config.tools)?;
+    // The config crate does not support providing a Path in an override, so merge_config converts
+    // these paths to strings, which can be lossy. Correct them here.
+    if let Some(ref input) = args.input {
+        config.input = input.clone();
+    }
+    if let Some(ref output) = args.output {
+        config.output = output.clone();
+    }
+    if let Some(ref recover) = args.recover {
+        config.recover = Some(recover.clone());
+    }
+    Ok(config)
 }
 
-fn load_config(args: &Args, config_dir: &Path) -> Config {
+/// Merges the config layers (see [load_config]). Each value's origin (see
+/// [config::Value::origin]) names the layer it came from.
+fn merge_config(
+    args: &Args,
+    config_dir: &Path,
+    environment: Environment,
+) -> Result<config::Config, InitializeError> {
     let mut settings = config::Config::builder()
-        .add_source(config::File::from_str(
-            include_str!("../default_config.toml"),
-            Toml,
-        ))
-        .add_source(config::File::from(config_file(config_dir)).required(false))
-        .add_source(config::File::from(PathBuf::from("config.toml")).required(false));
+        .add_source(Labeled {
+            origin: "default config".into(),
+            source: config::File::from_str(include_str!("../default_config.toml"), Toml),
+        })
+        .add_source(Labeled {
+            origin: config_file(config_dir).display().to_string(),
+            source: config::File::from(config_file(config_dir)).required(false),
+        })
+        .add_source(Labeled {
+            origin: "./config.toml".into(),
+            source: config::File::from(PathBuf::from("config.toml")).required(false),
+        });
+    let labeled = |origin: &str, value: config::ValueKind| {
+        config::Value::new(Some(&origin.to_owned()), value)
+    };
     for config_arg in &args.config {
         let Some((name, value)) = config_arg.split_once('=') else {
             panic!("failed to parse config value {config_arg:?}; no '=' found");
         };
         settings = settings
-            .set_override(name, value)
+            .set_override(name, labeled("--config", value.into()))
             .expect("settings override failed");
     }
 
     if args.force {
         settings = settings
-            .set_override("force", "true")
+            .set_override("force", labeled("--force", true.into()))
             .expect("settings override failed");
     }
 
-    // We need to set an override so that deserializing the config does not error.
-    // However, the config crate does not support providing a Path in an override.
-    // We could convert to a string and back, but that can be lossy. Instead, this just sets a
-    // blank value and then corrects it after deserialization.
-    if args.input.is_some() {
-        settings = settings
-            .set_override("input", " ")
-            .expect("settings override failed");
+    for (name, path) in [
+        ("input", &args.input),
+        ("output", &args.output),
+        ("recover", &args.recover),
+    ] {
+        if let Some(path) = path {
+            let path = path.to_string_lossy().into_owned();
+            settings = settings
+                .set_override(name, labeled("command line", path.into()))
+                .expect("settings override failed");
+        }
     }
 
-    if args.output.is_some() {
-        settings = settings
-            .set_override("output", " ")
-            .expect("settings override failed");
+    // The selected profile goes between the config files and the overrides (which the config crate
+    // always applies last).
+    let profile = match &args.profile {
+        Some(profile) => Some(profile.clone()),
+        None => settings
+            .clone()
+            .add_source(environment.clone())
+            .build()
+            .expect("failed to build settings")
+            .get_string("default_profile")
+            .ok(),
+    };
+    if let Some(profile) = profile {
+        let values = settings
+            .build_cloned()
+            .expect("failed to build settings")
+            .get_table(&format!("profile.{profile}"))
+            .map_err(|_| InitializeError::UnknownProfile(profile.clone()))?;
+        settings = settings.add_source(Labeled {
+            origin: format!("profile {profile}"),
+            source: ProfileSource(values),
+        });
     }
+    settings = settings.add_source(Labeled {
+        origin: "environment".into(),
+        source: environment,
+    });
+    Ok(settings.build().expect("failed to build settings"))
+}
 
-    let mut config: Config = settings
-        .build()
-        .expect("failed to build settings")
-        .try_deserialize()
-        .expect("config deserialization failed");
-    if let Some(ref input) = args.input {
-        config.input = input.clone();
+/// Replaces each `<name>_file` value in the tool config sections with a `<name>` value containing
+/// the contents of the named file (without trailing newlines). This lets secrets such as API keys
+/// be kept out of config files, environment variables, and command lines.
+fn read_secret_files(tools: &mut HashMap<String, Value>) -> Result<(), InitializeError> {
+    for (tool, section) in tools {
+        let Value::Object(section) = section else {
+            continue;
+        };
+        let file_keys: Vec<_> = section
+            .keys()
+            .filter(|key| key.ends_with("_file"))
+            .cloned()
+            .collect();
+        for file_key in file_keys {
+            let key = file_key.strip_suffix("_file").unwrap_or_default();
+            let path = format!("tools.{tool}.{file_key}");
+            let Some(Value::String(file)) = section.remove(&file_key) else {
+                return Err(InitializeError::SecretFile(path, "not a string".into()));
+            };
+            if section.contains_key(key) {
+                return Err(InitializeError::SecretFile(
+                    path,
+                    format!("{key} is also set"),
+                ));
+            }
+            let contents = read_to_string(&file)
+                .map_err(|error| InitializeError::SecretFile(path, format!("{file}: {error}")))?;
+            let contents = contents.trim_end_matches(['\n', '\r']);
+            section.insert(key.into(), contents.into());
+        }
     }
-    if let Some(ref output) = args.output {
-        config.output = output.clone();
+    Ok(())
+}
+
+/// A config source containing the values from a `[profile.<name>]` table.
+#[derive(Clone, Debug)]
+struct ProfileSource(Map<String, config::Value>);
+
+impl Source for ProfileSource {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, config::Value>, ConfigError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// A config source that sets the origin of each of `source`'s values to `origin`.
+#[derive(Clone, Debug)]
+struct Labeled<S> {
+    origin: String,
+    source: S,
+}
+
+impl<S: Clone + Source + Send + Sync + 'static> Source for Labeled<S> {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, config::Value>, ConfigError> {
+        fn label(value: config::Value, origin: &String) -> config::Value {
+            let kind = match value.kind {
+                ValueKind::Table(table) => ValueKind::Table(
+                    table
+                        .into_iter()
+                        .map(|(key, value)| (key, label(value, origin)))
+                        .collect(),
+                ),
+                ValueKind::Array(array) => {
+                    ValueKind::Array(array.into_iter().map(|v| label(v, origin)).collect())
+                }
+                kind => kind,
+            };
+            config::Value::new(Some(origin), kind)
+        }
+        let values = self.source.collect()?.into_iter();
+        Ok(values
+            .map(|(key, value)| (key, label(value, &self.origin)))
+            .collect())
+    }
+}
+
+/// Formats the merged config `settings` as TOML, with a comment after each value naming the layer
+/// it came from. Secret tool config values (according to `registry`) are redacted.
+fn config_toml(settings: &config::Config, registry: &ToolConfigRegistry) -> String {
+    let mut out = String::from("# Each value is followed by the config layer that set it.\n");
+    let table = settings.collect().expect("failed to collect settings");
+    write_table(&mut out, &mut vec![], &table, registry);
+    out
+}
+
+/// Writes `table`, which is at `path`, and its subtables to `out`. See [config_toml].
+fn write_table<'t>(
+    out: &mut String,
+    path: &mut Vec<&'t str>,
+    table: &'t Map<String, config::Value>,
+    registry: &ToolConfigRegistry,
+) {
+    let mut entries: Vec<_> = table.iter().collect();
+    entries.sort_unstable_by_key(|&(key, _)| key);
+    let is_table = |value: &config::Value| matches!(value.kind, ValueKind::Table(_));
+    let values: Vec<_> = entries
+        .iter()
+        .filter(|(_, value)| !is_table(value) && !matches!(value.kind, ValueKind::Nil))
+        .collect();
+    if !path.is_empty() && (!values.is_empty() || entries.is_empty()) {
+        let header: Vec<_> = path.iter().map(|key| toml_key(key)).collect();
+        _ = writeln!(out, "\n[{}]", header.join("."));
+    }
+    for &&(key, value) in &values {
+        path.push(key);
+        // Tool config sections are at tools.<tool>, including inside profiles.
+        let secret = path
+            .windows(3)
+            .any(|w| w[0] == "tools" && registry.is_secret(w[1], w[2]));
+        let text = match (secret, key.as_str()) {
+            (true, _) => "\"********\"".into(),
+            // Pipeline entries can override tool config values, including secrets.
+            (false, "pipeline") => toml_value(&redact_pipeline(value, registry)),
+            (false, _) => toml_value(value),
+        };
+        let origin = value.origin().unwrap_or("unknown");
+        _ = writeln!(out, "{} = {text}  # {origin}", toml_key(key));
+        path.pop();
+    }
+    for (key, value) in entries {
+        if let ValueKind::Table(table) = &value.kind {
+            path.push(key);
+            write_table(out, path, table, registry);
+            path.pop();
+        }
+    }
+}
+
+/// Returns `pipeline` with the secret arguments (see [ToolConfigRegistry::is_secret]) of each
+/// invocation redacted.
+fn redact_pipeline(pipeline: &config::Value, registry: &ToolConfigRegistry) -> config::Value {
+    let mut pipeline = pipeline.clone();
+    let ValueKind::Array(invocations) = &mut pipeline.kind else {
+        return pipeline;
+    };
+    for invocation in invocations {
+        let ValueKind::Table(args) = &mut invocation.kind else {
+            continue;
+        };
+        let tool = args.get("tool").map(|tool| tool.to_string());
+        for (arg, value) in args.iter_mut() {
+            if let Some(tool) = &tool
+                && registry.is_secret(tool, arg)
+            {
+                *value = config::Value::new(None, ValueKind::String("********".into()));
+            }
+        }
+    }
+    pipeline
+}
+
+/// Formats `key` as a TOML key, quoting it if necessary.
+fn toml_key(key: &str) -> String {
+    let bare = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    match !key.is_empty() && key.chars().all(bare) {
+        true => key.into(),
+        false => toml_string(key),
+    }
+}
+
+/// Formats `value` as a TOML string. JSON string escapes are valid in TOML basic strings.
+fn toml_string(value: &str) -> String {
+    serde_json::to_string(value).expect("failed to format string")
+}
+
+/// Formats `value` as an inline TOML value.
+fn toml_value(value: &config::Value) -> String {
+    match &value.kind {
+        // TOML has no null; this is only reachable inside arrays.
+        ValueKind::Nil => "\"\"".into(),
+        ValueKind::Boolean(value) => value.to_string(),
+        ValueKind::I64(value) => value.to_string(),
+        ValueKind::I128(value) => value.to_string(),
+        ValueKind::U64(value) => value.to_string(),
+        ValueKind::U128(value) => value.to_string(),
+        ValueKind::Float(value) if value.is_nan() => "nan".into(),
+        ValueKind::Float(value) => format!("{value:?}"),
+        ValueKind::String(value) => toml_string(value),
+        ValueKind::Array(values) => {
+            let values: Vec<_> = values.iter().map(toml_value).collect();
+            format!("[{}]", values.join(", "))
+        }
+        ValueKind::Table(table) => {
+            let mut entries: Vec<_> = table.iter().collect();
+            entries.sort_unstable_by_key(|&(key, _)| key);
+            let entries: Vec<_> = entries
+                .into_iter()
+                .map(|(key, value)| format!("{} = {}", toml_key(key), toml_value(value)))
+                .collect();
+            format!("{{ {} }}", entries.join(", "))
+        }
     }
-    config
 }
 
 /// Returns the config file path, given the config directory.
@@ -123,6 +435,22 @@ fn config_file(config_dir: &Path) -> PathBuf {
 
 #[cfg(test)]
 mod tests {
+    /// Verifies that a tool's built-in config defaults (used when its section is missing) match
+    /// the default config file.
+    #[cfg(not(miri))]
+    #[test]
+    fn default_tool_configs() {
+        use super::*;
+        use harvest_core::test_util::tempdir;
+        let config_dir = tempdir().unwrap();
+        let args = Args::parse_from(["", "a", "--output=/tmp/out"]);
+        let config = load_config(&args, config_dir.path(), environment()).unwrap();
+        let configs = crate::tool_config_registry().load(&config.tools).unwrap();
+        let loaded = configs.get::<load_raw_source::Config>("load_raw_source");
+        let default = load_raw_source::Config::default();
+        assert_eq!(format!("{:?}", loaded.unwrap()), format!("{default:?}"));
+    }
+
     #[cfg(not(miri))]
     #[test]
     fn load_config_test() {
@@ -135,7 +463,9 @@ mod tests {
             load_config(
                 &Args::parse_from(["", "a", "--output=/tmp/out"]),
                 config_dir.path(),
+                environment(),
             )
+            .unwrap()
             .input,
             AsRef::<Path>::as_ref("a")
         );
@@ -154,8 +484,10 @@ mod tests {
         assert_eq!(
             load_config(
                 &Args::parse_from(["", "--output=/tmp/out"]),
-                config_dir.path()
+                config_dir.path(),
+                environment(),
             )
+            .unwrap()
             .input,
             AsRef::<Path>::as_ref("b")
         );
@@ -163,8 +495,10 @@ mod tests {
         assert_eq!(
             load_config(
                 &Args::parse_from(["", "--config", "input=c", "--output=/tmp/out"]),
-                config_dir.path()
+                config_dir.path(),
+                environment(),
             )
+            .unwrap()
             .input,
             AsRef::<Path>::as_ref("c")
         );
@@ -172,8 +506,10 @@ mod tests {
         assert_eq!(
             load_config(
                 &Args::parse_from(["", "--config", "input=d", "d", "--output=/tmp/out"]),
-                config_dir.path()
+                config_dir.path(),
+                environment(),
             )
+            .unwrap()
             .input,
             AsRef::<Path>::as_ref("d")
         );
@@ -181,9 +517,271 @@ mod tests {
         assert!(
             load_config(
                 &Args::parse_from(["", "--force", "--output=/tmp/out"]),
-                config_dir.path()
+                config_dir.path(),
+                environment(),
             )
+            .unwrap()
             .force
         );
+
+        // The default and user configs contain valid tool configs.
+        let config = load_config(
+            &Args::parse_from(["", "--output=/tmp/out"]),
+            config_dir.path(),
+            environment(),
+        )
+        .unwrap();
+        assert!(crate::tool_config_registry().load(&config.tools).is_ok());
+        assert_eq!(crate::tool_registry().pipeline(&config).unwrap().len(), 4);
+        // All invalid tool config values are reported.
+        let config = load_config(
+            &Args::parse_from([
+                "",
+                "--config",
+                "tools.raw_source_to_cargo_llm.backend=nope",
+                "--config",
+                "tools.raw_source_to_cargo_llm.model=",
+                "--output=/tmp/out",
+            ]),
+            config_dir.path(),
+            environment(),
+        )
+        .unwrap();
+        let errors = crate::tool_config_registry().load(&config.tools);
+        assert_eq!(errors.unwrap_err().0.len(), 2);
+    }
+
+    #[cfg(not(miri))]
+    #[test]
+    fn pipeline() {
+        use super::*;
+        use harvest_core::test_util::tempdir;
+        use std::fs;
+        let config_dir = tempdir().unwrap();
+        fs::write(
+            config_file(config_dir.path()),
+            r#"
+                [[pipeline]]
+                tool = "load_raw_source"
+                [[pipeline]]
+                tool = "raw_source_to_cargo_llm"
+                model = "codellama:13b"
+                [[pipeline]]
+                tool = "raw_source_to_cargo_llm"
+                model = "qwen2.5-coder:7b"
+                max_tokens = 2000
+            "#,
+        )
+        .unwrap();
+        let args = Args::parse_from(["", "a", "--output=/tmp/out"]);
+        let config = load_config(&args, config_dir.path(), environment());
+        let config = config.unwrap();
+        assert_eq!(config.pipeline[2].args["max_tokens"], 2000);
+        let pipeline = crate::tool_registry().pipeline(&config).unwrap();
+        let names: Vec<_> = pipeline.iter().map(|tool| tool.name()).collect();
+        assert_eq!(
+            names,
+            [
+                "load_raw_source",
+                "raw_source_to_cargo_llm",
+                "raw_source_to_cargo_llm"
+            ]
+        );
+
+        // Unknown tools and invalid arguments are all reported.
+        fs::write(
+            config_file(config_dir.path()),
+            r#"
+                [[pipeline]]
+                tool = "load_raw_sauce"
+                [[pipeline]]
+                tool = "try_cargo_build"
+                release = false
+                [[pipeline]]
+                tool = "raw_source_to_cargo_llm"
+                max_tokens = 0
+            "#,
+        )
+        .unwrap();
+        let args = Args::parse_from(["", "a", "--output=/tmp/out"]);
+        let config = load_config(&args, config_dir.path(), environment());
+        let errors = crate::tool_registry().pipeline(&config.unwrap());
+        assert_eq!(
+            errors.err().unwrap().0,
+            [
+                "pipeline[0]: unknown tool \"load_raw_sauce\"",
+                "pipeline[1] (try_cargo_build): unknown argument \"release\"",
+                "pipeline[2] (raw_source_to_cargo_llm): max_tokens must be positive",
+            ]
+        );
+    }
+
+    #[cfg(not(miri))]
+    #[test]
+    fn profiles() {
+        use super::*;
+        use harvest_core::test_util::tempdir;
+        use std::fs;
+        let config_dir = tempdir().unwrap();
+        fs::write(
+            config_file(config_dir.path()),
+            r#"
+                default_profile = "local"
+                [profile.local.tools.raw_source_to_cargo_llm]
+                model = "codellama:13b"
+                [profile.openai]
+                save_ir = true
+                [profile.openai.tools.raw_source_to_cargo_llm]
+                backend = "openai"
+                model = "gpt-4o"
+            "#,
+        )
+        .unwrap();
+        let load = |args: &[&str]| {
+            let args = [&["", "a", "--output=/tmp/out"], args].concat();
+            load_config(&Args::parse_from(args), config_dir.path(), environment())
+        };
+        let llm_config = |config: &Config| config.tools["raw_source_to_cargo_llm"].clone();
+
+        let config = load(&[]).unwrap();
+        assert_eq!(llm_config(&config)["model"], "codellama:13b");
+        assert_eq!(llm_config(&config)["backend"], "ollama");
+        let config = load(&["--profile=openai"]).unwrap();
+        assert!(config.save_ir);
+        assert_eq!(llm_config(&config)["model"], "gpt-4o");
+        assert_eq!(llm_config(&config)["backend"], "openai");
+        // Values from the default config that the profile does not set are kept.
+        assert_eq!(llm_config(&config)["max_tokens"], 10000);
+        // --config takes precedence over the profile.
+        let args = [
+            "--profile=openai",
+            "--config=tools.raw_source_to_cargo_llm.model=o3",
+        ];
+        assert_eq!(llm_config(&load(&args).unwrap())["model"], "o3");
+        assert!(matches!(
+            load(&["--profile=missing"]),
+            Err(InitializeError::UnknownProfile(profile)) if profile == "missing"
+        ));
+    }
+
+    #[cfg(not(miri))]
+    #[test]
+    fn environment_and_secret_files() {
+        use super::*;
+        use harvest_core::test_util::tempdir;
+        use std::fs;
+        let config_dir = tempdir().unwrap();
+        fs::write(
+            config_file(config_dir.path()),
+            "[tools.raw_source_to_cargo_llm]\nmodel = \"file\"\n",
+        )
+        .unwrap();
+        let secret = config_dir.path().join("secret");
+        fs::write(&secret, "sk-secret\n").unwrap();
+        let load = |args: &[&str], vars: &[(&str, &str)]| {
+            let args = [&["", "a", "--output=/tmp/out"], args].concat();
+            let vars = vars.iter().map(|&(k, v)| (k.into(), v.into()));
+            let environment = environment().source(Some(Map::from_iter(vars)));
+            load_config(&Args::parse_from(args), config_dir.path(), environment)
+        };
+        let llm_config = |config: &Config| config.tools["raw_source_to_cargo_llm"].clone();
+
+        let vars = [
+            ("HARVEST__SAVE_IR", "true"),
+            ("HARVEST__TOOLS__RAW_SOURCE_TO_CARGO_LLM__MODEL", "env"),
+            ("HARVEST__TOOLS__RAW_SOURCE_TO_CARGO_LLM__MAX_TOKENS", "42"),
+            (
+                "HARVEST__TOOLS__RAW_SOURCE_TO_CARGO_LLM__API_KEY_FILE",
+                secret.to_str().unwrap(),
+            ),
+            ("OTHER__SAVE_IR", "false"),
+        ];
+        let config = load(&[], &vars).unwrap();
+        assert!(config.save_ir);
+        // The environment overrides config files. Values are parsed where a tool's config expects
+        // a number, but otherwise left as strings.
+        assert_eq!(llm_config(&config)["model"], "env");
+        assert_eq!(llm_config(&config)["max_tokens"], "42");
+        assert_eq!(llm_config(&config)["api_key"], "sk-secret");
+        assert!(llm_config(&config).get("api_key_file").is_none());
+        let tool_config = |config: &Config| {
+            let configs = crate::tool_config_registry().load(&config.tools).unwrap();
+            configs.get::<raw_source_to_cargo_llm::Config>("raw_source_to_cargo_llm")
+        };
+        assert_eq!(tool_config(&config).unwrap().max_tokens, 42);
+        let vars = [
+            ("HARVEST__TOOLS__RAW_SOURCE_TO_CARGO_LLM__MODEL", "12345"),
+            vars[3],
+        ];
+        assert_eq!(
+            tool_config(&load(&[], &vars).unwrap()).unwrap().model,
+            "12345"
+        );
+        // --config overrides the environment.
+        let args = ["--config=tools.raw_source_to_cargo_llm.model=flag"];
+        assert_eq!(llm_config(&load(&args, &vars).unwrap())["model"], "flag");
+
+        let args = ["--config=tools.raw_source_to_cargo_llm.api_key=sk-flag"];
+        let result = load(&args, &vars);
+        assert!(matches!(result, Err(InitializeError::SecretFile(..))));
+        let vars = [("HARVEST__TOOLS__X__TOKEN_FILE", "/nonexistent")];
+        let result = load(&[], &vars);
+        assert!(matches!(result, Err(InitializeError::SecretFile(..))));
+    }
+
+    #[cfg(not(miri))]
+    #[test]
+    fn print_config() {
+        use super::*;
+        use harvest_core::test_util::tempdir;
+        use std::fs;
+        let config_dir = tempdir().unwrap();
+        let user_config = config_file(config_dir.path());
+        fs::write(
+            &user_config,
+            r#"
+                save_ir = true
+                [[pipeline]]
+                tool = "raw_source_to_cargo_llm"
+                api_key = "sk-pipeline"
+                [profile.openai.tools.raw_source_to_cargo_llm]
+                api_key = "sk-profile"
+                model = "gpt-4o"
+            "#,
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "",
+            "--profile=openai",
+            "--config=tools.raw_source_to_cargo_llm.max_tokens=5",
+            "--force",
+            "a",
+        ]);
+        let vars = [("HARVEST__LOG_FILTER".into(), "debug".into())];
+        let environment = environment().source(Some(Map::from_iter(vars)));
+        let settings = merge_config(&args, config_dir.path(), environment).unwrap();
+        let toml = config_toml(&settings, &crate::tool_config_registry());
+        let user_config = user_config.display();
+        for line in [
+            "fail_on_invariant_violation = false  # default config",
+            "force = true  # --force",
+            "input = \"a\"  # command line",
+            "log_filter = \"debug\"  # environment",
+            &format!("save_ir = true  # {user_config}"),
+            "[tools.raw_source_to_cargo_llm]",
+            "api_key = \"********\"  # profile openai",
+            "max_tokens = \"5\"  # --config",
+            "model = \"gpt-4o\"  # profile openai",
+            "[profile.openai.tools.raw_source_to_cargo_llm]",
+            &format!("api_key = \"********\"  # {user_config}"),
+            &format!(
+                "pipeline = [{{ api_key = \"********\", tool = \"raw_source_to_cargo_llm\" }}]  \
+                 # {user_config}"
+            ),
+        ] {
+            assert!(toml.lines().any(|l| l == line), "{line:?} not in:\n{toml}");
+        }
+        assert!(!toml.contains("sk-profile"));
+        assert!(!toml.contains("sk-pipeline"));
     }
 }
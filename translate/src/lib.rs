@@ -6,34 +6,125 @@ mod runner;
 mod scheduler;
 pub mod util;
 
-use harvest_core::config::Config;
-use harvest_core::edit::{self, NewEditError};
-use harvest_core::tools::{MightWriteContext, MightWriteOutcome};
+use full_source::{CargoPackage, RawSource};
+use harvest_core::config::{Config, ToolConfigRegistry};
+use harvest_core::edit::{self, LockKind, NewEditError};
+use harvest_core::invariants::Invariants;
+use harvest_core::ir::Registry;
+use harvest_core::tools::{MightWriteContext, MightWriteOutcome, Tool, ToolRegistry, no_arguments};
 use harvest_core::{HarvestIR, diagnostics};
-use identify_project_kind::IdentifyProjectKind;
+use identify_project_kind::{IdentifyProjectKind, ProjectKind};
 use load_raw_source::LoadRawSource;
 use raw_source_to_cargo_llm::RawSourceToCargoLlm;
 use runner::{SpawnToolError, ToolRunner};
 use scheduler::{NextInvocationOutcome, Scheduler};
 use std::sync::Arc;
 use tracing::{debug, error, info};
-use try_cargo_build::TryCargoBuild;
+use try_cargo_build::{CargoBuildResult, TryCargoBuild};
+
+/// Returns a [Registry] containing every serializable representation produced by
+/// harvest_translate's tools. Used to load saved IRs.
+pub fn representation_registry() -> Registry {
+    let mut registry = Registry::default();
+    registry.register::<CargoBuildResult>("CargoBuildResult");
+    registry.register::<CargoPackage>("CargoPackage");
+    registry.register::<ProjectKind>("KindAndName");
+    registry.register::<RawSource>("RawSource");
+    registry
+}
+
+/// Returns a [ToolConfigRegistry] containing the config type of every configurable tool. Used by
+/// [cli::initialize] to parse and validate the `tools` config sections.
+pub fn tool_config_registry() -> ToolConfigRegistry {
+    let mut registry = ToolConfigRegistry::default();
+    registry.register::<load_raw_source::Config>("load_raw_source");
+    registry.register::<raw_source_to_cargo_llm::Config>("raw_source_to_cargo_llm");
+    registry
+}
+
+/// Returns a [ToolRegistry] containing the constructor of every tool. Used to create the tool
+/// invocations listed in the `pipeline` config.
+pub fn tool_registry() -> ToolRegistry {
+    let mut registry = ToolRegistry::default();
+    registry.register("identify_project_kind", |_, args| {
+        no_arguments(args)?;
+        Ok(Box::new(IdentifyProjectKind))
+    });
+    registry.register("load_raw_source", LoadRawSource::construct);
+    registry.register("raw_source_to_cargo_llm", RawSourceToCargoLlm::construct);
+    registry.register("try_cargo_build", |_, args| {
+        no_arguments(args)?;
+        Ok(Box::new(TryCargoBuild))
+    });
+    registry
+}
+
+/// Returns the IR invariants that are checked after every applied edit.
+pub fn invariants() -> Invariants {
+    let mut invariants = Invariants::default();
+    invariants.add("single_project_kind", |ir| {
+        match ir.count::<ProjectKind>() {
+            0 | 1 => vec![],
+            n => vec![format!(
+                "found {n} ProjectKind representations, expected at most 1"
+            )],
+        }
+    });
+    invariants.add("build_result_package_exists", |ir| {
+        ir.get_by_representation::<CargoBuildResult>()
+            .filter(|(_, r)| ir.typed_id::<CargoPackage>(r.package).is_none())
+            .map(|(id, r)| format!("{id} refers to missing CargoPackage {}", r.package))
+            .collect()
+    });
+    invariants
+}
 
 /// Performs the complete transpilation process using the scheduler.
 pub fn transpile(config: Arc<Config>) -> Result<Arc<HarvestIR>, Box<dyn std::error::Error>> {
+    let pipeline = tool_registry().pipeline(&config)?;
+    run_pipeline(config, pipeline)
+}
+
+/// Runs the tool invocations in `pipeline` until none of them can make progress.
+///
+/// Invocations of the same tool run one at a time, in pipeline order, so that each one sees the
+/// results of the previous ones (e.g. a second `raw_source_to_cargo_llm` invocation replaces the
+/// package written by the first, rather than adding another).
+fn run_pipeline(
+    config: Arc<Config>,
+    pipeline: Vec<Box<dyn Tool>>,
+) -> Result<Arc<HarvestIR>, Box<dyn std::error::Error>> {
     let collector = diagnostics::Collector::initialize(&config)?;
-    let mut ir_organizer = edit::Organizer::default();
+    let mut ir_organizer = match (&config.recover, &config.resume) {
+        (Some(journal), _) => {
+            info!("Recovering from journal at {}", journal.display());
+            edit::Organizer::recover(journal, &representation_registry())?
+        }
+        (None, Some(path)) => {
+            info!("Resuming from saved IR at {}", path.display());
+            edit::Organizer::with_harvest_ir(HarvestIR::load(path, &representation_registry())?)
+        }
+        (None, None) => edit::Organizer::default(),
+    };
+    if let (None, Some(journal)) = (&config.recover, &config.journal) {
+        ir_organizer.start_journal(journal)?;
+    }
     let mut runner = ToolRunner::new(collector.reporter());
+    runner.set_invariants(invariants());
     let mut scheduler = Scheduler::default();
-    scheduler.queue_invocation(LoadRawSource::new(&config.input));
-    scheduler.queue_invocation(IdentifyProjectKind);
-    scheduler.queue_invocation(TryCargoBuild);
-    scheduler.queue_invocation(RawSourceToCargoLlm);
+    pipeline
+        .into_iter()
+        .for_each(|tool| scheduler.queue_invocation(tool));
+    let mut result = Ok(());
     loop {
         let snapshot = ir_organizer.snapshot();
-        scheduler.next_invocations(|mut tool| {
+        let scheduled = scheduler.next_invocations(|mut tool| {
             use NextInvocationOutcome::{DontTryAgain, Error, TryLater};
             let name = tool.name();
+            if runner.is_running(name) {
+                debug!("Not invoking {name} because another invocation of it is running");
+                return TryLater(tool);
+            }
             let might_write = match tool.might_write(MightWriteContext::new(&snapshot)) {
                 MightWriteOutcome::NotRunnable => {
                     debug!("Tool {name} is not runnable");
@@ -59,8 +150,15 @@ pub fn transpile(config: Arc<Config>) -> Result<Arc<HarvestIR>, Box<dyn std::err
                     error!("I/O error spawning tool: {error}");
                     Error(SpawnToolError::IoError(error).into())
                 }
-                Err((SpawnToolError::NewEdit(NewEditError::IdInUse), tool)) => {
-                    debug!("Not spawning {name} because an ID it needs is in use.");
+                Err((SpawnToolError::NewEdit(NewEditError::IdInUse(LockKind::Write)), tool)) => {
+                    // The running tool might change this tool's inputs, so this tool's might_write
+                    // will be re-evaluated against the new IR before it is tried again.
+                    debug!("Not spawning {name} because an ID it needs is being written.");
+                    TryLater(tool)
+                }
+                Err((SpawnToolError::NewEdit(NewEditError::IdInUse(LockKind::Read)), tool)) => {
+                    // The IDs will not change, so this tool can run as soon as the readers finish.
+                    debug!("Not spawning {name} because an ID it writes is being read.");
                     TryLater(tool)
                 }
                 Err((SpawnToolError::NewEdit(NewEditError::UnknownId), _)) => {
@@ -72,16 +170,257 @@ pub fn transpile(config: Arc<Config>) -> Result<Arc<HarvestIR>, Box<dyn std::err
                     DontTryAgain
                 }
             }
-        })?;
-        if !runner.process_tool_results(&mut ir_organizer) {
+        });
+        if let Err(error) = scheduled {
+            result = Err(error);
+            break;
+        }
+        let tools_running = runner.process_tool_results(&mut ir_organizer);
+        runner
+            .take_retries()
+            .into_iter()
+            .for_each(|tool| scheduler.queue_invocation(tool));
+        if config.fail_on_invariant_violation && runner.invariants_violated() {
+            result = Err("IR invariant violated (see the diagnostics for details)".into());
+            break;
+        }
+        if !tools_running {
             // No tools are running now, which also indicates that no tools are schedulable.
             // Eventually we need some way to determine whether this is a successful outcome or a
             // failure, but for now we can just assume success.
             break;
         }
     }
+    // If translation stopped early, tools may still be running. Their edits are discarded, but they
+    // must finish before the diagnostics are.
+    runner.join_all();
     drop(scheduler);
     drop(runner);
     collector.diagnostics(); // TODO: Return this value (see issue 51)
-    Ok(ir_organizer.snapshot())
+    result.map(|()| ir_organizer.snapshot())
+}
+
+#[cfg(all(test, not(miri)))]
+mod tests {
+    use super::*;
+    use harvest_core::Id;
+    use harvest_core::fs::RawDir;
+    use harvest_core::test_util::{MockTool, tempdir};
+    use raw_source_to_cargo_llm::replace_package;
+    use std::fs::{create_dir, write};
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    /// Verifies every representation in `representation_registry` round-trips through
+    /// `HarvestIR::save` and `HarvestIR::load`.
+    #[test]
+    fn save_load_round_trip() {
+        let mut dir = RawDir::default();
+        dir.set_file("src/main.c", b"int main() {}".into()).unwrap();
+        let mut ir = HarvestIR::default();
+        ir.add_representation(Box::new(RawSource { dir }));
+        let mut dir = RawDir::default();
+        dir.set_file("Cargo.toml", b"[package]".into()).unwrap();
+        let package = ir.add_representation(Box::new(CargoPackage { dir })).id();
+        ir.add_representation(Box::new(CargoPackage {
+            dir: RawDir::default(),
+        }));
+        ir.add_representation(Box::new(ProjectKind::Executable));
+        ir.add_representation(Box::new(CargoBuildResult {
+            package,
+            result: Ok(vec![PathBuf::from("target/release/main")]),
+        }));
+        ir.add_representation(Box::new(CargoBuildResult {
+            package,
+            result: Err("error[E0425]".into()),
+        }));
+        let tempdir = tempdir().unwrap();
+        let path = PathBuf::from_iter([tempdir.path(), "ir".as_ref()]);
+        ir.save(&path).unwrap();
+        let loaded = HarvestIR::load(&path, &representation_registry()).unwrap();
+        assert_eq!(loaded.to_string(), ir.to_string());
+    }
+
+    #[test]
+    fn invariants() {
+        let invariants = super::invariants();
+        let mut ir = HarvestIR::default();
+        let package = ir
+            .add_representation(Box::new(CargoPackage {
+                dir: RawDir::default(),
+            }))
+            .id();
+        ir.add_representation(Box::new(ProjectKind::Library));
+        ir.add_representation(Box::new(CargoBuildResult {
+            package,
+            result: Err("error".into()),
+        }));
+        assert_eq!(invariants.check(&ir), []);
+        ir.add_representation(Box::new(ProjectKind::Executable));
+        let result = ir.add_representation(Box::new(CargoBuildResult {
+            package: Id::new(),
+            result: Err("error".into()),
+        }));
+        let violated: Vec<_> = invariants.check(&ir).iter().map(|v| v.invariant).collect();
+        assert_eq!(
+            violated,
+            ["single_project_kind", "build_result_package_exists"]
+        );
+        assert!(
+            invariants.check(&ir)[1]
+                .message
+                .starts_with(&result.to_string())
+        );
+    }
+
+    /// Verifies that translation stops when an invariant is violated, after waiting for the tools
+    /// that are still running.
+    #[test]
+    fn invariant_violation() {
+        let tempdir = tempdir().unwrap();
+        let mut config = Config::mock();
+        config.output = tempdir.path().join("output");
+        config.fail_on_invariant_violation = true;
+        let finished = Arc::new(AtomicBool::new(false));
+        let slow_finished = finished.clone();
+        let slow = MockTool::new().name("slow").run(move |_| {
+            sleep(Duration::from_millis(100));
+            slow_finished.store(true, Ordering::Release);
+            Ok(())
+        });
+        let violate = MockTool::new().name("violate").run(|context| {
+            context
+                .ir_edit
+                .add_representation(Box::new(ProjectKind::Library));
+            context
+                .ir_edit
+                .add_representation(Box::new(ProjectKind::Executable));
+            Ok(())
+        });
+        let Err(error) = run_pipeline(config.into(), vec![slow.boxed(), violate.boxed()]) else {
+            panic!("invariant violation did not stop translation");
+        };
+        assert!(error.to_string().contains("invariant violated"), "{error}");
+        assert!(
+            finished.load(Ordering::Acquire),
+            "running tool was not joined"
+        );
+    }
+
+    /// Runs a pipeline with two LLM translations followed by a build, and verifies that the second
+    /// translation replaces the first and is the one that gets built. The LLM invocations are
+    /// mocked (as they need an LLM server), but decide when to run the same way the real tool does.
+    #[test]
+    fn two_translations() {
+        let tempdir = tempdir().unwrap();
+        let mut config = Config::mock();
+        config.input = tempdir.path().join("input");
+        config.output = tempdir.path().join("output");
+        config.fail_on_invariant_violation = true;
+        create_dir(&config.input).unwrap();
+        write(config.input.join("CMakeLists.txt"), "add_executable(a a.c)").unwrap();
+        write(config.input.join("a.c"), "int main() {}").unwrap();
+        let packages = Arc::new(Mutex::new(vec![]));
+        let llm = |main: &'static str| {
+            let mut real = RawSourceToCargoLlm::default();
+            let packages = packages.clone();
+            MockTool::new()
+                .name("raw_source_to_cargo_llm")
+                .might_write(move |context| real.might_write(context))
+                .run(move |context| {
+                    let mut dir = RawDir::default();
+                    let manifest = "[package]\nname = \"a\"\nedition = \"2024\"\n";
+                    dir.set_file("Cargo.toml", manifest.into())?;
+                    dir.set_file("src/main.rs", main.into())?;
+                    let package = CargoPackage { dir };
+                    let id = replace_package(context.ir_edit, &context.ir_snapshot, package)?;
+                    packages.lock().unwrap().push(id);
+                    Ok(())
+                })
+                .boxed()
+        };
+        let pipeline: Vec<Box<dyn Tool>> = vec![
+            Box::new(LoadRawSource::new(&config.input)),
+            Box::new(IdentifyProjectKind),
+            llm("fn main() {}"),
+            llm("fn main() { println!(\"b\"); }"),
+            Box::new(TryCargoBuild),
+        ];
+        let ir = run_pipeline(config.into(), pipeline).unwrap();
+        let &[first, second] = packages.lock().unwrap().as_slice() else {
+            panic!("expected two translations");
+        };
+        let (package, CargoPackage { dir }) = ir.get_one::<CargoPackage>().unwrap();
+        assert_eq!(package, second);
+        assert!(
+            dir.get_file("src/main.rs")
+                .unwrap()
+                .starts_with(b"fn main() { println!")
+        );
+        assert_eq!(
+            ir.superseded_by(first.into()).map(|(new, _)| new),
+            Some(second.into())
+        );
+        let (_, build) = ir.get_one::<CargoBuildResult>().unwrap();
+        assert_eq!(build.package, second.into());
+        assert!(build.result.is_ok(), "{:?}", build.result);
+    }
+    /// Verifies that after resuming from an IR that contains a package, only the LLM invocations
+    /// configured with `replace_resumed_package` run.
+    #[test]
+    fn resumed_translation() {
+        let tempdir = tempdir().unwrap();
+        let mut config = Config::mock();
+        config.output = tempdir.path().join("output");
+        let section = serde_json::json!({"backend": "ollama", "model": "m", "max_tokens": 10});
+        config
+            .tools
+            .insert("raw_source_to_cargo_llm".into(), section);
+        let mut ir = HarvestIR::default();
+        ir.add_representation(Box::new(RawSource {
+            dir: RawDir::default(),
+        }));
+        ir.add_representation(Box::new(ProjectKind::Executable));
+        ir.add_representation(Box::new(CargoPackage {
+            dir: RawDir::default(),
+        }));
+        let saved = tempdir.path().join("saved");
+        ir.save(&saved).unwrap();
+        config.resume = Some(saved);
+
+        let ran = Arc::new(Mutex::new(vec![]));
+        let llm = |name: &'static str, args: serde_json::Value| {
+            let serde_json::Value::Object(args) = args else {
+                unreachable!()
+            };
+            let mut real = RawSourceToCargoLlm::construct(&config, &args).unwrap();
+            let ran = ran.clone();
+            MockTool::new()
+                .name("raw_source_to_cargo_llm")
+                .might_write(move |context| real.might_write(context))
+                .run(move |context| {
+                    let package = CargoPackage {
+                        dir: RawDir::default(),
+                    };
+                    replace_package(context.ir_edit, &context.ir_snapshot, package)?;
+                    ran.lock().unwrap().push(name);
+                    Ok(())
+                })
+                .boxed()
+        };
+        let pipeline: Vec<Box<dyn Tool>> = vec![
+            llm("first", serde_json::json!({})),
+            llm(
+                "second",
+                serde_json::json!({"replace_resumed_package": true}),
+            ),
+        ];
+        let ir = run_pipeline(config.into(), pipeline).unwrap();
+        assert_eq!(*ran.lock().unwrap(), ["second"]);
+        let (package, _) = ir.get_one::<CargoPackage>().unwrap();
+        assert!(!ir.is_resumed(package.into()));
+    }
 }
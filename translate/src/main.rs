@@ -15,7 +15,7 @@ fn main() {
 fn run() -> Result<(), Box<dyn std::error::Error>> {
     set_user_only_umask();
     let args: Arc<_> = Args::parse().into();
-    let Some(config) = initialize(args) else {
+    let Some(config) = initialize(args)? else {
         return Ok(()); // An early-exit argument was passed.
     };
     empty_writable_dir(&config.output, config.force).expect("output directory error");
@@ -1,6 +1,7 @@
-use harvest_core::diagnostics::Reporter;
-use harvest_core::edit::{self, NewEditError};
-use harvest_core::tools::{RunContext, Tool};
+use harvest_core::diagnostics::{Reporter, ToolRunId};
+use harvest_core::edit::{self, ApplyEditError, NewEditError};
+use harvest_core::invariants::Invariants;
+use harvest_core::tools::{MightWriteContext, RunContext, Tool};
 use harvest_core::{Edit, HarvestIR, Id};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
@@ -11,18 +12,23 @@ use std::sync::Arc;
 use std::sync::mpsc::{Receiver, Sender, channel};
 use std::thread::{self, JoinHandle, ThreadId, spawn};
 use thiserror::Error;
-use tracing::error;
+use tracing::{error, info};
 
 /// Spawns off each tool execution in its own thread, and keeps track of those threads.
 pub struct ToolRunner {
     invocations: HashMap<ThreadId, RunningInvocation>,
 
     // Diagnostic fields.
-    // IR version number. The version start at 0 and increments by 1 every time an IR edit is
-    // successfully applied.
-    ir_version: u64,
     reporter: Reporter,
 
+    // Invariants to check after each applied edit, and whether any have been violated so far.
+    invariants: Invariants,
+    invariants_violated: bool,
+
+    // Tool invocations whose edits were rejected because they read stale data, and which should be
+    // re-queued.
+    retries: Vec<Box<dyn Tool>>,
+
     // Channel used by threads to signal that they are completed running.
     receiver: Receiver<ThreadId>,
     sender: Sender<ThreadId>,
@@ -34,13 +40,39 @@ impl ToolRunner {
         let (sender, receiver) = channel();
         ToolRunner {
             invocations: HashMap::new(),
-            ir_version: 0,
             reporter,
+            invariants: Invariants::default(),
+            invariants_violated: false,
+            retries: vec![],
             receiver,
             sender,
         }
     }
 
+    /// Sets the invariants to check after each edit is applied. Violations are reported to the
+    /// diagnostics reporter.
+    pub fn set_invariants(&mut self, invariants: Invariants) {
+        self.invariants = invariants;
+    }
+
+    /// Returns `true` if an applied edit has violated one of the invariants.
+    pub fn invariants_violated(&self) -> bool {
+        self.invariants_violated
+    }
+
+    /// Returns `true` if an invocation of the tool named `name` is running.
+    pub fn is_running(&self, name: &str) -> bool {
+        self.invocations
+            .values()
+            .any(|invocation| invocation.name == name)
+    }
+
+    /// Returns the tool invocations that should be re-run because their edit was computed from
+    /// stale data (see [ApplyEditError::StaleRead]).
+    pub fn take_retries(&mut self) -> Vec<Box<dyn Tool>> {
+        std::mem::take(&mut self.retries)
+    }
+
     /// Waits until at least one tool has completed running, then process the results of all
     /// completed tool invocations. This will update the IR value in edit_organizer. Returns `true`
     /// if at least one tool completed, and `false` if no tools are currently running.
@@ -62,27 +94,56 @@ impl ToolRunner {
             let Ok(edit) = completed_invocation else {
                 continue;
             };
-            if let Err(error) = edit_organizer.apply_edit(edit) {
-                error!("Edit application error: {error:?}");
-                continue;
+            match edit_organizer.apply_edit(edit) {
+                Err(ApplyEditError::StaleRead(ids)) => {
+                    info!("Discarding edit that read changed IDs {ids:?}");
+                    self.retries.extend(invocation.retry);
+                    continue;
+                }
+                Err(error) => {
+                    error!("Edit application error: {error:?}");
+                    continue;
+                }
+                Ok(()) => {}
+            }
+            let (version, snapshot) = (edit_organizer.version(), edit_organizer.snapshot());
+            self.reporter.report_ir_version(version, &snapshot);
+            let violations = self.invariants.check(&snapshot);
+            if !violations.is_empty() {
+                self.reporter.report_invariant_violations(
+                    version,
+                    invocation.tool_run,
+                    &violations,
+                );
+                self.invariants_violated = true;
             }
-            self.ir_version += 1;
-            self.reporter
-                .report_ir_version(self.ir_version, &edit_organizer.snapshot());
         }
         true
     }
 
-    /// Runs a tool. The tool is run in a new thread.
+    /// Waits for every running tool to complete, discarding their edits. Used when translation
+    /// is stopped early, so that no tool threads are left running.
+    pub fn join_all(&mut self) {
+        for (_, invocation) in self.invocations.drain() {
+            _ = invocation
+                .join_handle
+                .join()
+                .expect("tool invocation thread panicked");
+        }
+    }
+
+    /// Runs a tool. The tool is run in a new thread. The IDs returned by [Tool::might_read] are
+    /// read-locked until the tool completes.
     pub fn spawn_tool(
         &mut self,
         edit_organizer: &mut edit::Organizer,
-        tool: Box<dyn Tool>,
+        mut tool: Box<dyn Tool>,
         ir_snapshot: Arc<HarvestIR>,
         might_write: HashSet<Id>,
         config: Arc<harvest_core::config::Config>,
     ) -> Result<(), (SpawnToolError, Box<dyn Tool>)> {
-        let mut edit = match edit_organizer.new_edit(&might_write) {
+        let might_read = tool.might_read(MightWriteContext::new(&ir_snapshot));
+        let mut edit = match edit_organizer.new_edit_with_reads(&might_write, &might_read) {
             Err(error) => return Err((error.into(), tool)),
             Ok(edit) => edit,
         };
@@ -91,6 +152,9 @@ impl ToolRunner {
             Err(error) => return Err((error.into(), tool)),
             Ok(joiner_reporter) => joiner_reporter,
         };
+        let tool_run = tool_reporter.tool_run();
+        edit.set_tool_run(tool_run);
+        let (name, retry) = (tool.name(), tool.retry());
         let join_handle = spawn(move || {
             let logger = tool_reporter.setup_thread_logger();
             // Tool::run is not necessarily unwind safe, which means that if it panics it might
@@ -125,8 +189,15 @@ impl ToolRunner {
             let _ = sender.send(thread::current().id());
             out
         });
-        self.invocations
-            .insert(join_handle.thread().id(), RunningInvocation { join_handle });
+        self.invocations.insert(
+            join_handle.thread().id(),
+            RunningInvocation {
+                join_handle,
+                name,
+                tool_run,
+                retry,
+            },
+        );
         Ok(())
     }
 }
@@ -144,6 +215,9 @@ pub enum SpawnToolError {
 /// thread.
 struct RunningInvocation {
     join_handle: JoinHandle<Result<Edit, ()>>,
+    name: &'static str,
+    tool_run: ToolRunId,
+    retry: Option<Box<dyn Tool>>,
 }
 
 #[cfg(all(test, not(miri)))]
@@ -153,7 +227,7 @@ mod tests {
     use crate::diagnostics::Collector;
     use harvest_core::Representation;
     use harvest_core::config::Config;
-    use harvest_core::edit::{self, NewEditError};
+    use harvest_core::edit::{self, LockKind, NewEditError};
     use harvest_core::test_util::MockTool;
     use std::fmt::{self, Display, Formatter};
 
@@ -175,7 +249,8 @@ mod tests {
         let mut edit_organizer = edit::Organizer::default();
         let mut edit = edit_organizer.new_edit(&[].into()).unwrap();
         let config = Arc::new(Config::mock());
-        let [a, b, c] = [(); 3].map(|_| edit.add_representation(Box::new(TestRepresentation)));
+        let [a, b, c]: [Id; 3] =
+            [(); 3].map(|_| edit.add_representation(Box::new(TestRepresentation)).into());
         edit_organizer.apply_edit(edit).expect("setup edit failed");
         let mut runner = ToolRunner::new(collector.reporter());
         let unknown_id = Id::new();
@@ -217,7 +292,9 @@ mod tests {
         assert!(
             matches!(
                 result.err().map(|(e, _)| e),
-                Some(SpawnToolError::NewEdit(NewEditError::IdInUse))
+                Some(SpawnToolError::NewEdit(NewEditError::IdInUse(
+                    LockKind::Write
+                )))
             ),
             "spawned tool with in-use ID"
         );
@@ -230,7 +307,7 @@ mod tests {
         let collector = Collector::initialize(&Config::mock()).unwrap();
         let mut edit_organizer = edit::Organizer::default();
         let mut edit = edit_organizer.new_edit(&[].into()).unwrap();
-        let a = edit.add_representation(Box::new(TestRepresentation));
+        let a = edit.add_representation(Box::new(TestRepresentation)).id();
         edit_organizer.apply_edit(edit).expect("setup edit failed");
         let mut runner = ToolRunner::new(collector.reporter());
         let (sender, receiver) = channel();
@@ -251,9 +328,12 @@ mod tests {
         );
         assert!(result.is_ok());
         // Verify that `a` was marked as in use
-        assert!(edit_organizer.new_edit(&[a].into()).err() == Some(NewEditError::IdInUse));
+        assert!(
+            edit_organizer.new_edit(&[a].into()).err()
+                == Some(NewEditError::IdInUse(LockKind::Write))
+        );
         let mut edit = edit_organizer.new_edit(&[].into()).unwrap();
-        let b = edit.add_representation(Box::new(TestRepresentation));
+        let b = edit.add_representation(Box::new(TestRepresentation)).id();
         sender.send(edit).expect("receiver dropped");
         runner.process_tool_results(&mut edit_organizer);
         let ir_ids: Vec<Id> = edit_organizer.snapshot().iter().map(|(id, _)| id).collect();
@@ -262,6 +342,83 @@ mod tests {
         assert_eq!(ir_ids, [a, b]);
     }
 
+    #[test]
+    fn stale_read() {
+        let collector = Collector::initialize(&Config::mock()).unwrap();
+        let mut edit_organizer = edit::Organizer::default();
+        let mut edit = edit_organizer.new_edit(&[].into()).unwrap();
+        let a = edit.add_representation(Box::new(TestRepresentation)).id();
+        edit_organizer.apply_edit(edit).expect("setup edit failed");
+        let mut runner = ToolRunner::new(collector.reporter());
+        let (sender, receiver) = channel::<()>();
+        let snapshot = edit_organizer.snapshot();
+        let config = Arc::new(Config::mock());
+        let result = runner.spawn_tool(
+            &mut edit_organizer,
+            MockTool::new()
+                .name("reader")
+                .run(move |c| {
+                    c.ir_edit.record_read(a);
+                    c.ir_edit.add_representation(Box::new(TestRepresentation));
+                    Ok(receiver.recv()?)
+                })
+                .retry(|| MockTool::new().name("reader_retry"))
+                .boxed(),
+            snapshot,
+            [].into(),
+            config.clone(),
+        );
+        assert!(result.is_ok());
+        // Replace `a` while the tool is running.
+        let mut edit = edit_organizer.new_edit(&[a].into()).unwrap();
+        edit.write_id(a, Box::new(TestRepresentation));
+        edit_organizer.apply_edit(edit).expect("replacing a failed");
+        sender.send(()).expect("receiver dropped");
+        runner.process_tool_results(&mut edit_organizer);
+        assert_eq!(
+            edit_organizer.snapshot().iter().count(),
+            1,
+            "stale edit applied"
+        );
+        let retries = runner.take_retries();
+        assert_eq!(
+            Vec::from_iter(retries.iter().map(|t| t.name())),
+            ["reader_retry"]
+        );
+        assert!(runner.take_retries().is_empty());
+    }
+
+    #[test]
+    fn reproducible_ids() {
+        let run = || {
+            let collector = Collector::initialize(&Config::mock()).unwrap();
+            let mut edit_organizer = edit::Organizer::default();
+            let mut runner = ToolRunner::new(collector.reporter());
+            let snapshot = edit_organizer.snapshot();
+            let tool = MockTool::new().run(|c| {
+                c.ir_edit.add_representation(Box::new(TestRepresentation));
+                c.ir_edit.add_representation(Box::new(TestRepresentation));
+                Ok(())
+            });
+            let config = Arc::new(Config::mock());
+            let result = runner.spawn_tool(
+                &mut edit_organizer,
+                tool.boxed(),
+                snapshot,
+                [].into(),
+                config,
+            );
+            assert!(result.is_ok());
+            runner.process_tool_results(&mut edit_organizer);
+            Vec::from_iter(edit_organizer.snapshot().ids().copied())
+        };
+        let ids = run();
+        assert_eq!(ids.len(), 2);
+        // IDs allocated elsewhere in the process should not affect the tool run's IDs.
+        Id::new();
+        assert_eq!(run(), ids);
+    }
+
     #[test]
     fn success() {
         let collector = Collector::initialize(&Config::mock()).unwrap();
@@ -36,11 +36,11 @@ impl Scheduler {
         Ok(())
     }
 
-    /// Add a tool invocation to the scheduler's queue. Note that scheduling a
-    /// tool invocation does not guarantee the tool will run, as a tool may
-    /// indicate that it is not runnable.
-    pub fn queue_invocation<T: Tool>(&mut self, invocation: T) {
-        self.queued_invocations.push(Box::new(invocation));
+    /// Add a tool invocation (e.g. one from the pipeline or returned by [Tool::retry]) to the
+    /// scheduler's queue. Note that scheduling a tool invocation does not guarantee the tool will
+    /// run, as a tool may indicate that it is not runnable.
+    pub fn queue_invocation(&mut self, invocation: Box<dyn Tool>) {
+        self.queued_invocations.push(invocation);
     }
 }
 
@@ -65,8 +65,8 @@ mod tests {
         // Counters for the number of times the scheduler tries to run each tool invocation.
         let [mut a_count, mut b_count] = [0, 0];
         let mut scheduler = Scheduler::default();
-        scheduler.queue_invocation(MockTool::new().name("a"));
-        scheduler.queue_invocation(MockTool::new().name("b"));
+        scheduler.queue_invocation(MockTool::new().name("a").boxed());
+        scheduler.queue_invocation(MockTool::new().name("b").boxed());
         scheduler
             .next_invocations(|t| match t.name() {
                 "a" => {